@@ -0,0 +1,59 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum LoaderError {
+    #[error("cannot read {path}. {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("cannot parse line {line} in {path}. {source}")]
+    Parse {
+        path: String,
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("config error in {path}. {message}")]
+    Config { path: String, message: String },
+    #[error("schema error in {path}. {message}")]
+    Schema { path: String, message: String },
+    #[error("cannot start the runtime. {0}")]
+    Runtime(std::io::Error),
+    #[error("{0}")]
+    Transport(String),
+    #[error("index error. {0}")]
+    Index(String),
+    #[error("too many malformed lines. more than {max_errors} lines were skipped")]
+    TooManyErrors { max_errors: usize },
+    #[error("{count} documents were rejected by the search engine")]
+    Rejected { count: usize },
+    #[error("{index} has {count} documents, but {expected} documents were loaded")]
+    CountMismatch {
+        index: String,
+        count: usize,
+        expected: usize,
+    },
+    #[error("{} of {} files failed to load", failures.len(), total)]
+    Incomplete {
+        total: usize,
+        failures: Vec<(String, LoaderError)>,
+    },
+}
+
+impl From<elasticsearch::Error> for LoaderError {
+    fn from(err: elasticsearch::Error) -> Self {
+        LoaderError::Transport(err.to_string())
+    }
+}
+
+impl From<reqwest::Error> for LoaderError {
+    fn from(err: reqwest::Error) -> Self {
+        LoaderError::Transport(err.to_string())
+    }
+}
+
+impl From<tantivy::TantivyError> for LoaderError {
+    fn from(err: tantivy::TantivyError) -> Self {
+        LoaderError::Index(err.to_string())
+    }
+}
@@ -4,5 +4,6 @@ extern crate log;
 extern crate reqwest;
 extern crate serde_derive;
 extern crate serde_json;
+pub mod error;
 pub mod loader;
 pub mod output;
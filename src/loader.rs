@@ -1,2 +1,9 @@
+pub mod checkpoint;
+pub mod cirrus;
+pub mod dead_letter;
 pub mod document;
+pub mod input;
+#[allow(clippy::module_inception)]
 pub mod loader;
+pub mod transform;
+pub mod wiki_xml;
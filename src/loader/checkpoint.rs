@@ -0,0 +1,149 @@
+use crate::error::LoaderError;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::Mutex;
+
+/// Progress of an input file. `line` is the last line (page for XML dumps) whose document was acknowledged
+/// by the search engine, and all lines before it were acknowledged too.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FileProgress {
+    pub completed: bool,
+    pub line: usize,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    files: BTreeMap<String, FileProgress>,
+}
+
+/// Records the progress of each input file to a JSON file, so that an interrupted load can be resumed.
+pub struct CheckpointFile {
+    path: String,
+    state: Mutex<Checkpoint>,
+}
+
+impl CheckpointFile {
+    /// Opens the checkpoint file. The recorded progress is read only if `resume` is true.
+    pub fn open(path: &str, resume: bool) -> Result<Self, LoaderError> {
+        let state = if resume {
+            match File::open(path) {
+                Ok(f) => {
+                    serde_json::from_reader(BufReader::new(f)).map_err(|e| LoaderError::Config {
+                        path: path.to_string(),
+                        message: format!("checkpoint file cannot read. {}", e),
+                    })?
+                }
+                Err(_) => Checkpoint::default(),
+            }
+        } else {
+            Checkpoint::default()
+        };
+        Ok(CheckpointFile {
+            path: path.to_string(),
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn progress(&self, file: &str) -> FileProgress {
+        let state = self.state.lock().unwrap();
+        state.files.get(file).cloned().unwrap_or_default()
+    }
+
+    pub fn update(&self, file: &str, line: usize) -> Result<(), LoaderError> {
+        let mut state = self.state.lock().unwrap();
+        state.files.entry(file.to_string()).or_default().line = line;
+        self.save(&state)
+    }
+
+    pub fn complete(&self, file: &str) -> Result<(), LoaderError> {
+        let mut state = self.state.lock().unwrap();
+        state.files.entry(file.to_string()).or_default().completed = true;
+        self.save(&state)
+    }
+
+    // write to a temporary file and rename it, so that the checkpoint is never half written
+    fn save(&self, state: &Checkpoint) -> Result<(), LoaderError> {
+        let io_error = |source| LoaderError::Io {
+            path: self.path.clone(),
+            source,
+        };
+        let tmp_path = format!("{}.tmp", self.path);
+        let f = File::create(&tmp_path).map_err(io_error)?;
+        serde_json::to_writer_pretty(f, state).map_err(|e| io_error(e.into()))?;
+        fs::rename(&tmp_path, &self.path).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn checkpoint_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "wiki-json-loader-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn read_written_progress() {
+        let path = checkpoint_path("checkpoint-read");
+        let checkpoint = CheckpointFile::open(&path, true).unwrap();
+        checkpoint.update("a.json", 10).unwrap();
+        checkpoint.update("a.json", 20).unwrap();
+        checkpoint.update("b.json", 5).unwrap();
+        checkpoint.complete("b.json").unwrap();
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+        let resumed = CheckpointFile::open(&path, true).unwrap();
+        let a = resumed.progress("a.json");
+        assert_eq!((a.completed, a.line), (false, 20));
+        let b = resumed.progress("b.json");
+        assert_eq!((b.completed, b.line), (true, 5));
+        let c = resumed.progress("c.json");
+        assert_eq!((c.completed, c.line), (false, 0));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ignore_progress_without_resume() {
+        let path = checkpoint_path("checkpoint-ignore");
+        CheckpointFile::open(&path, false)
+            .unwrap()
+            .complete("a.json")
+            .unwrap();
+        let checkpoint = CheckpointFile::open(&path, false).unwrap();
+        assert!(!checkpoint.progress("a.json").completed);
+        // the old progress is overwritten by the first update
+        checkpoint.update("b.json", 1).unwrap();
+        let resumed = CheckpointFile::open(&path, true).unwrap();
+        assert!(!resumed.progress("a.json").completed);
+        assert_eq!(resumed.progress("b.json").line, 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resume_without_checkpoint_file() {
+        let path = checkpoint_path("checkpoint-missing");
+        let checkpoint = CheckpointFile::open(&path, true).unwrap();
+        assert_eq!(checkpoint.progress("a.json").line, 0);
+    }
+
+    #[test]
+    fn fail_on_broken_checkpoint_file() {
+        let path = checkpoint_path("checkpoint-broken");
+        fs::write(&path, "{\"files\": ").unwrap();
+        match CheckpointFile::open(&path, true) {
+            Err(LoaderError::Config { message, .. }) => {
+                assert!(message.contains("checkpoint file cannot read"))
+            }
+            _ => panic!("broken checkpoint file was read"),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
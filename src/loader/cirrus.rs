@@ -0,0 +1,222 @@
+use crate::loader::document::{parse_timestamp, Document, Link};
+use crate::loader::input::Record;
+use serde::de::Error;
+use serde_json::Value;
+use std::io::{self, BufRead};
+
+/// A page of a CirrusSearch dump. Only the fields mapped onto `Document` are read.
+#[derive(Debug, Deserialize)]
+struct CirrusPage {
+    #[serde(default)]
+    page_id: Option<u64>,
+    title: String,
+    #[serde(default)]
+    namespace: i64,
+    #[serde(default)]
+    timestamp: String,
+    #[serde(default)]
+    version: Option<u64>,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    opening_text: Option<String>,
+    #[serde(default)]
+    heading: Vec<String>,
+    #[serde(default)]
+    category: Vec<String>,
+    #[serde(default)]
+    outgoing_link: Vec<String>,
+    #[serde(default)]
+    external_link: Vec<String>,
+    #[serde(default)]
+    incoming_links: Option<u64>,
+    #[serde(default)]
+    popularity_score: Option<f64>,
+}
+
+impl CirrusPage {
+    fn into_document(self, id: String) -> Result<Document, serde_json::Error> {
+        let timestamp = parse_timestamp(&self.timestamp).map_err(serde_json::Error::custom)?;
+        let links = self
+            .outgoing_link
+            .into_iter()
+            .map(|link_target| Link::Link { link_target })
+            .chain(
+                self.external_link
+                    .into_iter()
+                    .map(|link_target| Link::ExternalLink { link_target }),
+            )
+            .collect();
+        Ok(Document {
+            id,
+            revision_id: self.version.map(|v| v.to_string()).unwrap_or_default(),
+            title: self.title,
+            timestamp,
+            contents: vec![self.text],
+            headings: self.heading,
+            categories: self.category,
+            images: vec![],
+            links,
+            opening_text: self.opening_text,
+            incoming_links: self.incoming_links,
+            popularity_score: self.popularity_score,
+        })
+    }
+}
+
+/// Reads a CirrusSearch dump, the Elasticsearch bulk NDJSON published by Wikimedia.
+/// Each page line follows an `index` action line with the page id.
+/// Pages outside the main namespace are skipped.
+pub struct CirrusReader<R: BufRead> {
+    lines: io::Lines<R>,
+    // `_id` of the last action line
+    id: Option<String>,
+}
+
+impl<R: BufRead> CirrusReader<R> {
+    pub fn new(reader: R) -> Self {
+        CirrusReader {
+            lines: reader.lines(),
+            id: None,
+        }
+    }
+
+    fn read_line(&mut self, line: &str) -> Result<Option<Document>, serde_json::Error> {
+        let value: Value = serde_json::from_str(line)?;
+        if let Some(action) = value.get("index") {
+            self.id = match &action["_id"] {
+                Value::String(id) => Some(id.clone()),
+                Value::Number(id) => Some(id.to_string()),
+                _ => None,
+            };
+            return Ok(None);
+        }
+        let page: CirrusPage = serde_json::from_value(value)?;
+        let id = match (page.page_id, self.id.take()) {
+            (Some(page_id), _) => page_id.to_string(),
+            (None, Some(id)) => id,
+            (None, None) => return Err(serde_json::Error::custom("page id is not found")),
+        };
+        if page.namespace != 0 {
+            return Ok(None);
+        }
+        page.into_document(id).map(Some)
+    }
+}
+
+impl<R: BufRead> Iterator for CirrusReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(err) => return Some(Err(err)),
+        };
+        if line.trim().is_empty() {
+            return Some(Ok(Record::Skip));
+        }
+        Some(Ok(match self.read_line(&line) {
+            Ok(Some(document)) => Record::Document(Box::new(document)),
+            Ok(None) => Record::Skip,
+            Err(error) => Record::Malformed { raw: line, error },
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMP: &str = r#"{"index":{"_type":"page","_id":"5"}}
+{"namespace":0,"title":"Rust","timestamp":"2020-06-01T12:34:56Z","version":100,"text":"Rust is a programming language.","opening_text":"Rust is","heading":["History"],"category":["Programming languages"],"outgoing_link":["Mozilla"],"external_link":["https://www.rust-lang.org/"],"incoming_links":42,"popularity_score":0.0001}
+{"index":{"_type":"page","_id":"6"}}
+{"namespace":0,"title":"Broken","timestamp":"2020-06-01T12:34:56Z",
+{"index":{"_type":"page","_id":7}}
+{"page_id":8,"namespace":0,"title":"Ferris","timestamp":"2020-07-01T00:00:00Z","version":200,"text":"A crab."}
+{"index":{"_type":"page","_id":"9"}}
+{"namespace":4,"title":"Wikipedia:Help","timestamp":"2020-06-01T12:34:56Z","text":"help"}
+
+"#;
+
+    fn records() -> Vec<Record> {
+        CirrusReader::new(DUMP.as_bytes())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn pairs_action_and_source_lines() {
+        let records = records();
+        assert_eq!(records.len(), 9);
+        let ids: Vec<&str> = records
+            .iter()
+            .filter_map(|record| match record {
+                Record::Document(document) => Some(document.id.as_str()),
+                _ => None,
+            })
+            .collect();
+        // page_id takes precedence over _id
+        assert_eq!(ids, vec!["5", "8"]);
+        for i in [0, 2, 4, 6, 7, 8] {
+            assert!(matches!(records[i], Record::Skip), "line {}", i);
+        }
+    }
+
+    #[test]
+    fn malformed_source_line() {
+        match &records()[3] {
+            Record::Malformed { raw, .. } => {
+                assert!(raw.starts_with(r#"{"namespace":0,"title":"Broken""#))
+            }
+            _ => panic!("not malformed"),
+        }
+    }
+
+    #[test]
+    fn maps_cirrus_fields() {
+        let records = records();
+        let document = match &records[1] {
+            Record::Document(document) => document,
+            _ => panic!("not a document"),
+        };
+        assert_eq!(document.revision_id, "100");
+        assert_eq!(document.title, "Rust");
+        assert_eq!(document.timestamp.to_rfc3339(), "2020-06-01T12:34:56+00:00");
+        assert_eq!(document.contents, vec!["Rust is a programming language."]);
+        assert_eq!(document.headings, vec!["History"]);
+        assert_eq!(document.categories, vec!["Programming languages"]);
+        assert!(matches!(
+            &document.links[..],
+            [Link::Link { link_target: link }, Link::ExternalLink { link_target: external }]
+                if link == "Mozilla" && external == "https://www.rust-lang.org/"
+        ));
+        assert_eq!(document.opening_text.as_deref(), Some("Rust is"));
+        assert_eq!(document.incoming_links, Some(42));
+        assert_eq!(document.popularity_score, Some(0.0001));
+        let fields = document.to_hashmap();
+        assert_eq!(fields["opening_text"], "Rust is");
+        assert_eq!(fields["incoming_links"], 42);
+        assert_eq!(fields["popularity_score"], 0.0001);
+
+        let document = match &records[5] {
+            Record::Document(document) => document,
+            _ => panic!("not a document"),
+        };
+        assert!(document.opening_text.is_none());
+        assert!(document.incoming_links.is_none());
+        assert!(document.popularity_score.is_none());
+        assert!(!document.to_hashmap().contains_key("opening_text"));
+    }
+
+    #[test]
+    fn page_without_id() {
+        let dump = r#"{"namespace":0,"title":"Rust","timestamp":"2020-06-01T12:34:56Z"}"#;
+        let record = CirrusReader::new(dump.as_bytes()).next().unwrap().unwrap();
+        match record {
+            Record::Malformed { error, .. } => {
+                assert!(error.to_string().contains("page id is not found"))
+            }
+            _ => panic!("not malformed"),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::error::LoaderError;
+use log::warn;
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// An entry of the dead-letter file. The file is written as JSON Lines.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DeadLetter {
+    Parse {
+        path: String,
+        line: usize,
+        error: String,
+        raw: String,
+    },
+    /// A document rejected by the search engine. `document` can be loaded again with `--replay`.
+    Rejected {
+        id: String,
+        error_type: String,
+        reason: String,
+        document: Value,
+    },
+}
+
+pub struct DeadLetterWriter {
+    path: String,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl DeadLetterWriter {
+    /// Creates the dead-letter file. Existing entries are kept if `append` is true.
+    pub fn create(path: &str, append: bool) -> Result<Self, LoaderError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .map_err(|source| LoaderError::Io {
+                path: path.to_string(),
+                source,
+            })?;
+        Ok(DeadLetterWriter {
+            path: path.to_string(),
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    pub fn write(&self, entry: &DeadLetter) -> Result<(), LoaderError> {
+        let io_error = |source| LoaderError::Io {
+            path: self.path.clone(),
+            source,
+        };
+        let mut line = serde_json::to_string(entry).unwrap();
+        line.push('\n');
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(line.as_bytes()).map_err(io_error)
+    }
+
+    pub fn flush(&self) -> Result<(), LoaderError> {
+        self.writer
+            .lock()
+            .unwrap()
+            .flush()
+            .map_err(|source| LoaderError::Io {
+                path: self.path.clone(),
+                source,
+            })
+    }
+}
+
+/// Counts malformed lines across all input files and aborts the load once `max_errors` is exceeded.
+pub struct ErrorLimit {
+    count: AtomicUsize,
+    max_errors: Option<usize>,
+}
+
+impl ErrorLimit {
+    pub fn new(max_errors: Option<usize>) -> Self {
+        ErrorLimit {
+            count: AtomicUsize::new(0),
+            max_errors,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    pub fn record(
+        &self,
+        path: &str,
+        line: usize,
+        err: &serde_json::Error,
+    ) -> Result<(), LoaderError> {
+        warn!("skip line {} in {}. {}", line, path, err);
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.check()
+    }
+
+    pub fn check(&self) -> Result<(), LoaderError> {
+        match self.max_errors {
+            Some(max_errors) if self.count() > max_errors => {
+                Err(LoaderError::TooManyErrors { max_errors })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    fn dead_letter_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "wiki-json-loader-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn parse_error() -> serde_json::Error {
+        serde_json::from_str::<Value>("{broken").unwrap_err()
+    }
+
+    fn entries(path: &str) -> Vec<Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn write_parse_and_rejected_entries() {
+        let path = dead_letter_path("dead-letter-write");
+        let writer = DeadLetterWriter::create(&path, false).unwrap();
+        writer
+            .write(&DeadLetter::Parse {
+                path: String::from("input.json"),
+                line: 3,
+                error: parse_error().to_string(),
+                raw: String::from("{broken"),
+            })
+            .unwrap();
+        writer
+            .write(&DeadLetter::Rejected {
+                id: String::from("1"),
+                error_type: String::from("mapper_parsing_exception"),
+                reason: String::from("failed to parse"),
+                document: json!({"id": "1", "title": "Title"}),
+            })
+            .unwrap();
+        writer.flush().unwrap();
+        let entries = entries(&path);
+        assert_eq!(
+            entries[0],
+            json!({
+                "kind": "parse",
+                "path": "input.json",
+                "line": 3,
+                "error": parse_error().to_string(),
+                "raw": "{broken"
+            })
+        );
+        assert_eq!(
+            entries[1],
+            json!({
+                "kind": "rejected",
+                "id": "1",
+                "error_type": "mapper_parsing_exception",
+                "reason": "failed to parse",
+                "document": {"id": "1", "title": "Title"}
+            })
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn append_to_existing_entries() {
+        let path = dead_letter_path("dead-letter-append");
+        let entry = || DeadLetter::Parse {
+            path: String::from("input.json"),
+            line: 1,
+            error: String::from("error"),
+            raw: String::new(),
+        };
+        let writer = DeadLetterWriter::create(&path, false).unwrap();
+        writer.write(&entry()).unwrap();
+        writer.flush().unwrap();
+        let writer = DeadLetterWriter::create(&path, true).unwrap();
+        writer.write(&entry()).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(entries(&path).len(), 2);
+        // the entries are removed without append
+        DeadLetterWriter::create(&path, false).unwrap();
+        assert!(entries(&path).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn abort_after_max_errors() {
+        let errors = ErrorLimit::new(Some(2));
+        assert!(errors.record("input.json", 1, &parse_error()).is_ok());
+        assert!(errors.record("input.json", 2, &parse_error()).is_ok());
+        assert!(errors.check().is_ok());
+        match errors.record("input.json", 3, &parse_error()) {
+            Err(LoaderError::TooManyErrors { max_errors }) => assert_eq!(max_errors, 2),
+            _ => panic!("not aborted"),
+        }
+        assert!(errors.check().is_err());
+        assert_eq!(errors.count(), 3);
+    }
+
+    #[test]
+    fn count_errors_without_limit() {
+        let errors = ErrorLimit::new(None);
+        for line in 1..=100 {
+            errors.record("input.json", line, &parse_error()).unwrap();
+        }
+        assert_eq!(errors.count(), 100);
+        assert!(errors.check().is_ok());
+    }
+}
@@ -1,6 +1,5 @@
-use serde::export::fmt::Error;
-use serde::export::Formatter;
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fmt;
@@ -8,14 +7,22 @@ use std::fmt;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Document {
     pub id: String,
-    revision_id: String,
-    title: String,
-    timestamp: String,
-    contents: Vec<String>,
-    headings: Vec<String>,
-    categories: Vec<String>,
+    pub revision_id: String,
+    pub title: String,
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub timestamp: DateTime<Utc>,
+    pub contents: Vec<String>,
+    pub headings: Vec<String>,
+    pub categories: Vec<String>,
     pub images: Vec<Image>,
     pub links: Vec<Link>,
+    // fields only in CirrusSearch dumps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opening_text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incoming_links: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub popularity_score: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -51,9 +58,35 @@ pub enum Link {
     Link { link_target: String },
 }
 
+/// Parses an RFC 3339 timestamp like `2020-06-01T12:34:56Z`.
+pub fn parse_timestamp(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(|e| format!("invalid timestamp {:?}. {}", value, e))
+}
+
+fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_timestamp(&value).map_err(serde::de::Error::custom)
+}
+
 impl Document {
-    pub fn new(line: &str) -> Self {
-        serde_json::from_str(line).unwrap()
+    pub fn new(line: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+    /// Whether the revision is newer than `indexed_revision_id`. Revisions that are not numbers
+    /// are only compared for equality.
+    pub fn is_newer_than(&self, indexed_revision_id: &str) -> bool {
+        match (
+            self.revision_id.parse::<u64>(),
+            indexed_revision_id.parse::<u64>(),
+        ) {
+            (Ok(revision_id), Ok(indexed_revision_id)) => revision_id > indexed_revision_id,
+            _ => self.revision_id != indexed_revision_id,
+        }
     }
     pub fn to_hashmap(&self) -> HashMap<String, Value> {
         let mut data = HashMap::new();
@@ -68,7 +101,7 @@ impl Document {
         );
         data.insert(
             String::from("timestamp"),
-            serde_json::to_value(&self.timestamp).unwrap(),
+            serde_json::to_value(self.timestamp).unwrap(),
         );
         data.insert(
             String::from("contents"),
@@ -82,15 +115,117 @@ impl Document {
             String::from("categories"),
             serde_json::to_value(&self.categories).unwrap(),
         );
-        // FIXME
-        //data.insert(String::from("images"), Value::from(self.images));
-        //data.insert(String::from("links"), Value::from(self.links));
-        return data;
+        // serialized as the complex types in index_schema.json.
+        // images: {target, target_type: "Image" | "File", text: {text, link_target?}}
+        // links: {link_target}
+        data.insert(
+            String::from("images"),
+            serde_json::to_value(&self.images).unwrap(),
+        );
+        data.insert(
+            String::from("links"),
+            serde_json::to_value(&self.links).unwrap(),
+        );
+        if let Some(opening_text) = &self.opening_text {
+            data.insert(
+                String::from("opening_text"),
+                serde_json::to_value(opening_text).unwrap(),
+            );
+        }
+        if let Some(incoming_links) = self.incoming_links {
+            data.insert(String::from("incoming_links"), Value::from(incoming_links));
+        }
+        if let Some(popularity_score) = self.popularity_score {
+            data.insert(
+                String::from("popularity_score"),
+                Value::from(popularity_score),
+            );
+        }
+        data
     }
 }
 
 impl fmt::Display for Document {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", serde_json::to_string(self).unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn images_and_links_in_hashmap() {
+        let line = json!({
+            "id": "1",
+            "revision_id": "100",
+            "title": "Rust",
+            "timestamp": "2020-06-01T12:34:56Z",
+            "contents": ["Rust is a programming language."],
+            "headings": [],
+            "categories": [],
+            "images": [
+                {
+                    "target": "Rust logo.svg",
+                    "target_type": "File",
+                    "text": {"text": "The Rust logo"}
+                },
+                {
+                    "target": "Ferris.png",
+                    "target_type": "Image",
+                    "text": {"text": "Ferris", "link_target": "Ferris"}
+                }
+            ],
+            "links": [
+                {"link_target": "Programming language"},
+                {"link_target": "https://www.rust-lang.org/"}
+            ]
+        });
+        let document = Document::new(&line.to_string()).unwrap();
+        let fields = document.to_hashmap();
+        assert_eq!(fields["images"], line["images"]);
+        assert_eq!(fields["links"], line["links"]);
+        assert!(!fields.contains_key("opening_text"));
+    }
+
+    fn document_with_timestamp(timestamp: &str) -> Result<Document, serde_json::Error> {
+        let line = json!({
+            "id": "1",
+            "revision_id": "100",
+            "title": "Rust",
+            "timestamp": timestamp,
+            "contents": [],
+            "headings": [],
+            "categories": [],
+            "images": [],
+            "links": []
+        });
+        Document::new(&line.to_string())
+    }
+
+    #[test]
+    fn deserialize_rfc3339_timestamp() {
+        let document = document_with_timestamp("2020-06-01T12:34:56Z").unwrap();
+        assert_eq!(document.timestamp.to_rfc3339(), "2020-06-01T12:34:56+00:00");
+        let document = document_with_timestamp("2020-06-01T21:34:56.5+09:00").unwrap();
+        assert_eq!(
+            document.timestamp.to_rfc3339(),
+            "2020-06-01T12:34:56.500+00:00"
+        );
+    }
+
+    #[test]
+    fn deserialize_invalid_timestamp() {
+        for timestamp in [
+            "2020-06-01",
+            "2020-06-01T12:34:56",
+            "2020-13-01T00:00:00Z",
+            "",
+        ] {
+            let error = document_with_timestamp(timestamp).unwrap_err();
+            assert!(error.to_string().contains("invalid timestamp"), "{}", error);
+        }
+    }
+}
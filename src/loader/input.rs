@@ -0,0 +1,316 @@
+use crate::error::LoaderError;
+use crate::loader::cirrus::CirrusReader;
+use crate::loader::document::Document;
+use crate::loader::wiki_xml::WikiXmlReader;
+use bzip2::read::MultiBzDecoder;
+use clap::arg_enum;
+use flate2::read::MultiGzDecoder;
+use glob::{glob, Pattern};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read};
+use std::path::Path;
+
+/// The input path that means stdin.
+pub const STDIN: &str = "-";
+
+/// Default glob patterns of the input files under the input directory.
+pub const INPUT_PATTERNS: [&str; 4] = [
+    "**/*.json",
+    "**/*.json.gz",
+    "**/*.json.bz2",
+    "**/*.json.zst",
+];
+
+/// Default glob patterns of the Wikipedia XML dumps under the input directory.
+pub const XML_PATTERNS: [&str; 4] = ["**/*.xml", "**/*.xml.gz", "**/*.xml.bz2", "**/*.xml.zst"];
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    pub enum InputFormat {
+        Json,
+        WikiXml,
+        Cirrus
+    }
+}
+
+// arg_enum! doesn't accept #[default] on a variant
+#[allow(clippy::derivable_impls)]
+impl Default for InputFormat {
+    fn default() -> Self {
+        InputFormat::Json
+    }
+}
+
+impl InputFormat {
+    pub fn default_patterns(self) -> Vec<String> {
+        let patterns = match self {
+            InputFormat::Json | InputFormat::Cirrus => INPUT_PATTERNS,
+            InputFormat::WikiXml => XML_PATTERNS,
+        };
+        patterns.iter().map(|p| p.to_string()).collect()
+    }
+}
+
+/// A unit read from an input file. JSON lines are parsed by the loader.
+pub enum Record {
+    Line(String),
+    Document(Box<Document>),
+    /// A line that cannot be read as a document.
+    Malformed {
+        raw: String,
+        error: serde_json::Error,
+    },
+    /// A line without a document, like the action lines of CirrusSearch dumps.
+    Skip,
+}
+
+pub type Records = Box<dyn Iterator<Item = io::Result<Record>> + Send>;
+
+/// Opens an input file and reads it as `format`.
+pub fn records(path: &str, format: InputFormat) -> io::Result<Records> {
+    let reader = open(path)?;
+    Ok(match format {
+        InputFormat::Json => Box::new(reader.lines().map(|line| line.map(Record::Line))),
+        InputFormat::WikiXml => Box::new(WikiXmlReader::new(reader)),
+        InputFormat::Cirrus => Box::new(CirrusReader::new(reader)),
+    })
+}
+
+/// Opens an input file. `.gz`, `.bz2` and `.zst` files are decompressed while reading.
+pub fn open(path: &str) -> io::Result<Box<dyn BufRead + Send>> {
+    if path == STDIN {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+    let file = File::open(path)?;
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default();
+    let reader: Box<dyn Read + Send> = match extension {
+        "gz" => Box::new(MultiGzDecoder::new(file)),
+        // multistream, like the Wikipedia dumps
+        "bz2" => Box::new(MultiBzDecoder::new(file)),
+        "zst" => Box::new(zstd::stream::read::Decoder::new(file)?),
+        _ => Box::new(file),
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+/// Lists the input files of `input`. `input` is stdin, a file or a directory. Files in a directory
+/// are matched with the `include` patterns, and then the files matching the `exclude` patterns are removed.
+/// Both patterns are relative to the directory. The files are sorted by path.
+pub fn list_files(
+    input: &str,
+    include: &[String],
+    exclude: &[String],
+) -> Result<Vec<String>, LoaderError> {
+    if input == STDIN || Path::new(input).is_file() {
+        return Ok(vec![input.to_string()]);
+    }
+    let pattern_error = |e: glob::PatternError| LoaderError::Io {
+        path: input.to_string(),
+        source: io::Error::new(ErrorKind::InvalidInput, e),
+    };
+    let exclude = exclude
+        .iter()
+        .map(|pattern| Pattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pattern_error)?;
+    // a file matching more than one pattern is loaded once
+    let mut files = BTreeSet::new();
+    for pattern in include {
+        let path = Path::new(input).join(Path::new(pattern));
+        let path = path.to_str().ok_or_else(|| LoaderError::Io {
+            path: path.to_string_lossy().to_string(),
+            source: io::Error::new(ErrorKind::InvalidInput, "input path is not UTF-8"),
+        })?;
+        for entry in glob(path).map_err(pattern_error)?.filter_map(|x| x.ok()) {
+            let relative = entry.strip_prefix(input).unwrap_or(&entry);
+            if !exclude.iter().any(|p| p.matches_path(relative)) {
+                files.insert(entry.to_string_lossy().to_string());
+            }
+        }
+    }
+    Ok(files.into_iter().collect())
+}
+
+/// Reads a list of input files, one path per line. Empty lines and lines starting with `#` are ignored.
+pub fn read_file_list(path: &str) -> Result<Vec<String>, LoaderError> {
+    let io_error = |source| LoaderError::Io {
+        path: path.to_string(),
+        source,
+    };
+    let mut files = vec![];
+    for line in open(path).map_err(io_error)?.lines() {
+        let line = line.map_err(io_error)?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            files.push(line.to_string());
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    const LINES: [&str; 2] = ["{\"id\":\"1\"}", "{\"id\":\"2\"}"];
+
+    fn temp_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("wiki-json-loader-{}-{}", std::process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn read_lines(path: &str) -> Vec<String> {
+        open(path)
+            .unwrap()
+            .lines()
+            .map(|line| line.unwrap())
+            .collect()
+    }
+
+    // each line is compressed as its own stream, like the multistream dumps
+    fn write_streams<F>(name: &str, compress: F) -> String
+    where
+        F: Fn(&[u8]) -> Vec<u8>,
+    {
+        let path = temp_path(name);
+        let mut file = File::create(&path).unwrap();
+        for line in &LINES {
+            file.write_all(&compress(format!("{}\n", line).as_bytes()))
+                .unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn open_plain_file() {
+        let path = write_streams("input.json", |bytes| bytes.to_vec());
+        assert_eq!(read_lines(&path), LINES);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_gzip_file() {
+        let path = write_streams("input.json.gz", |bytes| {
+            let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(bytes).unwrap();
+            encoder.finish().unwrap()
+        });
+        assert_eq!(read_lines(&path), LINES);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_bzip2_file() {
+        let path = write_streams("input.json.bz2", |bytes| {
+            let mut encoder = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+            encoder.write_all(bytes).unwrap();
+            encoder.finish().unwrap()
+        });
+        assert_eq!(read_lines(&path), LINES);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_zstd_file() {
+        let path = write_streams("input.json.zst", |bytes| {
+            zstd::stream::encode_all(bytes, 0).unwrap()
+        });
+        assert_eq!(read_lines(&path), LINES);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fail_on_corrupt_compressed_file() {
+        let path = temp_path("corrupt.json.gz");
+        fs::write(&path, "not gzip").unwrap();
+        assert!(open(&path).unwrap().lines().any(|line| line.is_err()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn input_dir(name: &str, files: &[&str]) -> String {
+        let dir = temp_path(name);
+        let _ = fs::remove_dir_all(&dir);
+        for file in files {
+            let path = Path::new(&dir).join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn list_stdin_and_single_file() {
+        assert_eq!(list_files(STDIN, &[], &[]).unwrap(), vec![STDIN]);
+        let path = write_streams("single.txt", |bytes| bytes.to_vec());
+        // the patterns are not applied to a file
+        assert_eq!(
+            list_files(&path, &patterns(&["**/*.json"]), &[]).unwrap(),
+            vec![path.clone()]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn list_included_files_once() {
+        let dir = input_dir(
+            "include",
+            &["b/wiki_01.json", "a/wiki_00.json", "a/wiki_00.txt"],
+        );
+        let files = list_files(&dir, &patterns(&["**/*.json", "a/*"]), &[]).unwrap();
+        let expected: Vec<String> = ["a/wiki_00.json", "a/wiki_00.txt", "b/wiki_01.json"]
+            .iter()
+            .map(|file| Path::new(&dir).join(file).to_string_lossy().to_string())
+            .collect();
+        assert_eq!(files, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn list_files_without_excluded() {
+        let dir = input_dir(
+            "exclude",
+            &["a/wiki_00.json", "b/wiki_01.json", "wiki_02.json"],
+        );
+        let files = list_files(&dir, &patterns(&["**/*.json"]), &patterns(&["b/*"])).unwrap();
+        let expected: Vec<String> = ["a/wiki_00.json", "wiki_02.json"]
+            .iter()
+            .map(|file| Path::new(&dir).join(file).to_string_lossy().to_string())
+            .collect();
+        assert_eq!(files, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fail_on_invalid_pattern() {
+        let dir = input_dir("invalid-pattern", &["wiki_00.json"]);
+        assert!(list_files(&dir, &patterns(&["**/[.json"]), &[]).is_err());
+        assert!(list_files(&dir, &patterns(&["**/*.json"]), &patterns(&["[*"])).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_file_list_without_comments() {
+        let path = temp_path("files-from.txt");
+        fs::write(
+            &path,
+            "# dumps\na/wiki_00.json\n\n  b/wiki_01.json  \n#c/wiki_02.json\n",
+        )
+        .unwrap();
+        assert_eq!(
+            read_file_list(&path).unwrap(),
+            vec!["a/wiki_00.json", "b/wiki_01.json"]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
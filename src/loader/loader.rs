@@ -1,70 +1,670 @@
+use crate::error::LoaderError;
+use crate::loader::checkpoint::CheckpointFile;
+use crate::loader::dead_letter::{DeadLetter, DeadLetterWriter, ErrorLimit};
 use crate::loader::document::Document;
+use crate::loader::input::{self, InputFormat, Record};
+use crate::loader::transform::Pipeline;
 use crate::output::azure_search_output::AzureSearchOutput;
 use crate::output::elasticsearch_output::{ElasticsearchOutput, SearchEngine};
+use crate::output::meilisearch_output::MeilisearchOutput;
+use crate::output::opensearch_output::OpenSearchOutput;
+use crate::output::solr_output::SolrOutput;
+use crate::output::tantivy_output::TantivyOutput;
+use crate::output::typesense_output::TypesenseOutput;
+use chrono::{DateTime, Utc};
 use clap::arg_enum;
 use flamer::flame;
-use glob::glob;
-use log::info;
-use rayon::prelude::*;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::Path;
+use log::{error, info, warn};
+use std::collections::{HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Builder;
+use tokio::sync::Semaphore;
+use tokio::task;
+
+const READ_BATCH_SIZE: usize = 1000;
 
 arg_enum! {
+    #[derive(Clone, Copy)]
     pub enum SearchEngineType {
         Elasticsearch,
-        AzureSearch
+        AzureSearch,
+        OpenSearch,
+        Solr,
+        Meilisearch,
+        Typesense,
+        Tantivy
     }
 }
 
+/// Options of a load run that don't belong to a search engine config.
+#[derive(Debug, Default)]
+pub struct LoadOptions {
+    /// JSON Lines file where malformed input lines are written.
+    pub dead_letter_file: Option<String>,
+    /// Abort the load when more than this number of lines are malformed.
+    pub max_errors: Option<usize>,
+    /// Load the rejected documents of a dead-letter file instead of the input directory.
+    pub replay_file: Option<String>,
+    /// JSON file where the progress of each input file is recorded.
+    pub checkpoint_file: Option<String>,
+    /// Skip the files and lines already recorded in `checkpoint_file`.
+    pub resume: bool,
+    /// File listing the input files, one path per line.
+    pub files_from: Option<String>,
+    /// Glob patterns of the files loaded from the input directory. Default depends on `format`.
+    pub include: Vec<String>,
+    /// Glob patterns of the files skipped in the input directory.
+    pub exclude: Vec<String>,
+    pub format: InputFormat,
+    /// Load only the documents revised at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Load only the documents revised before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// Skip the documents whose revision is already indexed.
+    pub sync: bool,
+    /// Delete the indexed documents that are not in the input after loading.
+    pub delete_missing: bool,
+    /// Load into a new generation of the index and switch to it after loading.
+    pub rebuild: bool,
+}
+
+/// State shared by the tasks loading each input file.
+struct LoadContext {
+    config_file: String,
+    search_engine: SearchEngineType,
+    dead_letter: Option<Arc<DeadLetterWriter>>,
+    errors: ErrorLimit,
+    replay: bool,
+    format: InputFormat,
+    checkpoint: Option<CheckpointFile>,
+    pipeline: Pipeline,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    out_of_range: AtomicUsize,
+    sync: bool,
+    // ids in the input, kept only to delete missing documents
+    seen_ids: Option<Mutex<HashSet<String>>>,
+    // the new generation in the rebuild mode
+    generation: Option<String>,
+    // documents accepted by the search engine, to check the new generation
+    indexed: AtomicUsize,
+}
+
+impl LoadContext {
+    fn in_date_range(&self, document: &Document) -> bool {
+        in_date_range(document.timestamp, self.since, self.until)
+    }
+}
+
+// `since` is inclusive and `until` is exclusive
+fn in_date_range(
+    timestamp: DateTime<Utc>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> bool {
+    since.is_none_or(|since| timestamp >= since) && until.is_none_or(|until| timestamp < until)
+}
+
 fn create_search_engine(
     config_file: &str,
     search_engine: &SearchEngineType,
-) -> Box<dyn SearchEngine> {
-    return match search_engine {
-        SearchEngineType::Elasticsearch => Box::new(ElasticsearchOutput::new(config_file)),
-        SearchEngineType::AzureSearch => Box::new(AzureSearchOutput::new(config_file)),
-    };
+) -> Result<Box<dyn SearchEngine>, LoaderError> {
+    Ok(match search_engine {
+        SearchEngineType::Elasticsearch => Box::new(ElasticsearchOutput::new(config_file)?),
+        SearchEngineType::AzureSearch => Box::new(AzureSearchOutput::new(config_file)?),
+        SearchEngineType::OpenSearch => Box::new(OpenSearchOutput::new(config_file)?),
+        SearchEngineType::Solr => Box::new(SolrOutput::new(config_file)?),
+        SearchEngineType::Meilisearch => Box::new(MeilisearchOutput::new(config_file)?),
+        SearchEngineType::Typesense => Box::new(TypesenseOutput::new(config_file)?),
+        SearchEngineType::Tantivy => Box::new(TantivyOutput::new(config_file)?),
+    })
 }
-#[flame]
-fn load_file(filepath: &str, search_engine: &mut Box<dyn SearchEngine>) -> Result<String, String> {
+
+async fn load_file(
+    filepath: String,
+    mut search_engine: Box<dyn SearchEngine>,
+    context: Arc<LoadContext>,
+) -> Result<String, LoaderError> {
+    let progress = match &context.checkpoint {
+        Some(checkpoint) => checkpoint.progress(&filepath),
+        None => Default::default(),
+    };
+    if progress.completed {
+        return Ok(format!("Skip: {}", filepath));
+    }
     info!("Reading {}", filepath);
-    for line in BufReader::new(File::open(filepath).unwrap()).lines() {
-        let d = parse_document(line.unwrap().as_str());
-        search_engine.add_document(d);
+    let io_error = |source| LoaderError::Io {
+        path: filepath.clone(),
+        source,
+    };
+    let mut records = input::records(&filepath, context.format)
+        .map_err(io_error)?
+        .enumerate();
+    // line numbers of the documents that are not acknowledged yet
+    let mut pending_lines = VecDeque::new();
+    let mut acknowledged = 0;
+    let mut added: usize = 0;
+    loop {
+        let (rest, batch) = read_batch(records)
+            .await
+            .map_err(|e| io_error(std::io::Error::other(e)))?;
+        records = rest;
+        if batch.is_empty() {
+            break;
+        }
+        context.errors.check()?;
+        // line_number is the page number for XML dumps
+        for (line_number, record) in batch {
+            if line_number < progress.line {
+                continue;
+            }
+            let parsed = match record.map_err(io_error)? {
+                Record::Document(d) => Ok(Some(*d)),
+                Record::Line(line) => {
+                    parse_document(line.as_str(), context.replay).map_err(|err| (line, err))
+                }
+                Record::Malformed { raw, error } => Err((raw, error)),
+                Record::Skip => Ok(None),
+            };
+            let d = match parsed {
+                Ok(Some(d)) => d,
+                Ok(None) => continue,
+                Err((raw, err)) => {
+                    if let Some(dead_letter) = &context.dead_letter {
+                        dead_letter.write(&DeadLetter::Parse {
+                            path: filepath.clone(),
+                            line: line_number + 1,
+                            error: err.to_string(),
+                            raw,
+                        })?;
+                    }
+                    context.errors.record(&filepath, line_number + 1, &err)?;
+                    continue;
+                }
+            };
+            if let Some(seen_ids) = &context.seen_ids {
+                seen_ids.lock().unwrap().insert(d.id.clone());
+            }
+            if !context.in_date_range(&d) {
+                context.out_of_range.fetch_add(1, Ordering::SeqCst);
+                continue;
+            }
+            if context.checkpoint.is_some() {
+                pending_lines.push_back(line_number + 1);
+            }
+            search_engine
+                .add_document(context.pipeline.apply(d))
+                .await?;
+            added += 1;
+        }
+        if let Some(checkpoint) = &context.checkpoint {
+            let mut last_line = None;
+            while acknowledged < search_engine.acknowledged() {
+                last_line = pending_lines.pop_front();
+                acknowledged += 1;
+            }
+            if let Some(line) = last_line {
+                checkpoint.update(&filepath, line)?;
+            }
+        }
+    }
+    let closed = search_engine.close().await;
+    // the rejected documents are in the dead-letter file, so they don't stop the switch
+    let rejected = match &closed {
+        Err(LoaderError::Rejected { count }) => *count,
+        _ => 0,
+    };
+    context
+        .indexed
+        .fetch_add(added.saturating_sub(rejected), Ordering::SeqCst);
+    closed?;
+    if let Some(checkpoint) = &context.checkpoint {
+        checkpoint.complete(&filepath)?;
     }
-    search_engine.close();
-    Ok(format!("Finish: {}", filepath).to_string())
+    Ok(format!("Finish: {}", filepath))
 }
 
-fn parse_document(_line: &str) -> Document {
-    Document::new(_line)
+/// Reads the next records on the blocking thread pool, so that file IO doesn't stall bulk requests.
+async fn read_batch<I>(mut lines: I) -> Result<(I, Vec<I::Item>), task::JoinError>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    task::spawn_blocking(move || {
+        let batch: Vec<_> = lines.by_ref().take(READ_BATCH_SIZE).collect();
+        (lines, batch)
+    })
+    .await
 }
 
-#[flame]
-pub fn load(
-    input_dir: &str,
+fn parse_document(_line: &str, replay: bool) -> Result<Option<Document>, serde_json::Error> {
+    if !replay {
+        return Document::new(_line).map(Some);
+    }
+    match serde_json::from_str(_line)? {
+        DeadLetter::Rejected { document, .. } => serde_json::from_value(document).map(Some),
+        // malformed lines have no document to replay
+        DeadLetter::Parse { .. } => Ok(None),
+    }
+}
+
+async fn load_files(
+    input: &str,
     config_file: &str,
-    search_engine: &SearchEngineType,
-) -> Result<(), String> {
-    // TODO
-    let path = Path::new(input_dir).join(Path::new("**/*.json"));
-    let initializer = create_search_engine(config_file, &search_engine);
-    initializer.initialize();
-    // read files from input_dir
-    let files: Vec<_> = glob(path.to_str().unwrap())
-        .unwrap()
-        .filter_map(|x| x.ok())
-        .collect();
-    files
-        .par_iter()
+    search_engine: SearchEngineType,
+    options: &LoadOptions,
+) -> Result<(), LoaderError> {
+    let pipeline = Pipeline::load(config_file)?;
+    if options.replay_file.is_some() && options.replay_file == options.dead_letter_file {
+        return Err(LoaderError::Io {
+            path: options.replay_file.clone().unwrap_or_default(),
+            source: std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "the dead-letter file must differ from the replayed file",
+            ),
+        });
+    }
+    let dead_letter = match &options.dead_letter_file {
+        Some(dead_letter_file) => Some(Arc::new(DeadLetterWriter::create(
+            dead_letter_file,
+            options.resume,
+        )?)),
+        None => None,
+    };
+    let checkpoint = match &options.checkpoint_file {
+        Some(checkpoint_file) => Some(CheckpointFile::open(checkpoint_file, options.resume)?),
+        None => None,
+    };
+    let files: Vec<String> = match &options.replay_file {
+        Some(replay_file) => vec![replay_file.clone()],
+        None => {
+            let mut files = match &options.files_from {
+                Some(files_from) => input::read_file_list(files_from)?,
+                None => vec![],
+            };
+            if !input.is_empty() {
+                let include = if options.include.is_empty() {
+                    options.format.default_patterns()
+                } else {
+                    options.include.clone()
+                };
+                files.extend(input::list_files(input, &include, &options.exclude)?);
+            }
+            files
+        }
+    };
+    // the index settings may be changed from here until `finalize`
+    let initializer = create_search_engine(config_file, &search_engine)?;
+    let generation = if options.rebuild {
+        Some(initializer.create_generation().await?)
+    } else {
+        initializer.initialize().await?;
+        None
+    };
+    let context = Arc::new(LoadContext {
+        config_file: config_file.to_string(),
+        search_engine,
+        dead_letter,
+        errors: ErrorLimit::new(options.max_errors),
+        replay: options.replay_file.is_some(),
+        // the dead-letter file is always JSON Lines
+        format: if options.replay_file.is_some() {
+            InputFormat::Json
+        } else {
+            options.format
+        },
+        checkpoint,
+        pipeline,
+        since: options.since,
+        until: options.until,
+        out_of_range: AtomicUsize::new(0),
+        sync: options.sync,
+        seen_ids: if options.delete_missing {
+            Some(Mutex::new(HashSet::new()))
+        } else {
+            None
+        },
+        generation,
+        indexed: AtomicUsize::new(0),
+    });
+    let semaphore = Arc::new(Semaphore::new(num_cpus::get()));
+    let tasks: Vec<_> = files
+        .iter()
         .map(|filepath| {
             // read JSONs from file
             // create output instance search_engine_type
-            let mut search_engine = create_search_engine(config_file, &search_engine);
-            load_file(filepath.to_str().unwrap(), &mut search_engine)
+            let filepath = filepath.clone();
+            let context = context.clone();
+            let semaphore = semaphore.clone();
+            tokio::spawn(async move {
+                let _permit = semaphore.acquire().await;
+                let result =
+                    match create_search_engine(&context.config_file, &context.search_engine) {
+                        Ok(mut search_engine) => {
+                            if let Some(dead_letter) = &context.dead_letter {
+                                search_engine.set_dead_letter(dead_letter.clone());
+                            }
+                            search_engine.set_sync(context.sync);
+                            if let Some(generation) = &context.generation {
+                                search_engine.set_index(generation);
+                            }
+                            load_file(filepath.clone(), search_engine, context).await
+                        }
+                        Err(err) => Err(err),
+                    };
+                (filepath, result)
+            })
         })
-        .filter_map(|x| x.ok())
-        .collect::<String>();
-    Ok(())
+        .collect();
+    let mut failures: Vec<(String, LoaderError)> = vec![];
+    let mut joined = Ok(());
+    for task in tasks {
+        match task.await {
+            Ok((_, Ok(msg))) => info!("{}", msg),
+            Ok((filepath, Err(err))) => {
+                error!("Failed: {}. {}", filepath, err);
+                failures.push((filepath, err));
+            }
+            Err(e) => {
+                // the other tasks are still awaited, so nothing is loaded after `finalize`
+                if joined.is_ok() {
+                    joined = Err(LoaderError::Transport(format!("load task failed. {}", e)));
+                }
+            }
+        }
+    }
+    let flushed = match &context.dead_letter {
+        Some(dead_letter) => dead_letter.flush(),
+        None => Ok(()),
+    };
+    // the index settings are restored even if the load failed
+    let finalized = initializer.finalize().await;
+    joined?;
+    flushed?;
+    finalized?;
+    let out_of_range = context.out_of_range.load(Ordering::SeqCst);
+    if out_of_range > 0 {
+        info!(
+            "{} documents out of the date range were skipped.",
+            out_of_range
+        );
+    }
+    if context.errors.count() > 0 {
+        warn!("{} malformed lines were skipped.", context.errors.count());
+    }
+    if let Some(seen_ids) = &context.seen_ids {
+        if failures.is_empty() && context.errors.count() == 0 {
+            let seen_ids = std::mem::take(&mut *seen_ids.lock().unwrap());
+            let deleted = initializer.delete_missing(&seen_ids).await?;
+            info!("{} documents missing from the input were deleted.", deleted);
+        } else {
+            warn!("Skip deleting missing documents because some input could not be loaded.");
+        }
+    }
+    if let Some(generation) = &context.generation {
+        // min_doc_ratio decides whether the rejected documents are acceptable
+        let incomplete = failures
+            .iter()
+            .any(|(_, err)| !matches!(err, LoaderError::Rejected { .. }));
+        if !incomplete {
+            initializer
+                .switch_generation(generation, context.indexed.load(Ordering::SeqCst))
+                .await?;
+        } else {
+            warn!(
+                "{} index was not switched because some input could not be loaded.",
+                generation
+            );
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(LoaderError::Incomplete {
+            total: files.len(),
+            failures,
+        })
+    }
+}
+
+#[flame]
+pub fn load(
+    input: &str,
+    config_file: &str,
+    search_engine: &SearchEngineType,
+    options: &LoadOptions,
+) -> Result<(), LoaderError> {
+    let mut runtime = Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .map_err(LoaderError::Runtime)?;
+    runtime.block_on(load_files(input, config_file, *search_engine, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::transform::TransformedDocument;
+    use crate::output::test_support::block_on;
+    use async_trait::async_trait;
+    use chrono::TimeZone;
+    use serde_json::json;
+    use std::fs;
+    use std::sync::Mutex;
+
+    /// Keeps the ids of the added documents. The last `lag` documents are never acknowledged,
+    /// and closing fails if `lag` is not zero, like an interrupted load.
+    struct LaggingEngine {
+        ids: Arc<Mutex<Vec<String>>>,
+        lag: usize,
+    }
+
+    #[async_trait]
+    impl SearchEngine for LaggingEngine {
+        fn new(_config_file: &str) -> Result<Self, LoaderError> {
+            unimplemented!()
+        }
+        fn set_dead_letter(&mut self, _dead_letter: Arc<DeadLetterWriter>) {}
+        fn set_sync(&mut self, _sync: bool) {}
+        async fn add_document(&mut self, document: TransformedDocument) -> Result<(), LoaderError> {
+            self.ids.lock().unwrap().push(document.source.id);
+            Ok(())
+        }
+        fn acknowledged(&self) -> usize {
+            self.ids.lock().unwrap().len().saturating_sub(self.lag)
+        }
+        async fn initialize(&self) -> Result<(), LoaderError> {
+            Ok(())
+        }
+        async fn exist_index(&self) -> Result<bool, LoaderError> {
+            Ok(true)
+        }
+        async fn close(&mut self) -> Result<(), LoaderError> {
+            match self.lag {
+                0 => Ok(()),
+                _ => Err(LoaderError::Transport(String::from("interrupted"))),
+            }
+        }
+        async fn finalize(&self) -> Result<(), LoaderError> {
+            Ok(())
+        }
+        async fn delete_missing(&self, _ids: &HashSet<String>) -> Result<usize, LoaderError> {
+            Ok(0)
+        }
+        fn set_index(&mut self, _index_name: &str) {}
+        async fn create_generation(&self) -> Result<String, LoaderError> {
+            unimplemented!()
+        }
+        async fn switch_generation(
+            &self,
+            _generation: &str,
+            _loaded: usize,
+        ) -> Result<(), LoaderError> {
+            unimplemented!()
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("wiki-json-loader-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn line(id: &str) -> String {
+        json!({
+            "id": id,
+            "revision_id": "1",
+            "title": id,
+            "timestamp": "2020-06-01T12:34:56Z",
+            "contents": [],
+            "headings": [],
+            "categories": [],
+            "images": [],
+            "links": []
+        })
+        .to_string()
+    }
+
+    fn context(checkpoint: CheckpointFile) -> Arc<LoadContext> {
+        Arc::new(LoadContext {
+            config_file: String::new(),
+            search_engine: SearchEngineType::Elasticsearch,
+            dead_letter: None,
+            errors: ErrorLimit::new(None),
+            replay: false,
+            format: InputFormat::Json,
+            checkpoint: Some(checkpoint),
+            pipeline: Pipeline::default(),
+            since: None,
+            until: None,
+            out_of_range: AtomicUsize::new(0),
+            sync: false,
+            seen_ids: None,
+            generation: None,
+            indexed: AtomicUsize::new(0),
+        })
+    }
+
+    // loads `input` and returns the ids sent to the search engine
+    fn load(
+        input: &str,
+        checkpoint: CheckpointFile,
+        lag: usize,
+    ) -> (Vec<String>, Result<String, LoaderError>) {
+        let ids = Arc::new(Mutex::new(vec![]));
+        let engine = LaggingEngine {
+            ids: ids.clone(),
+            lag,
+        };
+        let result = block_on(load_file(
+            input.to_string(),
+            Box::new(engine),
+            context(checkpoint),
+        ));
+        let ids = ids.lock().unwrap().clone();
+        (ids, result)
+    }
+
+    #[test]
+    fn resume_from_acknowledged_line() {
+        let input = temp_path("resume.json");
+        let checkpoint_file = temp_path("resume-checkpoint.json");
+        let lines = [
+            line("a"),
+            String::from("{malformed"),
+            line("b"),
+            line("c"),
+            line("d"),
+        ];
+        fs::write(&input, lines.join("\n")).unwrap();
+
+        // c and d are sent but not acknowledged, so b on line 3 is the last recorded line
+        let checkpoint = CheckpointFile::open(&checkpoint_file, false).unwrap();
+        let (ids, result) = load(&input, checkpoint, 2);
+        assert_eq!(ids, vec!["a", "b", "c", "d"]);
+        assert!(result.is_err());
+        let progress = CheckpointFile::open(&checkpoint_file, true)
+            .unwrap()
+            .progress(&input);
+        assert_eq!((progress.completed, progress.line), (false, 3));
+
+        let checkpoint = CheckpointFile::open(&checkpoint_file, true).unwrap();
+        let (ids, result) = load(&input, checkpoint, 0);
+        assert_eq!(ids, vec!["c", "d"]);
+        assert!(result.is_ok());
+        assert!(
+            CheckpointFile::open(&checkpoint_file, true)
+                .unwrap()
+                .progress(&input)
+                .completed
+        );
+
+        // a completed file is skipped
+        let checkpoint = CheckpointFile::open(&checkpoint_file, true).unwrap();
+        let (ids, result) = load(&input, checkpoint, 0);
+        assert!(ids.is_empty());
+        assert!(result.unwrap().starts_with("Skip"));
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&checkpoint_file).unwrap();
+    }
+
+    #[test]
+    fn replay_rejected_documents() {
+        let path = temp_path("replay.jsonl");
+        let writer = DeadLetterWriter::create(&path, false).unwrap();
+        writer
+            .write(&DeadLetter::Parse {
+                path: String::from("input.json"),
+                line: 1,
+                error: String::from("EOF while parsing an object"),
+                raw: String::from("{broken"),
+            })
+            .unwrap();
+        let document: serde_json::Value = serde_json::from_str(&line("1")).unwrap();
+        writer
+            .write(&DeadLetter::Rejected {
+                id: String::from("1"),
+                error_type: String::from("mapper_parsing_exception"),
+                reason: String::from("failed to parse"),
+                document,
+            })
+            .unwrap();
+        writer.flush().unwrap();
+        let lines: Vec<String> = input::records(&path, InputFormat::Json)
+            .unwrap()
+            .map(|record| match record.unwrap() {
+                Record::Line(line) => line,
+                _ => panic!("not a line"),
+            })
+            .collect();
+        assert_eq!(lines.len(), 2);
+        // malformed lines have no document
+        assert!(parse_document(&lines[0], true).unwrap().is_none());
+        let replayed = parse_document(&lines[1], true).unwrap().unwrap();
+        assert_eq!(replayed.id, "1");
+        assert_eq!(replayed.revision_id, "1");
+        // a dead-letter entry is not a document without --replay
+        assert!(parse_document(&lines[1], false).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn since_is_inclusive_and_until_is_exclusive() {
+        let since = Utc.ymd(2020, 7, 1).and_hms(0, 0, 0);
+        let until = Utc.ymd(2020, 8, 1).and_hms(0, 0, 0);
+        let second = chrono::Duration::seconds(1);
+        assert!(in_date_range(since, Some(since), Some(until)));
+        assert!(!in_date_range(since - second, Some(since), Some(until)));
+        assert!(in_date_range(until - second, Some(since), Some(until)));
+        assert!(!in_date_range(until, Some(since), Some(until)));
+        assert!(in_date_range(since - second, None, Some(until)));
+        assert!(in_date_range(until, Some(since), None));
+        assert!(in_date_range(since, None, None));
+        // a range where since equals until is empty
+        assert!(!in_date_range(since, Some(since), Some(since)));
+    }
 }
@@ -0,0 +1,392 @@
+use crate::error::LoaderError;
+use crate::loader::document::Document;
+use crate::output::elasticsearch_output::read_config;
+use log::warn;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// A step of the transform pipeline. Put the steps under `transforms:` in the config yaml.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Transform {
+    Drop {
+        fields: Vec<String>,
+    },
+    Copy {
+        from: String,
+        to: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    /// Truncates a string, or each string of an array, to `max_chars` characters.
+    Truncate {
+        field: String,
+        max_chars: usize,
+    },
+    JoinArray {
+        field: String,
+        #[serde(default = "default_separator")]
+        separator: String,
+    },
+    /// Lowercases a string, or each string of an array.
+    Lowercase {
+        field: String,
+    },
+    SetConstant {
+        field: String,
+        value: Value,
+    },
+}
+
+fn default_separator() -> String {
+    String::from(" ")
+}
+
+#[derive(Debug, Deserialize)]
+struct TransformConfig {
+    #[serde(default)]
+    transforms: Vec<Transform>,
+    // older settings of the Azure Search output
+    #[serde(default)]
+    drop_fields: Vec<String>,
+    #[serde(default)]
+    copy_fields: Vec<String>,
+}
+
+/// A document after the transform pipeline.
+pub struct TransformedDocument {
+    /// Fields sent to the search engine.
+    pub fields: HashMap<String, Value>,
+    /// The parsed document, kept for the dead-letter file if the document is rejected.
+    pub source: Document,
+}
+
+/// Transforms the fields of every document in the same way for all search engines.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    transforms: Vec<Transform>,
+}
+
+impl Pipeline {
+    /// Reads the `transforms` in the search engine config file.
+    /// `drop_fields` and `copy_fields` (`source=>target`) are run before them.
+    /// A malformed `copy_fields` entry is skipped with a warning, as it always was.
+    pub fn load(config_file: &str) -> Result<Self, LoaderError> {
+        let config: TransformConfig = read_config(config_file)?;
+        let mut transforms = vec![];
+        if !config.drop_fields.is_empty() {
+            transforms.push(Transform::Drop {
+                fields: config.drop_fields,
+            });
+        }
+        for copy_field in config.copy_fields {
+            match parse_copy_field(&copy_field) {
+                Some((from, to)) => transforms.push(Transform::Copy { from, to }),
+                None => warn!("copy_fields setting parse error. setting is {}", copy_field),
+            }
+        }
+        transforms.extend(config.transforms);
+        Ok(Pipeline { transforms })
+    }
+
+    pub fn apply(&self, document: Document) -> TransformedDocument {
+        let mut fields = document.to_hashmap();
+        for transform in &self.transforms {
+            transform.apply(&mut fields);
+        }
+        TransformedDocument {
+            fields,
+            source: document,
+        }
+    }
+}
+
+impl Transform {
+    fn apply(&self, fields: &mut HashMap<String, Value>) {
+        match self {
+            Transform::Drop { fields: names } => {
+                for name in names {
+                    fields.remove(name);
+                }
+            }
+            Transform::Copy { from, to } => match fields.get(from) {
+                Some(value) => {
+                    let value = value.clone();
+                    fields.insert(to.clone(), value);
+                }
+                None => warn!("there is no {} field in document.", from),
+            },
+            Transform::Rename { from, to } => match fields.remove(from) {
+                Some(value) => {
+                    fields.insert(to.clone(), value);
+                }
+                None => warn!("there is no {} field in document.", from),
+            },
+            Transform::Truncate { field, max_chars } => {
+                map_strings(fields, field, |s| s.chars().take(*max_chars).collect())
+            }
+            Transform::JoinArray { field, separator } => {
+                if let Some(Value::Array(values)) = fields.get(field) {
+                    let joined = values
+                        .iter()
+                        .map(|value| match value {
+                            Value::String(s) => s.clone(),
+                            other => other.to_string(),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(separator);
+                    fields.insert(field.clone(), Value::from(joined));
+                }
+            }
+            Transform::Lowercase { field } => map_strings(fields, field, |s| s.to_lowercase()),
+            Transform::SetConstant { field, value } => {
+                fields.insert(field.clone(), value.clone());
+            }
+        }
+    }
+}
+
+// applies `f` to a string field, or to each string of an array field
+fn map_strings<F>(fields: &mut HashMap<String, Value>, field: &str, f: F)
+where
+    F: Fn(&str) -> String,
+{
+    match fields.get_mut(field) {
+        Some(Value::String(s)) => *s = f(s),
+        Some(Value::Array(values)) => {
+            for value in values {
+                if let Value::String(s) = value {
+                    *s = f(s);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn parse_copy_field(setting: &str) -> Option<(String, String)> {
+    let splitted_setting: Vec<&str> = setting.split("=>").collect();
+    if splitted_setting.len() == 2 {
+        Some((
+            splitted_setting[0].to_string(),
+            splitted_setting[1].to_string(),
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    fn document() -> Document {
+        let line = json!({
+            "id": "1",
+            "revision_id": "100",
+            "title": "東京都 Tokyo",
+            "timestamp": "2020-06-01T12:34:56Z",
+            "contents": ["First Paragraph.", "Second Paragraph."],
+            "headings": ["History", "Geography"],
+            "categories": ["Cities"],
+            "images": [],
+            "links": [{"link_target": "Japan"}]
+        });
+        Document::new(&line.to_string()).unwrap()
+    }
+
+    fn transformed(transforms: Vec<Transform>) -> HashMap<String, Value> {
+        Pipeline { transforms }.apply(document()).fields
+    }
+
+    fn config_file(name: &str, yaml: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "wiki-json-loader-{}-{}.yaml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, yaml).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn drop_fields() {
+        let fields = transformed(vec![Transform::Drop {
+            fields: vec![String::from("links"), String::from("missing")],
+        }]);
+        assert!(!fields.contains_key("links"));
+        assert!(fields.contains_key("images"));
+    }
+
+    #[test]
+    fn copy_field() {
+        let fields = transformed(vec![
+            Transform::Copy {
+                from: String::from("title"),
+                to: String::from("title_ngram"),
+            },
+            Transform::Copy {
+                from: String::from("missing"),
+                to: String::from("other"),
+            },
+        ]);
+        assert_eq!(fields["title"], json!("東京都 Tokyo"));
+        assert_eq!(fields["title_ngram"], json!("東京都 Tokyo"));
+        assert!(!fields.contains_key("other"));
+    }
+
+    #[test]
+    fn rename_field() {
+        let fields = transformed(vec![Transform::Rename {
+            from: String::from("contents"),
+            to: String::from("body"),
+        }]);
+        assert!(!fields.contains_key("contents"));
+        assert_eq!(
+            fields["body"],
+            json!(["First Paragraph.", "Second Paragraph."])
+        );
+    }
+
+    #[test]
+    fn truncate_field() {
+        let fields = transformed(vec![
+            Transform::Truncate {
+                field: String::from("contents"),
+                max_chars: 5,
+            },
+            Transform::Truncate {
+                field: String::from("revision_id"),
+                max_chars: 10,
+            },
+        ]);
+        assert_eq!(fields["contents"], json!(["First", "Secon"]));
+        assert_eq!(fields["revision_id"], json!("100"));
+    }
+
+    #[test]
+    fn truncate_at_multibyte_character() {
+        // "東京都" is 9 bytes, so a byte limit would split a character
+        let fields = transformed(vec![Transform::Truncate {
+            field: String::from("title"),
+            max_chars: 2,
+        }]);
+        assert_eq!(fields["title"], json!("東京"));
+    }
+
+    #[test]
+    fn join_array_field() {
+        let fields = transformed(vec![
+            Transform::JoinArray {
+                field: String::from("headings"),
+                separator: String::from(" / "),
+            },
+            Transform::JoinArray {
+                field: String::from("title"),
+                separator: default_separator(),
+            },
+        ]);
+        assert_eq!(fields["headings"], json!("History / Geography"));
+        // not an array
+        assert_eq!(fields["title"], json!("東京都 Tokyo"));
+
+        let transform: Transform =
+            serde_yaml::from_str("type: join_array\nfield: contents").unwrap();
+        let fields = transformed(vec![transform]);
+        assert_eq!(
+            fields["contents"],
+            json!("First Paragraph. Second Paragraph.")
+        );
+    }
+
+    #[test]
+    fn lowercase_field() {
+        let fields = transformed(vec![
+            Transform::Lowercase {
+                field: String::from("title"),
+            },
+            Transform::Lowercase {
+                field: String::from("headings"),
+            },
+        ]);
+        assert_eq!(fields["title"], json!("東京都 tokyo"));
+        assert_eq!(fields["headings"], json!(["history", "geography"]));
+    }
+
+    #[test]
+    fn set_constant_field() {
+        let fields = transformed(vec![
+            Transform::SetConstant {
+                field: String::from("source"),
+                value: json!("jawiki"),
+            },
+            Transform::SetConstant {
+                field: String::from("title"),
+                value: json!(["replaced"]),
+            },
+        ]);
+        assert_eq!(fields["source"], json!("jawiki"));
+        assert_eq!(fields["title"], json!(["replaced"]));
+    }
+
+    #[test]
+    fn load_transforms_after_legacy_settings() {
+        let path = config_file(
+            "transforms",
+            r#"index_name: wiki
+drop_fields: ["images", "title_ngram"]
+copy_fields: ["title=>title_ngram"]
+transforms:
+  - type: lowercase
+    field: title_ngram
+  - type: rename
+    from: links
+    to: related
+"#,
+        );
+        let pipeline = Pipeline::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let fields = pipeline.apply(document()).fields;
+        assert!(!fields.contains_key("images"));
+        // title_ngram is dropped before it is copied
+        assert_eq!(fields["title_ngram"], json!("東京都 tokyo"));
+        assert_eq!(fields["title"], json!("東京都 Tokyo"));
+        assert!(!fields.contains_key("links"));
+        assert_eq!(fields["related"], json!([{"link_target": "Japan"}]));
+    }
+
+    #[test]
+    fn load_without_transforms() {
+        let path = config_file("no-transforms", "index_name: wiki\n");
+        let pipeline = Pipeline::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(pipeline.transforms.is_empty());
+    }
+
+    #[test]
+    fn skip_invalid_copy_fields() {
+        let path = config_file(
+            "invalid-copy-fields",
+            "copy_fields: [\"title->title2\", \"title=>title2\"]\n",
+        );
+        let pipeline = Pipeline::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(pipeline.transforms.len(), 1);
+        let fields = pipeline.apply(document()).fields;
+        assert_eq!(fields["title2"], fields["title"]);
+    }
+
+    #[test]
+    fn parse_copy_fields() {
+        assert_eq!(
+            parse_copy_field("title=>title_ngram"),
+            Some((String::from("title"), String::from("title_ngram")))
+        );
+        assert_eq!(parse_copy_field("title"), None);
+        assert_eq!(parse_copy_field("a=>b=>c"), None);
+    }
+}
@@ -0,0 +1,626 @@
+use crate::loader::document::{parse_timestamp, Document, Image, ImageType, Link, Text};
+use crate::loader::input::Record;
+use lazy_static::lazy_static;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use regex::Regex;
+use serde::de::Error;
+use std::io::{self, BufRead, ErrorKind};
+
+lazy_static! {
+    static ref COMMENT: Regex = Regex::new(r"(?s)<!--.*?-->").unwrap();
+    static ref EMPTY_REF: Regex = Regex::new(r"<ref[^>]*/>").unwrap();
+    static ref REF: Regex = Regex::new(r"(?s)<ref(\s[^>]*)?>.*?</ref>").unwrap();
+    static ref NON_TEXT_TAG: Regex = Regex::new(
+        r"(?s)<(math|gallery|timeline|syntaxhighlight|source|score|graph|mapframe)\b[^>]*>.*?</(math|gallery|timeline|syntaxhighlight|source|score|graph|mapframe)>"
+    )
+    .unwrap();
+    static ref TAG: Regex = Regex::new(r"</?[a-zA-Z][^>]*>").unwrap();
+    static ref EXTERNAL_LINK: Regex = Regex::new(r"\[(https?://[^\s\]]+)(?:\s+([^\]]*))?\]").unwrap();
+    static ref EMPHASIS: Regex = Regex::new(r"'{2,}").unwrap();
+    static ref MAGIC_WORD: Regex = Regex::new(r"__[A-Z]+__").unwrap();
+    static ref HEADING: Regex = Regex::new(r"^(={1,6})\s*(.+?)\s*={1,6}$").unwrap();
+    static ref IMAGE_SIZE: Regex = Regex::new(r"^(\d+)?(x\d+)?px$").unwrap();
+}
+
+const ENTITIES: [(&str, &str); 6] = [
+    ("&nbsp;", " "),
+    ("&ndash;", "–"),
+    ("&mdash;", "—"),
+    ("&lt;", "<"),
+    ("&gt;", ">"),
+    ("&amp;", "&"),
+];
+
+const IMAGE_OPTIONS: [&str; 16] = [
+    "thumb",
+    "thumbnail",
+    "frame",
+    "framed",
+    "frameless",
+    "border",
+    "left",
+    "right",
+    "center",
+    "centre",
+    "none",
+    "upright",
+    "サムネイル",
+    "左",
+    "右",
+    "中央",
+];
+
+/// Fields of a `<page>` element.
+#[derive(Default, Serialize)]
+struct Page {
+    id: String,
+    title: String,
+    ns: String,
+    #[serde(skip)]
+    redirect: bool,
+    revision_id: String,
+    timestamp: String,
+    text: String,
+}
+
+impl Page {
+    /// Returns the error and the page as JSON if it cannot be a document.
+    fn into_document(self) -> Result<Document, (String, String)> {
+        let timestamp = match parse_timestamp(&self.timestamp) {
+            Ok(timestamp) => timestamp,
+            Err(message) => return Err((message, serde_json::to_string(&self).unwrap())),
+        };
+        let mut wikitext = Wikitext::default();
+        wikitext.parse(&self.text);
+        Ok(Document {
+            id: self.id,
+            revision_id: self.revision_id,
+            title: self.title,
+            timestamp,
+            contents: wikitext.contents,
+            headings: wikitext.headings,
+            categories: wikitext.categories,
+            images: wikitext.images,
+            links: wikitext.links,
+            opening_text: None,
+            incoming_links: None,
+            popularity_score: None,
+        })
+    }
+}
+
+/// Streams the articles of a Wikipedia XML dump (pages-articles.xml) as documents.
+/// Redirects and pages outside the main namespace are skipped. Pages with an invalid timestamp
+/// are returned as malformed records with the fields of the page as JSON.
+pub struct WikiXmlReader<R: BufRead> {
+    reader: Reader<R>,
+    buf: Vec<u8>,
+    finished: bool,
+}
+
+impl<R: BufRead> WikiXmlReader<R> {
+    pub fn new(reader: R) -> Self {
+        WikiXmlReader {
+            reader: Reader::from_reader(reader),
+            buf: Vec::new(),
+            finished: false,
+        }
+    }
+
+    fn read_page(&mut self) -> Result<Option<Page>, quick_xml::Error> {
+        let mut page: Option<Page> = None;
+        // element names under <page>
+        let mut path: Vec<String> = vec![];
+        loop {
+            self.buf.clear();
+            match self.reader.read_event(&mut self.buf)? {
+                Event::Start(e) => {
+                    if e.name() == b"page" {
+                        page = Some(Page::default());
+                        path.clear();
+                    } else if page.is_some() {
+                        path.push(String::from_utf8_lossy(e.name()).to_string());
+                    }
+                }
+                Event::Empty(e) => {
+                    if let Some(page) = page.as_mut() {
+                        if e.name() == b"redirect" {
+                            page.redirect = true;
+                        }
+                    }
+                }
+                Event::Text(e) => {
+                    if let Some(page) = page.as_mut() {
+                        let field = match path.join("/").as_str() {
+                            "id" => &mut page.id,
+                            "title" => &mut page.title,
+                            "ns" => &mut page.ns,
+                            "revision/id" => &mut page.revision_id,
+                            "revision/timestamp" => &mut page.timestamp,
+                            "revision/text" => &mut page.text,
+                            _ => continue,
+                        };
+                        field.push_str(&e.unescape_and_decode(&self.reader)?);
+                    }
+                }
+                Event::End(e) => {
+                    if e.name() == b"page" {
+                        return Ok(page);
+                    }
+                    path.pop();
+                }
+                Event::Eof => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for WikiXmlReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            match self.read_page() {
+                Ok(Some(page)) => {
+                    if page.ns.trim() == "0" && !page.redirect {
+                        return Some(Ok(match page.into_document() {
+                            Ok(document) => Record::Document(Box::new(document)),
+                            Err((message, raw)) => Record::Malformed {
+                                raw,
+                                error: serde_json::Error::custom(message),
+                            },
+                        }));
+                    }
+                }
+                Ok(None) => self.finished = true,
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "cannot read XML dump at {}. {}",
+                            self.reader.buffer_position(),
+                            err
+                        ),
+                    )));
+                }
+            }
+        }
+        None
+    }
+}
+
+enum Namespace {
+    Category,
+    Image(ImageType),
+}
+
+fn namespace(target: &str) -> Option<(Namespace, &str)> {
+    let (prefix, name) = target.split_at(target.find(':')?);
+    let namespace = match prefix.trim().to_lowercase().as_str() {
+        "category" | "カテゴリ" => Namespace::Category,
+        "image" | "画像" => Namespace::Image(ImageType::Image),
+        "file" | "ファイル" => Namespace::Image(ImageType::File),
+        _ => return None,
+    };
+    Some((namespace, name[1..].trim()))
+}
+
+// options like `thumb`, `200px` and `alt=...`. The other parameter is the caption.
+fn is_image_option(param: &str) -> bool {
+    IMAGE_OPTIONS.contains(&param)
+        || IMAGE_SIZE.is_match(param)
+        || (param.contains('=') && !param.contains("[["))
+}
+
+/// Finds the `]]` closing a `[[`, skipping nested links.
+fn find_closing(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        if bytes[i] == b'[' && bytes[i + 1] == b'[' {
+            depth += 1;
+            i += 2;
+        } else if bytes[i] == b']' && bytes[i + 1] == b']' {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Splits the parameters of a link by `|`, except in nested links.
+fn split_params(text: &str) -> Vec<&str> {
+    let bytes = text.as_bytes();
+    let mut params = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for i in 0..bytes.len() {
+        match bytes[i] {
+            b'[' if bytes.get(i + 1) == Some(&b'[') => depth += 1,
+            b']' if bytes.get(i + 1) == Some(&b']') && depth > 0 => depth -= 1,
+            b'|' if depth == 0 => {
+                params.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(&text[start..]);
+    params
+}
+
+/// Removes the blocks between `open` and `close`, like templates and tables.
+/// If `line_start` is true, the blocks are recognized only at the start of a line.
+fn remove_blocks(text: &str, open: &str, close: &str, line_start: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut depth = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let at_line_start = line_start && (result.is_empty() || result.ends_with('\n'));
+        if rest.starts_with(open) && (!line_start || at_line_start || depth > 0) {
+            depth += 1;
+            rest = &rest[open.len()..];
+        } else if depth > 0 && rest.starts_with(close) {
+            depth -= 1;
+            rest = &rest[close.len()..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            if depth == 0 || (line_start && c == '\n') {
+                result.push(c);
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    result
+}
+
+/// Plain text, headings, categories, images and links extracted from wikitext.
+#[derive(Default)]
+struct Wikitext {
+    contents: Vec<String>,
+    headings: Vec<String>,
+    categories: Vec<String>,
+    images: Vec<Image>,
+    links: Vec<Link>,
+}
+
+impl Wikitext {
+    fn parse(&mut self, text: &str) {
+        let text = COMMENT.replace_all(text, "");
+        let text = EMPTY_REF.replace_all(&text, "");
+        let text = REF.replace_all(&text, "");
+        let text = NON_TEXT_TAG.replace_all(&text, "");
+        let text = remove_blocks(&text, "{{", "}}", false);
+        let text = remove_blocks(&text, "{|", "|}", true);
+        let text = self.replace_links(&text);
+        let text = self.replace_external_links(&text);
+        let text = TAG.replace_all(&text, "");
+        let text = EMPHASIS.replace_all(&text, "");
+        let text = MAGIC_WORD.replace_all(&text, "");
+        let mut text = text.to_string();
+        for (entity, c) in ENTITIES.iter() {
+            text = text.replace(entity, c);
+        }
+
+        let mut paragraph: Vec<&str> = vec![];
+        for line in text.lines().map(|line| line.trim()) {
+            if let Some(caps) = HEADING.captures(line) {
+                self.push_paragraph(&mut paragraph);
+                self.headings.push(caps[2].to_string());
+            } else if line.is_empty() {
+                self.push_paragraph(&mut paragraph);
+            } else {
+                // list items
+                let line = line.trim_start_matches(['*', '#', ':', ';']);
+                if !line.trim().is_empty() {
+                    paragraph.push(line.trim());
+                }
+            }
+        }
+        self.push_paragraph(&mut paragraph);
+    }
+
+    fn push_paragraph(&mut self, paragraph: &mut Vec<&str>) {
+        if !paragraph.is_empty() {
+            self.contents.push(paragraph.join("\n"));
+            paragraph.clear();
+        }
+    }
+
+    /// Replaces `[[...]]` with the displayed text. Categories and images are removed from the text.
+    fn replace_links(&mut self, text: &str) -> String {
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("[[") {
+            result.push_str(&rest[..start]);
+            let inner = &rest[start + 2..];
+            match find_closing(inner) {
+                Some(end) => {
+                    result.push_str(&self.link(&inner[..end]));
+                    rest = &inner[end + 2..];
+                }
+                None => {
+                    result.push_str("[[");
+                    rest = inner;
+                }
+            }
+        }
+        result.push_str(rest);
+        result
+    }
+
+    fn link(&mut self, inner: &str) -> String {
+        let params = split_params(inner);
+        let target = params[0].trim();
+        match namespace(target) {
+            Some((Namespace::Category, name)) => {
+                self.categories.push(name.to_string());
+                String::new()
+            }
+            Some((Namespace::Image(target_type), name)) => {
+                self.image(name, target_type, &params[1..]);
+                String::new()
+            }
+            None => {
+                let target = target.trim_start_matches(':');
+                self.links.push(Link::Link {
+                    link_target: target.to_string(),
+                });
+                match params.get(1) {
+                    Some(label) if !label.trim().is_empty() => {
+                        self.replace_links(&params[1..].join("|"))
+                    }
+                    _ => target.to_string(),
+                }
+            }
+        }
+    }
+
+    fn image(&mut self, name: &str, target_type: ImageType, params: &[&str]) {
+        let mut caption = String::new();
+        let mut link = None;
+        for param in params.iter().map(|param| param.trim()) {
+            if let Some(target) = param.strip_prefix("link=") {
+                link = Some(target.to_string());
+            } else if !is_image_option(param) {
+                caption = self.replace_links(param);
+            }
+        }
+        let text = match link {
+            Some(link_target) if !link_target.is_empty() => Text::LinkText {
+                text: caption,
+                link: Link::Link { link_target },
+            },
+            _ => Text::Text { text: caption },
+        };
+        self.images.push(Image {
+            target: name.to_string(),
+            target_type,
+            text,
+        });
+    }
+
+    /// Replaces `[url label]` with the label.
+    fn replace_external_links(&mut self, text: &str) -> String {
+        let links = &mut self.links;
+        EXTERNAL_LINK
+            .replace_all(text, |caps: &regex::Captures| {
+                links.push(Link::ExternalLink {
+                    link_target: caps[1].to_string(),
+                });
+                caps.get(2)
+                    .map(|label| label.as_str().to_string())
+                    .unwrap_or_default()
+            })
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn page(id: &str, title: &str, ns: &str, extra: &str, timestamp: &str, text: &str) -> String {
+        format!(
+            "  <page>\n    <title>{}</title>\n    <ns>{}</ns>\n    <id>{}</id>\n{}    <revision>\n      <id>{}00</id>\n      <parentid>1</parentid>\n      <timestamp>{}</timestamp>\n      <contributor><username>User</username><id>7</id></contributor>\n      <text bytes=\"10\" xml:space=\"preserve\">{}</text>\n    </revision>\n  </page>\n",
+            title, ns, id, extra, id, timestamp, text
+        )
+    }
+
+    fn dump(pages: &[String]) -> String {
+        format!(
+            "<mediawiki xml:lang=\"ja\">\n  <siteinfo>\n    <sitename>Wikipedia</sitename>\n  </siteinfo>\n{}</mediawiki>\n",
+            pages.concat()
+        )
+    }
+
+    fn records(xml: &str) -> Vec<Record> {
+        WikiXmlReader::new(xml.as_bytes())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    fn documents(xml: &str) -> Vec<Document> {
+        records(xml)
+            .into_iter()
+            .map(|record| match record {
+                Record::Document(document) => *document,
+                _ => panic!("not a document"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_page_fields() {
+        let xml = dump(&[page(
+            "5",
+            "Rust",
+            "0",
+            "",
+            "2020-06-01T12:34:56Z",
+            "'''Rust''' is a [[programming language]] &amp; more.",
+        )]);
+        let documents = documents(&xml);
+        assert_eq!(documents.len(), 1);
+        let document = &documents[0];
+        assert_eq!(document.id, "5");
+        assert_eq!(document.revision_id, "500");
+        assert_eq!(document.title, "Rust");
+        assert_eq!(document.timestamp.to_rfc3339(), "2020-06-01T12:34:56+00:00");
+        assert_eq!(
+            document.contents,
+            vec!["Rust is a programming language & more."]
+        );
+        assert!(matches!(
+            &document.links[..],
+            [Link::Link { link_target }] if link_target == "programming language"
+        ));
+        assert!(document.opening_text.is_none());
+    }
+
+    #[test]
+    fn skips_redirects_and_other_namespaces() {
+        let xml = dump(&[
+            page(
+                "1",
+                "Redirect",
+                "0",
+                "    <redirect title=\"Rust\" />\n",
+                "2020-06-01T12:34:56Z",
+                "#REDIRECT [[Rust]]",
+            ),
+            page(
+                "2",
+                "Wikipedia:Help",
+                "4",
+                "",
+                "2020-06-01T12:34:56Z",
+                "help",
+            ),
+            page(
+                "3",
+                "Category:Languages",
+                "14",
+                "",
+                "2020-06-01T12:34:56Z",
+                "languages",
+            ),
+            page("4", "Article", "0", "", "2020-06-01T12:34:56Z", "text"),
+        ]);
+        let documents = documents(&xml);
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0].id, "4");
+    }
+
+    #[test]
+    fn removes_nested_templates() {
+        let text = "{{Infobox\n| name = {{lang|en|Rust}}\n| logo = {{Image|{{PAGENAME}}.svg}}\n}}\nRust{{efn|a {{nested}} note}} is fast.\n\n{| class=\"wikitable\"\n| cell {{x}}\n|}\n== History ==\nSince 2010.";
+        let documents = documents(&dump(&[page(
+            "5",
+            "Rust",
+            "0",
+            "",
+            "2020-06-01T12:34:56Z",
+            text,
+        )]));
+        let document = &documents[0];
+        assert_eq!(document.contents, vec!["Rust is fast.", "Since 2010."]);
+        assert_eq!(document.headings, vec!["History"]);
+    }
+
+    #[test]
+    fn reads_file_link_with_caption() {
+        let text = "[[ファイル:Rust logo.svg|thumb|200px|alt=logo|The [[Rust (programming language)|Rust]] logo]]\n[[Image:Ferris.png|link=Ferris|Ferris]]\n[[Category:Programming languages|R]]\nRust.";
+        let documents = documents(&dump(&[page(
+            "5",
+            "Rust",
+            "0",
+            "",
+            "2020-06-01T12:34:56Z",
+            text,
+        )]));
+        let document = &documents[0];
+        assert_eq!(
+            serde_json::to_value(&document.images).unwrap(),
+            json!([
+                {
+                    "target": "Rust logo.svg",
+                    "target_type": "File",
+                    "text": {"text": "The Rust logo"}
+                },
+                {
+                    "target": "Ferris.png",
+                    "target_type": "Image",
+                    "text": {"text": "Ferris", "link_target": "Ferris"}
+                }
+            ])
+        );
+        assert_eq!(document.categories, vec!["Programming languages"]);
+        assert!(matches!(
+            &document.links[..],
+            [Link::Link { link_target }] if link_target == "Rust (programming language)"
+        ));
+        // images and categories are removed from the text
+        assert_eq!(document.contents, vec!["Rust."]);
+    }
+
+    #[test]
+    fn reads_external_links() {
+        let documents = documents(&dump(&[page(
+            "5",
+            "Rust",
+            "0",
+            "",
+            "2020-06-01T12:34:56Z",
+            "See [https://www.rust-lang.org/ the site].",
+        )]));
+        let document = &documents[0];
+        assert_eq!(document.contents, vec!["See the site."]);
+        assert!(matches!(
+            &document.links[..],
+            [Link::ExternalLink { link_target }] if link_target == "https://www.rust-lang.org/"
+        ));
+    }
+
+    #[test]
+    fn invalid_timestamp_is_malformed() {
+        let xml = dump(&[
+            page("1", "Broken", "0", "", "yesterday", "text"),
+            page("2", "Article", "0", "", "2020-06-01T12:34:56Z", "text"),
+        ]);
+        let records = records(&xml);
+        assert_eq!(records.len(), 2);
+        match &records[0] {
+            Record::Malformed { raw, error } => {
+                let raw: serde_json::Value = serde_json::from_str(raw).unwrap();
+                assert_eq!(raw["title"], "Broken");
+                assert_eq!(raw["timestamp"], "yesterday");
+                assert_eq!(raw["text"], "text");
+                assert!(error.to_string().contains("invalid timestamp"));
+            }
+            _ => panic!("not malformed"),
+        }
+        assert!(matches!(&records[1], Record::Document(document) if document.id == "2"));
+    }
+
+    #[test]
+    fn broken_xml_is_error() {
+        let xml = "<mediawiki><page><title>Rust</title></revision></page></mediawiki>";
+        let mut reader = WikiXmlReader::new(xml.as_bytes());
+        match reader.next() {
+            Some(Err(err)) => assert_eq!(err.kind(), ErrorKind::InvalidData),
+            _ => panic!("not an error"),
+        }
+        assert!(reader.next().is_none());
+    }
+}
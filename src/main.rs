@@ -1,13 +1,18 @@
 #[macro_use]
 extern crate clap;
 
-use clap::{App, AppSettings, Arg};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind};
 use flame as f;
 use flamer::flame;
-use log::info;
+use log::{error, info};
 use std::env;
 use std::fs::File;
-use wiki_json_loader::loader::loader::{load, SearchEngineType};
+use std::process;
+use wiki_json_loader::error::LoaderError;
+use wiki_json_loader::loader::document::parse_timestamp;
+use wiki_json_loader::loader::input::InputFormat;
+use wiki_json_loader::loader::loader::{load, LoadOptions, SearchEngineType};
 
 #[flame]
 fn main() {
@@ -24,10 +29,10 @@ fn main() {
         .version_message("Prints version information.")
         .version_short("v")
         .arg(
-            Arg::with_name("INPUT_DIR")
-                .help("The directory where JSON files made wiki-extractor-rs containing. Support only *.json files.")
-                .value_name("INPUT_DIR")
-                .required(true)
+            Arg::with_name("INPUT")
+                .help("The directory where JSON files made wiki-extractor-rs containing, a JSON file, or - for stdin. Support *.json, *.json.gz, *.json.bz2 and *.json.zst files. With --format WikiXml, Wikipedia XML dumps (*.xml, *.xml.bz2, ...).")
+                .value_name("INPUT")
+                .required_unless_one(&["REPLAY", "FILES_FROM"])
                 .takes_value(true),
         )
         .arg(
@@ -47,19 +52,228 @@ fn main() {
                 .value_name("SEARCH_ENGINE_TYPE")
                 .required(true)
                 .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("FORMAT")
+                .help("Input format. Json for wiki-extractor-rs output, WikiXml for Wikipedia XML dump (pages-articles.xml), Cirrus for CirrusSearch dump.")
+                .long("format")
+                .value_name("FORMAT")
+                .default_value("Json")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("SINCE")
+                .help("Load only the pages revised at or after this time. RFC 3339 timestamp or YYYY-MM-DD (UTC).")
+                .long("since")
+                .value_name("SINCE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("UNTIL")
+                .help("Load only the pages revised before this time. RFC 3339 timestamp or YYYY-MM-DD (UTC).")
+                .long("until")
+                .value_name("UNTIL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("SYNC")
+                .help("Skip the pages whose revision is already indexed. Elasticsearch uses revision_id as the external version.")
+                .long("sync"),
+        )
+        .arg(
+            Arg::with_name("DELETE_MISSING")
+                .help("Delete the indexed pages that are not in the input after loading.")
+                .long("delete-missing")
+                .requires("SYNC")
+                .conflicts_with_all(&["RESUME", "REPLAY"]),
+        )
+        .arg(
+            Arg::with_name("REBUILD")
+                .help("Load into a new index created from the schema file, then switch the alias to it.")
+                .long("rebuild")
+                .conflicts_with_all(&["SYNC", "RESUME", "REPLAY"]),
+        )
+        .arg(
+            Arg::with_name("DEAD_LETTER")
+                .help("The JSON Lines file where malformed input lines are written.")
+                .value_name("DEAD_LETTER")
+                .long("dead-letter")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("REPLAY")
+                .help("Load the rejected documents in the dead-letter file instead of INPUT.")
+                .value_name("REPLAY")
+                .long("replay")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("MAX_ERRORS")
+                .help("Abort loading when more than this number of malformed lines are found. Default is unlimited.")
+                .value_name("MAX_ERRORS")
+                .long("max-errors")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("CHECKPOINT")
+                .help("The JSON file where finished input files and lines are recorded.")
+                .value_name("CHECKPOINT")
+                .long("checkpoint")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("RESUME")
+                .help("Skip the input files and lines recorded in the checkpoint file.")
+                .long("resume")
+                .requires("CHECKPOINT"),
+        )
+        .arg(
+            Arg::with_name("FILES_FROM")
+                .help("The file listing the input files, one path per line.")
+                .value_name("FILES_FROM")
+                .long("files-from")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("INCLUDE")
+                .help("Glob pattern of the files loaded from the INPUT directory. Default is **/*.json, **/*.json.gz, **/*.json.bz2 and **/*.json.zst, or **/*.xml and so on for WikiXml.")
+                .value_name("INCLUDE")
+                .long("include")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("EXCLUDE")
+                .help("Glob pattern of the files skipped in the INPUT directory.")
+                .value_name("EXCLUDE")
+                .long("exclude")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("PROFILE")
+                .help("Print the profile of the run and write the flame graph to flame.html.")
+                .long("profile"),
         );
     let matches = app.get_matches();
     let config_file = matches.value_of("CONFIG").unwrap();
-    let input_dir = matches.value_of("INPUT_DIR").unwrap();
+    let input = matches.value_of("INPUT").unwrap_or_default();
     let search_engine_type =
         value_t!(matches, "SEARCH_ENGINE_TYPE", SearchEngineType).unwrap_or_else(|e| e.exit());
+    let options = LoadOptions {
+        dead_letter_file: matches.value_of("DEAD_LETTER").map(String::from),
+        max_errors: if matches.is_present("MAX_ERRORS") {
+            Some(value_t!(matches, "MAX_ERRORS", usize).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        },
+        replay_file: matches.value_of("REPLAY").map(String::from),
+        checkpoint_file: matches.value_of("CHECKPOINT").map(String::from),
+        resume: matches.is_present("RESUME"),
+        files_from: matches.value_of("FILES_FROM").map(String::from),
+        include: values_of(&matches, "INCLUDE"),
+        exclude: values_of(&matches, "EXCLUDE"),
+        format: value_t!(matches, "FORMAT", InputFormat).unwrap_or_else(|e| e.exit()),
+        since: date_of(&matches, "SINCE"),
+        until: date_of(&matches, "UNTIL"),
+        sync: matches.is_present("SYNC"),
+        delete_missing: matches.is_present("DELETE_MISSING"),
+        rebuild: matches.is_present("REBUILD"),
+    };
 
-    match load(input_dir, config_file, &search_engine_type) {
+    match load(input, config_file, &search_engine_type, &options) {
         Ok(()) => {
             info!("{}", "done");
-            f::dump_stdout();
-            f::dump_html(&mut File::create("./flame.html").unwrap()).unwrap();
+            if matches.is_present("PROFILE") {
+                f::dump_stdout();
+                f::dump_html(&mut File::create("./flame.html").unwrap()).unwrap();
+            }
         }
-        Err(msg) => info!("{}", msg),
+        Err(err) => {
+            error!("{}", err);
+            if let LoaderError::Incomplete { failures, .. } = &err {
+                for (filepath, cause) in failures {
+                    error!("  {}: {}", filepath, cause);
+                }
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn values_of(matches: &ArgMatches, name: &str) -> Vec<String> {
+    matches
+        .values_of(name)
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn date_of(matches: &ArgMatches, name: &str) -> Option<DateTime<Utc>> {
+    matches.value_of(name).map(|value| {
+        parse_date(value).unwrap_or_else(|| {
+            clap::Error::with_description(
+                &format!(
+                    "Invalid value for --{}: {} is not a timestamp or a date",
+                    name.to_lowercase(),
+                    value
+                ),
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        })
+    })
+}
+
+/// Parses an RFC 3339 timestamp or a date, which is the start of the day in UTC.
+fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    parse_timestamp(value).ok().or_else(|| {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|datetime| Utc.from_utc_datetime(&datetime))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn since(args: &[&str]) -> Option<DateTime<Utc>> {
+        let app = App::new("test").arg(Arg::with_name("SINCE").long("since").takes_value(true));
+        let matches = app.get_matches_from(std::iter::once("test").chain(args.iter().copied()));
+        date_of(&matches, "SINCE")
+    }
+
+    #[test]
+    fn date_only() {
+        assert_eq!(
+            since(&["--since", "2020-07-01"]),
+            Some(Utc.ymd(2020, 7, 1).and_hms(0, 0, 0))
+        );
+        assert_eq!(since(&[]), None);
+    }
+
+    #[test]
+    fn rfc3339_timestamp() {
+        assert_eq!(
+            since(&["--since", "2020-07-01T12:34:56Z"]),
+            Some(Utc.ymd(2020, 7, 1).and_hms(12, 34, 56))
+        );
+        // converted to UTC
+        assert_eq!(
+            since(&["--since", "2020-07-01T09:00:00+09:00"]),
+            Some(Utc.ymd(2020, 7, 1).and_hms(0, 0, 0))
+        );
+    }
+
+    #[test]
+    fn invalid_date() {
+        assert_eq!(parse_date("2020-13-01"), None);
+        assert_eq!(parse_date("2020/07/01"), None);
+        assert_eq!(parse_date("2020-07-01T12:34:56"), None);
+        assert_eq!(parse_date("yesterday"), None);
+        assert_eq!(parse_date(""), None);
     }
 }
@@ -1,13 +1,21 @@
+use crate::error::LoaderError;
+use crate::loader::dead_letter::{DeadLetter, DeadLetterWriter};
 use crate::loader::document::Document;
-use crate::output::elasticsearch_output::load_schema;
+use crate::loader::transform::TransformedDocument;
+use crate::output::batch::split_by_bytes;
 use crate::output::elasticsearch_output::SearchEngine;
+use crate::output::elasticsearch_output::{
+    default_concurrent_requests, load_schema, read_config, BulkTask,
+};
+use crate::output::rebuild::{generation_name, is_generation, RebuildConfig};
+use crate::output::retry::{is_retryable_status, RetryConfig};
+use async_trait::async_trait;
 use log::{debug, error, info, warn};
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::{Client, StatusCode};
-use serde_json::Value;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Error;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct UploadResponse {
@@ -23,321 +31,1031 @@ struct DocResponse {
     status_code: u16,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct AzureSearchConfig {
     service_name: String,
+    /// Url of the service. Default is `https://{service_name}.search.windows.net`.
+    endpoint: Option<String>,
     buffer_size: usize,
     index_name: String,
     schema_file: String,
     //TODO need secure store
     api_key: String,
-    #[serde(default = "Vec::new")]
-    drop_fields: Vec<String>,
-    #[serde(default = "Vec::new")]
-    copy_fields: Vec<String>,
+    #[serde(default = "default_concurrent_requests")]
+    concurrent_requests: usize,
+    /// Maximum size of a request body. Requests are split to fit in it.
+    #[serde(default = "default_max_batch_bytes")]
+    max_batch_bytes: usize,
+    #[serde(default)]
+    retry: RetryConfig,
+    #[serde(default)]
+    rebuild: RebuildConfig,
+    /// API version of the alias requests of a rebuild.
+    #[serde(default = "default_alias_api_version")]
+    alias_api_version: String,
+}
+
+// below the limit of 16 MB per request
+fn default_max_batch_bytes() -> usize {
+    15 * 1024 * 1024
 }
 
+// Index aliases are only in the preview API versions, so they are used only for the alias
+// requests. The other requests keep the stable version of `get_api_version`.
+fn default_alias_api_version() -> String {
+    String::from("2024-05-01-preview")
+}
+
+// maximum $top of a search request
+const MAX_TOP: usize = 1000;
+
 pub struct AzureSearchOutput {
     client: Client,
     buffer: Vec<AzureDocument>,
-    config: AzureSearchConfig,
+    config: Arc<AzureSearchConfig>,
+    config_file: String,
+    in_flight: VecDeque<(usize, BulkTask)>,
+    acknowledged: usize,
+    rejected: usize,
+    dead_letter: Option<Arc<DeadLetterWriter>>,
+    sync: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    value: Vec<Value>,
 }
 
 struct AzureDocument {
     data: HashMap<String, Value>,
+    // kept for the dead-letter file if the document is rejected
+    source: Document,
 }
 
 impl AzureDocument {
-    fn new(_document: &Document, _config: &AzureSearchConfig) -> Self {
-        // copy field value to AzureDocument hashmap
-        let mut data = _document.to_hashmap();
+    fn new(_document: TransformedDocument) -> Self {
+        let mut data = _document.fields;
         data.insert(String::from("@search.action"), Value::from("upload"));
-        // impl copy_fields
-        for copy_field in &_config.copy_fields {
-            match parse_copy_field(copy_field) {
-                Some((source, target)) => {
-                    if data.contains_key(&source) {
-                        data.insert(target, data.get(&source).unwrap().clone());
-                    } else {
-                        warn!("there is no {} field in document.", source);
-                    }
-                }
-                None => warn!("copy_fields setting parse error. setting is {}", copy_field),
-            };
+        AzureDocument {
+            data,
+            source: _document.source,
         }
-
-        AzureDocument { data }
     }
 
     fn get_id(&self) -> String {
-        return self.data.get("id").unwrap().to_string();
+        self.source.id.clone()
     }
 
     fn to_json_string(&self) -> String {
-        return serde_json::to_string(&self.data).unwrap();
+        serde_json::to_string(&self.data).unwrap()
     }
 }
 
-fn parse_copy_field(setting: &str) -> Option<(String, String)> {
-    let splitted_setting: Vec<&str> = setting.split("=>").collect();
-    if splitted_setting.len() == 2 {
-        return Some((
-            splitted_setting[0].to_string(),
-            splitted_setting[1].to_string(),
-        ));
-    } else {
-        return None;
-    }
+// single quotes in OData string literals are escaped by doubling
+fn escape_string(value: &str) -> String {
+    value.replace('\'', "''")
+}
+
+/// A separator that is in none of `ids`, to join them in a filter that has no escaping.
+pub fn id_separator(ids: &[String]) -> char {
+    // then the characters of the private use area
+    ['|', ',', ';', '~', '^']
+        .iter()
+        .copied()
+        .chain('\u{E000}'..='\u{F8FF}')
+        .find(|separator| !ids.iter().any(|id| id.contains(*separator)))
+        .unwrap_or('|')
+}
+
+// status codes of a document in a multi-status response that can succeed when sent again.
+// Azure documents 409 as a version conflict with a concurrent update of the document and 422 as
+// an index temporarily unavailable while its definition is updated, and asks to retry both
+fn is_retryable_document_status(status_code: u16) -> bool {
+    matches!(status_code, 409 | 422 | 503) || is_retryable_status(status_code)
 }
 
-fn load_config(config_file: &str) -> AzureSearchConfig {
-    let f = File::open(config_file)
-        .expect(format!("config file is not found. {}", config_file).as_str());
-    let config: AzureSearchConfig =
-        serde_yaml::from_reader(f).expect(format!("Parse Error").as_str());
-    return config;
+fn load_config(config_file: &str) -> Result<AzureSearchConfig, LoaderError> {
+    read_config(config_file)
 }
 
+#[async_trait]
 impl SearchEngine for AzureSearchOutput {
-    fn new(_config_file: &str) -> Self
+    fn new(config_file: &str) -> Result<Self, LoaderError>
     where
         Self: Sized,
     {
-        let config = load_config(_config_file);
-        let buffer = vec![];
+        let config = load_config(config_file)?;
+        let buffer = Vec::with_capacity(config.buffer_size);
         let client = reqwest::Client::new();
-        AzureSearchOutput {
+        Ok(AzureSearchOutput {
             client,
             buffer,
-            config,
-        }
+            config: Arc::new(config),
+            config_file: config_file.to_string(),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+        })
     }
 
-    fn add_document(&mut self, mut _document: Document) {
-        // TODO Is it smart??
-        for field in &self.config.drop_fields {
-            let field = field.as_str();
-            match field {
-                "images" => &_document.images.clear(),
-                "links" => &_document.links.clear(),
-                &_ => &(),
-            };
-        }
-        let azure_doc = AzureDocument::new(&_document, &self.config);
+    fn set_dead_letter(&mut self, dead_letter: Arc<DeadLetterWriter>) {
+        self.dead_letter = Some(dead_letter);
+    }
+
+    fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    async fn add_document(&mut self, _document: TransformedDocument) -> Result<(), LoaderError> {
+        let azure_doc = AzureDocument::new(_document);
         self.buffer.push(azure_doc);
+        if self.buffer.len() >= self.config.buffer_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    fn acknowledged(&self) -> usize {
+        self.acknowledged
     }
 
-    fn initialize(&self) {
-        if self.exist_index() {
+    async fn initialize(&self) -> Result<(), LoaderError> {
+        if self.exist_index().await? {
             info!(
                 "{} index already exists. skip initialization phase.",
                 &self.config.index_name
             );
         } else {
             info!("{} index is creating...", &self.config.index_name);
-            let mut _rt = tokio::runtime::Runtime::new().expect("Fail initializing runtime");
-            let task = self.call_indices_create();
-            _rt.block_on(task).expect("Something wrong...")
+            self.call_indices_create(&self.config.index_name).await?;
         }
+        Ok(())
     }
 
-    fn exist_index(&self) -> bool {
-        let mut _rt = tokio::runtime::Runtime::new().expect("Fail initializing runtime");
-        let task = self.call_indices_exists();
-        _rt.block_on(task).expect("Something wrong...")
+    /// The index may be an alias made by a rebuild.
+    async fn exist_index(&self) -> Result<bool, LoaderError> {
+        Ok(self.call_indices_exists().await? || self.call_alias_exists().await?)
     }
 
-    fn close(&mut self) {
-        let chunk_size = if self.buffer.len() <= self.config.buffer_size {
-            self.buffer.len()
-        } else {
-            self.config.buffer_size
-        };
-        let mut _rt = tokio::runtime::Runtime::new().expect("Fail initializing runtime");
-        let mut tasks = vec![];
-        for chunk in self.buffer.chunks(chunk_size) {
-            let task = self.proceed_chunk(chunk);
-            tasks.push(task);
+    async fn close(&mut self) -> Result<(), LoaderError> {
+        if !self.buffer.is_empty() {
+            self.flush().await?;
+        }
+        while !self.in_flight.is_empty() {
+            self.wait_oldest().await?;
+        }
+        match self.rejected {
+            0 => Ok(()),
+            count => Err(LoaderError::Rejected { count }),
+        }
+    }
+
+    async fn finalize(&self) -> Result<(), LoaderError> {
+        Ok(())
+    }
+
+    async fn delete_missing(&self, ids: &HashSet<String>) -> Result<usize, LoaderError> {
+        // page through all ids in order, because $skip is limited
+        let mut missing = vec![];
+        let mut last_id: Option<String> = None;
+        loop {
+            let mut query = json!({
+                "search": "*",
+                "select": "id",
+                "orderby": "id asc",
+                "top": self.config.buffer_size.min(MAX_TOP),
+            });
+            if let Some(last_id) = &last_id {
+                query["filter"] = Value::from(format!("id gt '{}'", escape_string(last_id)));
+            }
+            let found = AzureSearchOutput::call_search(&self.client, &self.config, &query).await?;
+            if found.is_empty() {
+                break;
+            }
+            for doc in found {
+                if let Some(id) = doc["id"].as_str() {
+                    if !ids.contains(id) {
+                        missing.push(id.to_string());
+                    }
+                    last_id = Some(id.to_string());
+                }
+            }
+        }
+        for chunk in missing.chunks(self.config.buffer_size) {
+            let docs: Vec<Value> = chunk
+                .iter()
+                .map(|id| json!({"@search.action": "delete", "id": id}))
+                .collect();
+            info!("Deleting {} documents... {}", chunk.len(), chunk[0]);
+            let response = self
+                .client
+                .post(
+                    format!(
+                        "{}/docs/index{}",
+                        AzureSearchOutput::get_service_url(&self.config),
+                        AzureSearchOutput::get_api_version()
+                    )
+                    .as_str(),
+                )
+                .headers(AzureSearchOutput::get_headers(&self.config))
+                .json(&json!({ "value": docs }))
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(LoaderError::Transport(format!(
+                    "delete request failed. Status Code is {:?}.",
+                    response.status()
+                )));
+            }
+        }
+        Ok(missing.len())
+    }
+
+    fn set_index(&mut self, index_name: &str) {
+        Arc::make_mut(&mut self.config).index_name = index_name.to_string();
+    }
+
+    async fn create_generation(&self) -> Result<String, LoaderError> {
+        // an alias cannot have the name of an index, so the switch would fail after loading
+        if !self.call_alias_exists().await? && self.call_indices_exists().await? {
+            return Err(LoaderError::Config {
+                path: self.config_file.clone(),
+                message: format!(
+                    "{} is an index, but --rebuild needs an alias of that name. Move the index to an alias before rebuilding it",
+                    self.config.index_name
+                ),
+            });
         }
+        // index names of Azure Search cannot have underscores
+        let generation = generation_name(&self.config.index_name, '-');
+        info!("{} index is creating...", generation);
+        self.call_indices_create(&generation).await?;
+        Ok(generation)
+    }
 
-        for task in tasks {
-            _rt.block_on(task).expect("Error on task...");
+    async fn switch_generation(&self, generation: &str, loaded: usize) -> Result<(), LoaderError> {
+        // uploaded documents are counted after a short delay
+        let mut attempt = 0;
+        loop {
+            let count = self.call_count(generation).await?;
+            info!("{} index has {} documents.", generation, count);
+            match self.config.rebuild.check_count(generation, count, loaded) {
+                Ok(()) => break,
+                Err(_) if attempt < self.config.retry.max_retries => {
+                    attempt += 1;
+                    self.config.retry.wait(attempt).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let alias = self.config.index_name.as_str();
+        let response = self
+            .client
+            .put(AzureSearchOutput::get_alias_url(&self.config, alias).as_str())
+            .headers(AzureSearchOutput::get_headers(&self.config))
+            .json(&json!({"name": alias, "indexes": [generation]}))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(LoaderError::Transport(format!(
+                "update alias request failed. Status Code is {:?}.",
+                response.status()
+            )));
+        }
+        info!("{} alias was switched to {}.", alias, generation);
+        let generations = self.call_generations().await?;
+        for index in self.config.rebuild.expired(generations, generation) {
+            let response = self
+                .client
+                .delete(
+                    format!(
+                        "{}/indexes/{}{}",
+                        AzureSearchOutput::get_search_url(&self.config),
+                        index,
+                        AzureSearchOutput::get_api_version()
+                    )
+                    .as_str(),
+                )
+                .headers(AzureSearchOutput::get_headers(&self.config))
+                .send()
+                .await?;
+            if response.status().is_success() {
+                info!("{} index was deleted.", index);
+            } else {
+                warn!(
+                    "Delete index request has failed. Status Code is {:?}. {}",
+                    response.status(),
+                    index
+                );
+            }
         }
-        self.buffer.clear();
+        Ok(())
     }
 }
 
 impl AzureSearchOutput {
+    /// Sends the buffered documents as one request and waits for the oldest
+    /// request if more than `concurrent_requests` are in flight.
+    async fn flush(&mut self) -> Result<(), LoaderError> {
+        let chunk = std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(self.config.buffer_size),
+        );
+        let chunk_len = chunk.len();
+        let task = AzureSearchOutput::proceed_chunk(
+            self.client.clone(),
+            self.config.clone(),
+            chunk,
+            self.sync,
+        );
+        self.in_flight.push_back((chunk_len, tokio::spawn(task)));
+        while self.in_flight.len() > self.config.concurrent_requests {
+            self.wait_oldest().await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_oldest(&mut self) -> Result<(), LoaderError> {
+        if let Some((chunk_len, task)) = self.in_flight.pop_front() {
+            let rejected = task
+                .await
+                .map_err(|e| LoaderError::Transport(format!("bulk task failed. {}", e)))??;
+            self.acknowledged += chunk_len;
+            self.rejected += rejected.len();
+            if let Some(dead_letter) = &self.dead_letter {
+                for entry in &rejected {
+                    dead_letter.write(entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn get_api_version() -> String {
         String::from("?api-version=2019-05-06")
     }
 
-    fn get_headers(&self) -> HeaderMap {
+    fn get_headers(config: &AzureSearchConfig) -> HeaderMap {
         let mut headers = HeaderMap::new();
         headers.insert(
             "Content-Type",
             HeaderValue::from_str("application/json").unwrap(),
         );
-        headers.insert(
-            "api-key",
-            HeaderValue::from_str(&self.config.api_key).unwrap(),
-        );
-        return headers;
+        headers.insert("api-key", HeaderValue::from_str(&config.api_key).unwrap());
+        headers
+    }
+
+    fn get_search_url(config: &AzureSearchConfig) -> String {
+        match &config.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://{}.search.windows.net", &config.service_name),
+        }
+    }
+
+    fn get_alias_url(config: &AzureSearchConfig, alias: &str) -> String {
+        format!(
+            "{}/aliases/{}?api-version={}",
+            AzureSearchOutput::get_search_url(config),
+            alias,
+            config.alias_api_version
+        )
     }
 
-    fn get_service_url(&self) -> String {
+    fn get_service_url(config: &AzureSearchConfig) -> String {
         format!(
-            "https://{}.search.windows.net/indexes/{}",
-            &self.config.service_name, &self.config.index_name
+            "{}/indexes/{}",
+            AzureSearchOutput::get_search_url(config),
+            &config.index_name
         )
     }
 
-    async fn call_indices_create(&self) -> Result<(), Error> {
-        let schema_json = load_schema(&self.config.schema_file);
-        let result = &self
+    async fn call_indices_create(&self, index_name: &str) -> Result<(), LoaderError> {
+        let schema_json = load_schema(&self.config.schema_file)?;
+        let response = self
             .client
             .put(
                 format!(
-                    "{}{}",
-                    &self.get_service_url(),
+                    "{}/indexes/{}{}",
+                    AzureSearchOutput::get_search_url(&self.config),
+                    index_name,
                     AzureSearchOutput::get_api_version()
                 )
                 .as_str(),
             )
-            .headers(self.get_headers())
+            .headers(AzureSearchOutput::get_headers(&self.config))
             .json(&schema_json)
             .send()
-            .await;
-        match result {
-            Ok(response) => {
-                if response.status().is_success() {
-                    info!("{} index was created.", &self.config.index_name);
-                } else {
-                    warn!(
-                        "Create index request has failed. Status Code is {:?}.",
-                        response.status()
-                    );
-                    warn!("{:?}", response);
-                    // FIXME if ...
-                    panic!("create index failed")
-                }
-                return Ok(());
-            }
-            Err(_) => panic!("create index failed"),
+            .await?;
+        if !response.status().is_success() {
+            warn!(
+                "Create index request has failed. Status Code is {:?}.",
+                response.status()
+            );
+            warn!("{:?}", response);
+            return Err(LoaderError::Transport(format!(
+                "create index failed. Status Code is {:?}.",
+                response.status()
+            )));
+        }
+        info!("{} index was created.", index_name);
+        Ok(())
+    }
+
+    async fn call_count(&self, index_name: &str) -> Result<usize, LoaderError> {
+        let response = self
+            .client
+            .get(
+                format!(
+                    "{}/indexes/{}/docs/$count{}",
+                    AzureSearchOutput::get_search_url(&self.config),
+                    index_name,
+                    AzureSearchOutput::get_api_version()
+                )
+                .as_str(),
+            )
+            .headers(AzureSearchOutput::get_headers(&self.config))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(LoaderError::Transport(format!(
+                "count request failed. Status Code is {:?}.",
+                response.status()
+            )));
         }
+        let count = response.text().await?;
+        // the count is returned as plain text, sometimes with a byte order mark
+        count
+            .trim_start_matches('\u{feff}')
+            .trim()
+            .parse()
+            .map_err(|e| LoaderError::Transport(format!("invalid count {:?}. {}", count, e)))
     }
 
-    async fn call_indices_exists(&self) -> Result<bool, Error> {
+    /// Lists the generations of the index in the config.
+    async fn call_generations(&self) -> Result<Vec<String>, LoaderError> {
+        let response = self
+            .client
+            .get(
+                format!(
+                    "{}/indexes{}&$select=name",
+                    AzureSearchOutput::get_search_url(&self.config),
+                    AzureSearchOutput::get_api_version()
+                )
+                .as_str(),
+            )
+            .headers(AzureSearchOutput::get_headers(&self.config))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(LoaderError::Transport(format!(
+                "list indexes request failed. Status Code is {:?}.",
+                response.status()
+            )));
+        }
+        Ok(response
+            .json::<SearchResponse>()
+            .await?
+            .value
+            .into_iter()
+            .filter_map(|index| index["name"].as_str().map(String::from))
+            .filter(|name| is_generation(name, &self.config.index_name, '-'))
+            .collect())
+    }
+
+    async fn call_indices_exists(&self) -> Result<bool, LoaderError> {
         //        GET  https://{{host}}/indexes/hogehoge?api-version=2019-05-06
         //        Content-Type: application/json
         //        api-key: {{api-key}}
-        let result = &self
+        let result = self
             .client
             .get(
                 format!(
                     "{}{}",
-                    &self.get_service_url(),
+                    AzureSearchOutput::get_service_url(&self.config),
                     AzureSearchOutput::get_api_version()
                 )
                 .as_str(),
             )
-            .headers(self.get_headers())
+            .headers(AzureSearchOutput::get_headers(&self.config))
             .send()
             .await;
         match result {
             Ok(response) => match response.status() {
-                StatusCode::NOT_FOUND => return Ok(false),
-                StatusCode::OK => return Ok(true),
+                StatusCode::NOT_FOUND => Ok(false),
+                StatusCode::OK => Ok(true),
                 _ => {
                     warn!(
                         "Indices exists request has failed. Status Code is {:?}.",
                         response.status()
                     );
                     warn!("{:?}", response);
-                    panic!("Indices exists request failed")
+                    Err(LoaderError::Transport(format!(
+                        "indices exists request failed. Status Code is {:?}.",
+                        response.status()
+                    )))
                 }
             },
             Err(err) => {
                 error!("{:?}", err);
-                panic!("Indices exists request failed...")
+                Err(err.into())
             }
         }
     }
 
-    async fn proceed_chunk(
-        &self,
-        chunk: &[AzureDocument],
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        //FIXME copy fields...
-        // need other settings like field copy mapping...
-
-        let mut docs: Vec<String> = vec![];
-        let mut doc_id = String::new();
-        let mut chunk_size = 0;
-        for d in chunk {
-            if doc_id.is_empty() {
-                doc_id.push_str(d.get_id().as_str());
-            }
-
-            // read json as hashmap
-            //serde_json::to_value(d).unwrap().
-            let json_string = d.to_json_string();
-
-            docs.push(json_string);
-            chunk_size += 1;
-        }
-        let root_json = format!("{{ \"value\": [{}]}}", docs.join(", "));
-        debug!("root_json is {}", &root_json);
-
-        info!("Sending {} documents... {}", chunk_size, doc_id);
+    async fn call_alias_exists(&self) -> Result<bool, LoaderError> {
         let response = self
             .client
+            .get(AzureSearchOutput::get_alias_url(&self.config, &self.config.index_name).as_str())
+            .headers(AzureSearchOutput::get_headers(&self.config))
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            StatusCode::OK => Ok(true),
+            status => Err(LoaderError::Transport(format!(
+                "get alias request failed. Status Code is {:?}.",
+                status
+            ))),
+        }
+    }
+
+    async fn call_search(
+        client: &Client,
+        config: &AzureSearchConfig,
+        query: &Value,
+    ) -> Result<Vec<Value>, LoaderError> {
+        let response = client
             .post(
                 format!(
-                    "{}/docs/index{}",
-                    &self.get_service_url(),
+                    "{}/docs/search{}",
+                    AzureSearchOutput::get_service_url(config),
                     AzureSearchOutput::get_api_version()
                 )
                 .as_str(),
             )
-            .headers(self.get_headers())
-            .body(root_json)
+            .headers(AzureSearchOutput::get_headers(config))
+            .json(query)
             .send()
             .await?;
-        if response.status().is_success() {
+        if !response.status().is_success() {
+            warn!(
+                "Search request has failed. Status Code is {:?}.",
+                response.status()
+            );
+            return Err(LoaderError::Transport(format!(
+                "search request failed. Status Code is {:?}.",
+                response.status()
+            )));
+        }
+        Ok(response.json::<SearchResponse>().await?.value)
+    }
+
+    /// Removes the documents whose revision is already indexed.
+    async fn skip_unchanged(
+        client: &Client,
+        config: &AzureSearchConfig,
+        chunk: Vec<AzureDocument>,
+    ) -> Result<Vec<AzureDocument>, LoaderError> {
+        let ids: Vec<String> = chunk.iter().map(|d| escape_string(&d.get_id())).collect();
+        let mut indexed: HashMap<String, String> = HashMap::new();
+        for ids in ids.chunks(MAX_TOP) {
+            let separator = id_separator(ids);
+            let query = json!({
+                "search": "*",
+                "filter": format!(
+                    "search.in(id, '{}', '{}')",
+                    ids.join(&separator.to_string()),
+                    separator
+                ),
+                "select": "id,revision_id",
+                "top": ids.len(),
+            });
+            let found = AzureSearchOutput::call_search(client, config, &query).await?;
+            indexed.extend(found.into_iter().filter_map(|doc| {
+                Some((
+                    doc["id"].as_str()?.to_string(),
+                    doc["revision_id"].as_str()?.to_string(),
+                ))
+            }));
+        }
+        let chunk_len = chunk.len();
+        let changed: Vec<AzureDocument> = chunk
+            .into_iter()
+            .filter(|d| match indexed.get(&d.source.id) {
+                Some(revision_id) => d.source.is_newer_than(revision_id),
+                None => true,
+            })
+            .collect();
+        if changed.len() < chunk_len {
+            info!("Skipped {} unchanged documents.", chunk_len - changed.len());
+        }
+        Ok(changed)
+    }
+
+    async fn proceed_chunk(
+        client: Client,
+        config: Arc<AzureSearchConfig>,
+        chunk: Vec<AzureDocument>,
+        sync: bool,
+    ) -> Result<Vec<DeadLetter>, LoaderError> {
+        let mut chunk = chunk;
+        if sync {
+            chunk = AzureSearchOutput::skip_unchanged(&client, &config, chunk).await?;
+        }
+        let (batches, mut rejected) = split_by_bytes(
+            chunk,
+            config.max_batch_bytes,
+            // separated by ", "
+            |d| d.to_json_string().len() + 2,
+            |d| &d.source,
+        );
+        for batch in batches {
+            rejected.extend(AzureSearchOutput::send_batch(&client, &config, batch).await?);
+        }
+        Ok(rejected)
+    }
+
+    async fn send_batch(
+        client: &Client,
+        config: &AzureSearchConfig,
+        chunk: Vec<AzureDocument>,
+    ) -> Result<Vec<DeadLetter>, LoaderError> {
+        let mut chunk = chunk;
+        let mut rejected = vec![];
+        // retries of the whole request and of the failed documents
+        let mut attempt = 0;
+        let mut document_attempt = 0;
+        loop {
+            let mut docs: Vec<String> = vec![];
+            let mut doc_id = String::new();
+            for d in &chunk {
+                if doc_id.is_empty() {
+                    doc_id.push_str(d.get_id().as_str());
+                }
+
+                // read json as hashmap
+                //serde_json::to_value(d).unwrap().
+                let json_string = d.to_json_string();
+
+                docs.push(json_string);
+            }
+            let root_json = format!("{{ \"value\": [{}]}}", docs.join(", "));
+            debug!("root_json is {}", &root_json);
+
+            info!("Sending {} documents... {}", chunk.len(), doc_id);
+            let result = client
+                .post(
+                    format!(
+                        "{}/docs/index{}",
+                        AzureSearchOutput::get_service_url(config),
+                        AzureSearchOutput::get_api_version()
+                    )
+                    .as_str(),
+                )
+                .headers(AzureSearchOutput::get_headers(config))
+                .body(root_json)
+                .send()
+                .await;
+            let response = match result {
+                Ok(response) => response,
+                Err(err) => {
+                    warn!(
+                        "Bulk request has failed. {}. First doc id is [{}]",
+                        err, doc_id
+                    );
+                    if attempt < config.retry.max_retries {
+                        attempt += 1;
+                        config.retry.wait(attempt).await;
+                        continue;
+                    }
+                    return Err(err.into());
+                }
+            };
+            if !response.status().is_success() {
+                let status = response.status();
+                warn!(
+                    "Bulk request has failed. Status Code is {:?}. First doc id is [{}]",
+                    status, doc_id
+                );
+                warn!("response - {:?}", response);
+                let response_body = response.text().await?;
+                warn!("res_body - {:?}", response_body);
+                if is_retryable_status(status.as_u16()) && attempt < config.retry.max_retries {
+                    attempt += 1;
+                    config.retry.wait(attempt).await;
+                    continue;
+                }
+                return Err(LoaderError::Transport(format!(
+                    "bulk request failed. Status Code is {:?}. First doc id is [{}]",
+                    status, doc_id
+                )));
+            }
             info!("response : {}", response.status());
             debug!("{:?}", response);
-            let result = response.json::<UploadResponse>().await;
-            match result {
-                Ok(upload_response) => {
-                    for doc_response in upload_response.value {
-                        if !doc_response.status {
-                            warn!(
-                                "error id:[{}], status_code:[{}], reason:[{}]",
-                                doc_response.key,
-                                doc_response.status_code,
-                                doc_response.error_message.unwrap()
-                            );
-                        }
+            let upload_response = response.json::<UploadResponse>().await.map_err(|err| {
+                warn!("Error parse json from response body. {:?}", err);
+                LoaderError::Transport(format!(
+                    "cannot parse bulk response. First doc id is [{}]. {}",
+                    doc_id, err
+                ))
+            })?;
+            let mut failed_keys = vec![];
+            for doc_response in upload_response.value {
+                if !doc_response.status {
+                    let key = doc_response.key;
+                    let reason = doc_response.error_message.unwrap_or_default();
+                    warn!(
+                        "error id:[{}], status_code:[{}], reason:[{}]",
+                        key, doc_response.status_code, reason
+                    );
+                    if is_retryable_document_status(doc_response.status_code)
+                        && document_attempt < config.retry.max_document_retries
+                    {
+                        failed_keys.push(key);
+                    } else if let Some(d) = chunk.iter().find(|d| d.source.id == key) {
+                        rejected.push(DeadLetter::Rejected {
+                            id: key,
+                            error_type: doc_response.status_code.to_string(),
+                            reason,
+                            document: serde_json::to_value(&d.source).unwrap(),
+                        });
                     }
                 }
-                Err(err) => {
-                    warn!("Error parse json from response body. {:?}", err);
-                    panic!("bulk indexing failed.");
-                }
             }
-        } else {
-            warn!(
-                "Bulk request has failed. Status Code is {:?}. First doc id is [{}]",
-                response.status(),
-                doc_id
-            );
-            warn!("response - {:?}", response);
-            let response_body = response.text().await?;
-            warn!("res_body - {:?}", response_body);
-            panic!("bulk indexing failed")
+            if failed_keys.is_empty() {
+                info!("Finished bulk request. {}", doc_id);
+                return Ok(rejected);
+            }
+            document_attempt += 1;
+            config.retry.wait(document_attempt).await;
+            chunk.retain(|d| failed_keys.contains(&d.source.id));
         }
-        info!("Finished bulk request. {}", doc_id);
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::test_support::{block_on, document, TestServer};
+
+    fn output(url: &str) -> AzureSearchOutput {
+        let config: AzureSearchConfig = serde_yaml::from_str(&format!(
+            "service_name: wiki\nendpoint: {}\napi_key: key\nindex_name: wiki\nschema_file: schema.json\nbuffer_size: 10\nretry:\n  initial_backoff_ms: 1\n  max_backoff_ms: 1\n",
+            url
+        ))
+        .unwrap();
+        AzureSearchOutput {
+            client: Client::new(),
+            buffer: vec![],
+            config: Arc::new(config),
+            config_file: String::from("azure.yaml"),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+        }
+    }
+
+    #[test]
+    fn alias_exists_as_index() {
+        let server = TestServer::start(|request| {
+            if request
+                .path
+                .starts_with("/aliases/wiki?api-version=2024-05-01-preview")
+            {
+                (
+                    200,
+                    json!({"name": "wiki", "indexes": ["wiki-20200701000000"]}).to_string(),
+                )
+            } else {
+                (404, String::from("{}"))
+            }
+        });
+        let mut other = output(&server.url);
+        other.set_index("enwiki");
+        assert!(!block_on(other.exist_index()).unwrap());
+        let output = output(&server.url);
+        assert!(block_on(output.exist_index()).unwrap());
+    }
+
+    #[test]
+    fn fail_rebuild_of_index_before_loading() {
+        let server = TestServer::start(|request| {
+            if request.path.starts_with("/indexes/wiki?") {
+                (200, json!({"name": "wiki"}).to_string())
+            } else {
+                (404, String::from("{}"))
+            }
+        });
+        let output = output(&server.url);
+        match block_on(output.create_generation()) {
+            Err(LoaderError::Config { message, .. }) => {
+                assert!(message.contains("wiki is an index"))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(server
+            .requests()
+            .iter()
+            .all(|request| request.method == "GET"));
+    }
+
+    fn load(output: &mut AzureSearchOutput, ids: &[&str]) -> Result<(), LoaderError> {
+        block_on(async {
+            for id in ids {
+                output.add_document(document(id, "1")).await?;
+            }
+            output.close().await
+        })
+    }
+
+    // ids of the documents in an index request
+    fn ids(body: &str) -> Vec<String> {
+        serde_json::from_str::<Value>(body).unwrap()["value"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|doc| doc["id"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn doc_response(key: &str, status_code: u16) -> Value {
+        json!({
+            "key": key,
+            "status": status_code < 300,
+            "errorMessage": if status_code < 300 { Value::Null } else { json!("error") },
+            "statusCode": status_code
+        })
+    }
+
+    #[test]
+    fn retry_document_status() {
+        for status_code in [409, 422, 429, 503] {
+            assert!(is_retryable_document_status(status_code));
+        }
+        for status_code in [200, 201, 400, 404, 413] {
+            assert!(!is_retryable_document_status(status_code));
+        }
+    }
+
+    #[test]
+    fn resend_failed_documents_of_multi_status() {
+        let mut requests = 0;
+        let server = TestServer::start(move |_| {
+            requests += 1;
+            let body = match requests {
+                1 => json!({"value": [
+                    doc_response("1", 201),
+                    doc_response("2", 503),
+                    doc_response("3", 400)
+                ]}),
+                _ => json!({"value": [doc_response("2", 200)]}),
+            };
+            (if requests == 1 { 207 } else { 200 }, body.to_string())
+        });
+        let mut output = output(&server.url);
+        match load(&mut output, &["1", "2", "3"]) {
+            Err(LoaderError::Rejected { count }) => assert_eq!(count, 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].path.starts_with("/indexes/wiki/docs/index?"));
+        assert_eq!(ids(&requests[0].body), vec!["1", "2", "3"]);
+        assert_eq!(ids(&requests[1].body), vec!["2"]);
+        assert_eq!(output.acknowledged(), 3);
+    }
+
+    #[test]
+    fn reject_documents_after_document_retries() {
+        let server = TestServer::start(|request| {
+            let value: Vec<Value> = ids(&request.body)
+                .iter()
+                .map(|id| doc_response(id, 503))
+                .collect();
+            (207, json!({ "value": value }).to_string())
+        });
+        let mut output = output(&server.url);
+        match load(&mut output, &["1"]) {
+            Err(LoaderError::Rejected { count }) => assert_eq!(count, 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // the first request and the default max_document_retries
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[test]
+    fn split_requests_by_bytes() {
+        let server = TestServer::start(|request| {
+            let value: Vec<Value> = ids(&request.body)
+                .iter()
+                .map(|id| doc_response(id, 201))
+                .collect();
+            (200, json!({ "value": value }).to_string())
+        });
+        let mut output = output(&server.url);
+        // separated by ", "
+        let bytes = AzureDocument::new(document("1", "1"))
+            .to_json_string()
+            .len()
+            + 2;
+        Arc::make_mut(&mut output.config).max_batch_bytes = bytes * 2;
+        let mut large = document("4", "1");
+        large
+            .fields
+            .insert(String::from("contents"), json!(["x".repeat(bytes * 2)]));
+        let result = block_on(async {
+            for id in &["1", "2", "3"] {
+                output.add_document(document(id, "1")).await?;
+            }
+            output.add_document(large).await?;
+            output.close().await
+        });
+        match result {
+            Err(LoaderError::Rejected { count }) => assert_eq!(count, 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let batches: Vec<Vec<String>> = server
+            .requests()
+            .iter()
+            .map(|request| {
+                assert!(request.body.len() <= bytes * 2 + "{ \"value\": []}".len());
+                ids(&request.body)
+            })
+            .collect();
+        assert_eq!(batches, vec![vec!["1", "2"], vec!["3"]]);
+    }
+
+    #[test]
+    fn separator_not_in_ids() {
+        let ids = vec![String::from("a"), String::from("b")];
+        assert_eq!(id_separator(&ids), '|');
+        let ids = vec![String::from("a|b"), String::from("c,d"), String::from("e")];
+        assert_eq!(id_separator(&ids), ';');
+        let ids = vec![String::from("|,;~^")];
+        assert_eq!(id_separator(&ids), '\u{E000}');
+    }
+
+    // answers the searches with `indexed` documents and the index requests with success
+    fn sync_server(indexed: Value) -> TestServer {
+        TestServer::start(move |request| {
+            if request.path.contains("/docs/search") {
+                return (200, json!({ "value": indexed }).to_string());
+            }
+            let value: Vec<Value> = ids(&request.body)
+                .iter()
+                .map(|id| doc_response(id, 201))
+                .collect();
+            (200, json!({ "value": value }).to_string())
+        })
+    }
+
+    #[test]
+    fn skip_unchanged_documents() {
+        let server = sync_server(json!([
+            {"id": "1", "revision_id": "1"},
+            {"id": "2", "revision_id": "0"}
+        ]));
+        let mut output = output(&server.url);
+        output.set_sync(true);
+        load(&mut output, &["1", "2", "3"]).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let query: Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(query["filter"], "search.in(id, '1|2|3', '|')");
+        assert_eq!(query["select"], "id,revision_id");
+        assert_eq!(query["top"], 3);
+        assert_eq!(ids(&requests[1].body), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn search_indexed_revisions_by_max_top() {
+        let server = sync_server(json!([]));
+        let mut output = output(&server.url);
+        output.set_sync(true);
+        Arc::make_mut(&mut output.config).buffer_size = MAX_TOP + 1;
+        let ids: Vec<String> = (0..=MAX_TOP).map(|i| i.to_string()).collect();
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        load(&mut output, &ids).unwrap();
+        let tops: Vec<Value> = server
+            .requests()
+            .iter()
+            .filter(|request| request.path.contains("/docs/search"))
+            .map(|request| serde_json::from_str::<Value>(&request.body).unwrap()["top"].clone())
+            .collect();
+        assert_eq!(tops, vec![json!(MAX_TOP), json!(1)]);
+    }
+
+    #[test]
+    fn escape_quotes_in_filter() {
+        let server = sync_server(json!([]));
+        let mut output = output(&server.url);
+        output.set_sync(true);
+        load(&mut output, &["O'Brien", "a|b"]).unwrap();
+        let query: Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(query["filter"], "search.in(id, 'O''Brien,a|b', ',')");
     }
 }
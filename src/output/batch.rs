@@ -0,0 +1,122 @@
+use crate::loader::dead_letter::DeadLetter;
+use crate::loader::document::Document;
+use log::warn;
+
+/// Default `max_batch_bytes` of the outputs.
+// well below the request size limits, like 100mb of http.max_content_length of Elasticsearch
+pub fn default_max_batch_bytes() -> usize {
+    20 * 1024 * 1024
+}
+
+/// Splits `docs` into batches of at most `max_bytes`. `size` is the number of bytes
+/// a document takes in the request body. A document larger than `max_bytes` by itself
+/// is not sent and is returned as rejected.
+pub fn split_by_bytes<T, S, D>(
+    docs: Vec<T>,
+    max_bytes: usize,
+    size: S,
+    source: D,
+) -> (Vec<Vec<T>>, Vec<DeadLetter>)
+where
+    S: Fn(&T) -> usize,
+    D: Fn(&T) -> &Document,
+{
+    let mut batches = vec![];
+    let mut rejected = vec![];
+    let mut batch = vec![];
+    let mut batch_bytes = 0;
+    for d in docs {
+        let bytes = size(&d);
+        if bytes > max_bytes {
+            let document = source(&d);
+            warn!(
+                "error id:[{}], the document has {} bytes. max_batch_bytes is {}",
+                document.id, bytes, max_bytes
+            );
+            rejected.push(DeadLetter::Rejected {
+                id: document.id.clone(),
+                error_type: String::from("document_too_large"),
+                reason: format!(
+                    "the document has {} bytes. max_batch_bytes is {}",
+                    bytes, max_bytes
+                ),
+                document: serde_json::to_value(document).unwrap(),
+            });
+            continue;
+        }
+        if !batch.is_empty() && batch_bytes + bytes > max_bytes {
+            batches.push(std::mem::take(&mut batch));
+            batch_bytes = 0;
+        }
+        batch_bytes += bytes;
+        batch.push(d);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    (batches, rejected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::test_support::document;
+
+    // documents of the given sizes
+    fn docs(sizes: &[usize]) -> Vec<(usize, Document)> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| (*size, document(&i.to_string(), "1").source))
+            .collect()
+    }
+
+    fn split(sizes: &[usize], max_bytes: usize) -> (Vec<Vec<usize>>, Vec<DeadLetter>) {
+        let (batches, rejected) =
+            split_by_bytes(docs(sizes), max_bytes, |(size, _)| *size, |(_, d)| d);
+        let batches = batches
+            .into_iter()
+            .map(|batch| batch.into_iter().map(|(size, _)| size).collect())
+            .collect();
+        (batches, rejected)
+    }
+
+    #[test]
+    fn batch_fits_exactly() {
+        let (batches, rejected) = split(&[3, 3, 4], 10);
+        assert_eq!(batches, vec![vec![3, 3, 4]]);
+        assert!(rejected.is_empty());
+        let (batches, _) = split(&[10], 10);
+        assert_eq!(batches, vec![vec![10]]);
+        let (batches, _) = split(&[], 10);
+        assert!(batches.is_empty());
+    }
+
+    #[test]
+    fn split_at_limit() {
+        let (batches, rejected) = split(&[4, 4, 4, 10, 1], 10);
+        assert_eq!(batches, vec![vec![4, 4], vec![4], vec![10], vec![1]]);
+        assert!(rejected.is_empty());
+    }
+
+    #[test]
+    fn reject_document_too_large() {
+        let (batches, rejected) = split(&[4, 11, 6], 10);
+        assert_eq!(batches, vec![vec![4, 6]]);
+        assert_eq!(rejected.len(), 1);
+        match &rejected[0] {
+            DeadLetter::Rejected {
+                id,
+                error_type,
+                reason,
+                document,
+            } => {
+                assert_eq!(id, "1");
+                assert_eq!(error_type, "document_too_large");
+                assert_eq!(reason, "the document has 11 bytes. max_batch_bytes is 10");
+                assert_eq!(document["title"], "Title 1");
+            }
+            _ => panic!("not rejected"),
+        }
+    }
+}
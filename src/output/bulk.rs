@@ -0,0 +1,511 @@
+use crate::error::LoaderError;
+use crate::loader::dead_letter::DeadLetter;
+use crate::loader::transform::TransformedDocument;
+use crate::output::batch::split_by_bytes;
+use crate::output::rebuild::{is_generation, RebuildConfig};
+use crate::output::retry::{is_retryable_status, RetryConfig};
+use async_trait::async_trait;
+use log::{info, warn};
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+const SCROLL_KEEP_ALIVE: &str = "1m";
+
+/// A response of the REST API of Elasticsearch or OpenSearch.
+pub struct ApiResponse {
+    pub status: StatusCode,
+    /// Null if the response has no body.
+    pub body: Value,
+}
+
+impl ApiResponse {
+    /// Parses a response body. An empty body is Null.
+    pub fn new(status: StatusCode, text: &str) -> Result<Self, LoaderError> {
+        let body = if text.trim().is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_str(text).map_err(|e| {
+                LoaderError::Transport(format!(
+                    "cannot parse the response. Status Code is {:?}. {}",
+                    status, e
+                ))
+            })?
+        };
+        Ok(ApiResponse { status, body })
+    }
+
+    /// Returns the body of a successful response.
+    pub fn check(self, request: &str) -> Result<Value, LoaderError> {
+        if !self.status.is_success() {
+            return Err(LoaderError::Transport(format!(
+                "{} request failed. Status Code is {:?}.",
+                request, self.status
+            )));
+        }
+        Ok(self.body)
+    }
+}
+
+/// The REST API of Elasticsearch and OpenSearch. The bulk, scroll and alias requests are
+/// shared by both outputs through it.
+#[async_trait]
+pub trait RestApi: Send + Sync {
+    /// Sends a request with a JSON body. Fails if the request cannot be sent.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<ApiResponse, LoaderError>;
+    /// Sends a bulk request body of action and source lines to `index_name`.
+    async fn bulk(&self, index_name: &str, body: String) -> Result<ApiResponse, LoaderError>;
+}
+
+/// The action line of a document in a bulk request of Elasticsearch or OpenSearch.
+/// In sync mode, the revision is used as the external version, and `None` is returned
+/// if the revision is not a number.
+pub fn bulk_action(d: &TransformedDocument, sync: bool) -> Option<Value> {
+    if sync {
+        let version = d.source.revision_id.parse::<u64>().ok()?;
+        Some(json!({"index": {
+            "_id": d.source.id,
+            "version": version,
+            "version_type": "external"
+        }}))
+    } else {
+        Some(json!({"index": {"_id": d.source.id}}))
+    }
+}
+
+/// Size of the action and source lines of a document in the bulk request body.
+pub fn bulk_size(d: &TransformedDocument, sync: bool) -> usize {
+    let action = bulk_action(d, sync).unwrap_or_default();
+    action.to_string().len() + serde_json::to_string(&d.fields).unwrap().len() + 2
+}
+
+/// Rejects a document whose revision cannot be an external version.
+fn reject_invalid_revision(d: TransformedDocument) -> DeadLetter {
+    warn!(
+        "error id:[{}], revision_id:[{}] is not a number",
+        d.source.id, d.source.revision_id
+    );
+    DeadLetter::Rejected {
+        id: d.source.id.clone(),
+        error_type: String::from("invalid_revision_id"),
+        reason: format!("revision_id {} is not a number", d.source.revision_id),
+        document: serde_json::to_value(&d.source).unwrap(),
+    }
+}
+
+/// Documents of a bulk request sorted by the items of its response.
+#[derive(Default)]
+pub struct BulkItems {
+    /// Documents that failed with a retryable status.
+    pub failed: Vec<TransformedDocument>,
+    pub rejected: Vec<DeadLetter>,
+    /// Documents skipped in sync mode because the same or a newer revision is indexed.
+    pub unchanged: usize,
+}
+
+/// Reads the items of a bulk response with errors. Failed documents are retried
+/// only if `retry` is true, otherwise they are rejected.
+pub fn read_bulk_items(
+    response_body: &Value,
+    docs: Vec<TransformedDocument>,
+    sync: bool,
+    retry: bool,
+) -> BulkItems {
+    let mut result = BulkItems::default();
+    let items = response_body["items"].as_array().into_iter().flatten();
+    // items are returned in the same order as the request
+    for (item, d) in items.zip(docs) {
+        if let Some(index_obj) = item["index"].as_object() {
+            if let Some(obj) = index_obj.get("error").and_then(|e| e.as_object()) {
+                let status = index_obj["status"].as_u64().unwrap_or_default() as u16;
+                // the same or a newer revision is already indexed
+                if sync && status == 409 {
+                    result.unchanged += 1;
+                    continue;
+                }
+                warn!(
+                    "error id:[{}], type:[{}], reason:[{}]",
+                    index_obj["_id"], obj["type"], obj["reason"]
+                );
+                if is_retryable_status(status) && retry {
+                    result.failed.push(d);
+                } else {
+                    result.rejected.push(DeadLetter::Rejected {
+                        id: d.source.id.clone(),
+                        error_type: obj["type"].as_str().unwrap_or_default().to_string(),
+                        reason: obj["reason"].as_str().unwrap_or_default().to_string(),
+                        document: serde_json::to_value(&d.source).unwrap(),
+                    });
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Sends the documents in bulk requests of at most `max_batch_bytes`. Returns the rejected documents.
+pub async fn proceed_chunk<A: RestApi>(
+    api: A,
+    index_name: String,
+    max_batch_bytes: usize,
+    retry: RetryConfig,
+    chunk: Vec<TransformedDocument>,
+    sync: bool,
+) -> Result<Vec<DeadLetter>, LoaderError> {
+    let (batches, mut rejected) = split_by_bytes(
+        chunk,
+        max_batch_bytes,
+        |d| bulk_size(d, sync),
+        |d| &d.source,
+    );
+    for batch in batches {
+        rejected.extend(send_bulk(&api, &index_name, &retry, batch, sync).await?);
+    }
+    Ok(rejected)
+}
+
+/// Sends the documents in one bulk request. The request is sent again if it fails with
+/// a connection error or a busy status, and the documents that failed with a busy status
+/// are sent again in a new request.
+pub async fn send_bulk<A: RestApi>(
+    api: &A,
+    index_name: &str,
+    retry: &RetryConfig,
+    docs: Vec<TransformedDocument>,
+    sync: bool,
+) -> Result<Vec<DeadLetter>, LoaderError> {
+    let mut docs = docs;
+    let mut rejected = vec![];
+    // retries of the whole request and of the failed documents
+    let mut attempt = 0;
+    let mut document_attempt = 0;
+    loop {
+        let mut body = String::new();
+        let mut sent = Vec::with_capacity(docs.len());
+        for d in docs {
+            match bulk_action(&d, sync) {
+                Some(action) => {
+                    body.push_str(&action.to_string());
+                    body.push('\n');
+                    body.push_str(&serde_json::to_string(&d.fields).unwrap());
+                    body.push('\n');
+                    sent.push(d);
+                }
+                None => rejected.push(reject_invalid_revision(d)),
+            }
+        }
+        docs = sent;
+        if docs.is_empty() {
+            return Ok(rejected);
+        }
+        let doc_id = docs[0].source.id.clone();
+        info!("Sending {} documents... {}", docs.len(), doc_id);
+        let bulk_response = match api.bulk(index_name, body).await {
+            Ok(response) if response.status.is_success() => response,
+            Ok(response) => {
+                warn!(
+                    "Bulk request has failed. Status Code is {:?}. First doc id is [{}]",
+                    response.status, doc_id
+                );
+                if is_retryable_status(response.status.as_u16()) && attempt < retry.max_retries {
+                    attempt += 1;
+                    retry.wait(attempt).await;
+                    continue;
+                }
+                return Err(LoaderError::Transport(format!(
+                    "bulk request failed. Status Code is {:?}. First doc id is [{}]",
+                    response.status, doc_id
+                )));
+            }
+            Err(err) => {
+                warn!(
+                    "Bulk request has failed. {}. First doc id is [{}]",
+                    err, doc_id
+                );
+                if attempt < retry.max_retries {
+                    attempt += 1;
+                    retry.wait(attempt).await;
+                    continue;
+                }
+                return Err(err);
+            }
+        };
+        info!("response : {}", bulk_response.status);
+        let response_body = bulk_response.body;
+        if !response_body["errors"].as_bool().unwrap_or(false) {
+            info!("Finished bulk request. {}", doc_id);
+            return Ok(rejected);
+        }
+        if !sync {
+            warn!("Bulk Request has some errors. {}", doc_id);
+        }
+        let items = read_bulk_items(
+            &response_body,
+            docs,
+            sync,
+            document_attempt < retry.max_document_retries,
+        );
+        rejected.extend(items.rejected);
+        if items.unchanged > 0 {
+            info!(
+                "Skipped {} unchanged documents. {}",
+                items.unchanged, doc_id
+            );
+        }
+        if items.failed.is_empty() {
+            info!("Finished bulk request. {}", doc_id);
+            return Ok(rejected);
+        }
+        document_attempt += 1;
+        retry.wait(document_attempt).await;
+        docs = items.failed;
+    }
+}
+
+/// Deletes the documents of `ids` in bulk requests of `chunk_size` documents.
+pub async fn delete_documents<A: RestApi>(
+    api: &A,
+    index_name: &str,
+    ids: &[String],
+    chunk_size: usize,
+) -> Result<(), LoaderError> {
+    for chunk in ids.chunks(chunk_size) {
+        let body: String = chunk
+            .iter()
+            .map(|id| format!("{}\n", json!({"delete": {"_id": id}})))
+            .collect();
+        info!("Deleting {} documents... {}", chunk.len(), chunk[0]);
+        api.bulk(index_name, body).await?.check("delete")?;
+    }
+    Ok(())
+}
+
+/// Lists the ids of the documents in `index_name` that are not in `ids`.
+pub async fn scroll_missing_ids<A: RestApi>(
+    api: &A,
+    index_name: &str,
+    size: usize,
+    ids: &HashSet<String>,
+) -> Result<Vec<String>, LoaderError> {
+    let mut body = api
+        .send(
+            Method::POST,
+            &format!("{}/_search?scroll={}", index_name, SCROLL_KEEP_ALIVE),
+            Some(json!({"size": size, "_source": false, "sort": ["_doc"]})),
+        )
+        .await?
+        .check("scroll")?;
+    let mut missing = vec![];
+    let mut scroll_id = None;
+    loop {
+        scroll_id = body["_scroll_id"].as_str().map(String::from).or(scroll_id);
+        let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+        if hits.is_empty() {
+            break;
+        }
+        for hit in hits {
+            if let Some(id) = hit["_id"].as_str() {
+                if !ids.contains(id) {
+                    missing.push(id.to_string());
+                }
+            }
+        }
+        body = api
+            .send(
+                Method::POST,
+                "_search/scroll",
+                Some(json!({"scroll": SCROLL_KEEP_ALIVE, "scroll_id": scroll_id})),
+            )
+            .await?
+            .check("scroll")?;
+    }
+    if let Some(scroll_id) = scroll_id {
+        api.send(
+            Method::DELETE,
+            "_search/scroll",
+            Some(json!({ "scroll_id": [scroll_id] })),
+        )
+        .await?;
+    }
+    Ok(missing)
+}
+
+/// Fails if `alias` is the name of an index. An alias cannot be added with the name of an index,
+/// so a rebuild would fail after loading.
+pub async fn check_alias_name<A: RestApi>(
+    api: &A,
+    alias: &str,
+    config_file: &str,
+) -> Result<(), LoaderError> {
+    let response = api
+        .send(Method::GET, &format!("_alias/{}", alias), None)
+        .await?;
+    if response.status != StatusCode::NOT_FOUND {
+        response.check("get alias")?;
+        return Ok(());
+    }
+    let response = api.send(Method::HEAD, alias, None).await?;
+    match response.status {
+        StatusCode::NOT_FOUND => Ok(()),
+        StatusCode::OK => Err(LoaderError::Config {
+            path: config_file.to_string(),
+            message: format!(
+                "{} is an index, but --rebuild needs an alias of that name. Move the index to an alias before rebuilding it",
+                alias
+            ),
+        }),
+        status => Err(LoaderError::Transport(format!(
+            "indices exists request failed. Status Code is {:?}.",
+            status
+        ))),
+    }
+}
+
+/// Checks that `generation` has the `loaded` documents, moves `alias` to it and deletes
+/// the old generations.
+pub async fn switch_alias<A: RestApi>(
+    api: &A,
+    alias: &str,
+    generation: &str,
+    loaded: usize,
+    rebuild: &RebuildConfig,
+) -> Result<(), LoaderError> {
+    api.send(Method::POST, &format!("{}/_refresh", generation), None)
+        .await?
+        .check("refresh")?;
+    let count = api
+        .send(Method::GET, &format!("{}/_count", generation), None)
+        .await?
+        .check("count")?["count"]
+        .as_u64()
+        .unwrap_or_default() as usize;
+    info!("{} index has {} documents.", generation, count);
+    rebuild.check_count(generation, count, loaded)?;
+    // the alias is moved in one request, so searches never see an empty alias
+    let response = api
+        .send(Method::GET, &format!("_alias/{}", alias), None)
+        .await?;
+    let mut actions: Vec<Value> = match response.status {
+        StatusCode::NOT_FOUND => vec![],
+        _ => response
+            .check("get alias")?
+            .as_object()
+            .into_iter()
+            .flat_map(|indices| indices.keys())
+            .map(|index| json!({"remove": {"index": index, "alias": alias}}))
+            .collect(),
+    };
+    actions.push(json!({"add": {"index": generation, "alias": alias}}));
+    api.send(
+        Method::POST,
+        "_aliases",
+        Some(json!({ "actions": actions })),
+    )
+    .await?
+    .check("update aliases")?;
+    info!("{} alias was switched to {}.", alias, generation);
+    let generations: Vec<String> = api
+        .send(Method::GET, &format!("{}_*", alias), None)
+        .await?
+        .check("get index")?
+        .as_object()
+        .into_iter()
+        .flat_map(|indices| indices.keys())
+        .filter(|name| is_generation(name, alias, '_'))
+        .cloned()
+        .collect();
+    for index in rebuild.expired(generations, generation) {
+        let response = api.send(Method::DELETE, &index, None).await?;
+        if response.status.is_success() {
+            info!("{} index was deleted.", index);
+        } else {
+            warn!(
+                "Delete index request has failed. Status Code is {:?}. {}",
+                response.status, index
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::test_support::{block_on, document};
+    use std::sync::Mutex;
+
+    /// Answers every bulk request with a successful response.
+    #[derive(Default)]
+    struct AcceptAll {
+        bodies: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl RestApi for AcceptAll {
+        async fn send(
+            &self,
+            _method: Method,
+            _path: &str,
+            _body: Option<Value>,
+        ) -> Result<ApiResponse, LoaderError> {
+            ApiResponse::new(StatusCode::OK, "{}")
+        }
+
+        async fn bulk(&self, _index_name: &str, body: String) -> Result<ApiResponse, LoaderError> {
+            self.bodies.lock().unwrap().push(body);
+            ApiResponse::new(StatusCode::OK, r#"{"errors": false, "items": []}"#)
+        }
+    }
+
+    #[test]
+    fn external_version_in_sync_mode() {
+        assert_eq!(
+            bulk_action(&document("1", "42"), true),
+            Some(json!({"index": {"_id": "1", "version": 42, "version_type": "external"}}))
+        );
+        assert_eq!(bulk_action(&document("1", "r42"), true), None);
+        assert_eq!(
+            bulk_action(&document("1", "r42"), false),
+            Some(json!({"index": {"_id": "1"}}))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_revision_in_sync_mode() {
+        let api = AcceptAll::default();
+        let docs = vec![document("1", "10"), document("2", "r20")];
+        let rejected =
+            block_on(send_bulk(&api, "wiki", &RetryConfig::default(), docs, true)).unwrap();
+        assert_eq!(rejected.len(), 1);
+        match &rejected[0] {
+            DeadLetter::Rejected { id, error_type, .. } => {
+                assert_eq!(id, "2");
+                assert_eq!(error_type, "invalid_revision_id");
+            }
+            _ => panic!("not a rejected document"),
+        }
+        let bodies = api.bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0].lines().count(), 2);
+    }
+
+    #[test]
+    fn skip_request_without_valid_documents() {
+        let api = AcceptAll::default();
+        let rejected = block_on(send_bulk(
+            &api,
+            "wiki",
+            &RetryConfig::default(),
+            vec![document("1", "r10")],
+            true,
+        ))
+        .unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert!(api.bodies.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use tantivy::tokenizer::{Token, TokenStream, Tokenizer};
+
+/// Tokenizer for Japanese text without a dictionary. Runs of CJK characters are split into
+/// overlapping bigrams, like the `cjk` analyzer of Elasticsearch. A single CJK character is
+/// kept as a unigram. Other letters and digits are split into words.
+#[derive(Clone, Default)]
+pub struct CjkBigramTokenizer;
+
+pub struct CjkBigramTokenStream {
+    tokens: Vec<Token>,
+    // index of the current token + 1
+    next: usize,
+}
+
+/// Hiragana, katakana, CJK ideographs, the iteration mark and halfwidth katakana.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3005}'
+        | '\u{3040}'..='\u{30FF}'
+        | '\u{31F0}'..='\u{31FF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FF66}'..='\u{FF9F}')
+}
+
+fn push_token(tokens: &mut Vec<Token>, text: &str, offset_from: usize, offset_to: usize) {
+    tokens.push(Token {
+        offset_from,
+        offset_to,
+        position: tokens.len(),
+        text: text[offset_from..offset_to].to_string(),
+        position_length: 1,
+    });
+}
+
+/// Adds the tokens of a run of CJK characters or of a word.
+fn push_run(tokens: &mut Vec<Token>, text: &str, run: &[(usize, char)], cjk: bool) {
+    let end = |(offset, c): (usize, char)| offset + c.len_utf8();
+    match run {
+        [] => {}
+        [first, .., last] if !cjk => push_token(tokens, text, first.0, end(*last)),
+        [single] => push_token(tokens, text, single.0, end(*single)),
+        _ => {
+            for bigram in run.windows(2) {
+                push_token(tokens, text, bigram[0].0, end(bigram[1]));
+            }
+        }
+    }
+}
+
+impl Tokenizer for CjkBigramTokenizer {
+    type TokenStream<'a> = CjkBigramTokenStream;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> CjkBigramTokenStream {
+        let mut tokens = vec![];
+        let mut run = vec![];
+        let mut run_is_cjk = false;
+        for (offset, c) in text.char_indices() {
+            if !c.is_alphanumeric() {
+                push_run(&mut tokens, text, &run, run_is_cjk);
+                run.clear();
+                continue;
+            }
+            let cjk = is_cjk(c);
+            if cjk != run_is_cjk {
+                push_run(&mut tokens, text, &run, run_is_cjk);
+                run.clear();
+                run_is_cjk = cjk;
+            }
+            run.push((offset, c));
+        }
+        push_run(&mut tokens, text, &run, run_is_cjk);
+        CjkBigramTokenStream { tokens, next: 0 }
+    }
+}
+
+impl TokenStream for CjkBigramTokenStream {
+    fn advance(&mut self) -> bool {
+        if self.next < self.tokens.len() {
+            self.next += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.next - 1]
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.next - 1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<(String, usize, usize, usize)> {
+        let mut tokenizer = CjkBigramTokenizer;
+        let mut stream = tokenizer.token_stream(text);
+        let mut tokens = vec![];
+        while stream.advance() {
+            let token = stream.token();
+            tokens.push((
+                token.text.clone(),
+                token.offset_from,
+                token.offset_to,
+                token.position,
+            ));
+        }
+        tokens
+    }
+
+    fn texts(text: &str) -> Vec<String> {
+        tokens(text).into_iter().map(|(text, ..)| text).collect()
+    }
+
+    #[test]
+    fn unigram_of_single_character() {
+        assert_eq!(texts("本"), vec!["本"]);
+        assert_eq!(texts("本 と 猫"), vec!["本", "と", "猫"]);
+    }
+
+    #[test]
+    fn bigrams_of_kana_and_kanji() {
+        assert_eq!(texts("東京都"), vec!["東京", "京都"]);
+        assert_eq!(
+            texts("日本のカタカナ"),
+            vec!["日本", "本の", "のカ", "カタ", "タカ", "カナ"]
+        );
+        // the iteration mark and halfwidth katakana
+        assert_eq!(texts("人々"), vec!["人々"]);
+        assert_eq!(texts("ｶﾀｶﾅ"), vec!["ｶﾀ", "ﾀｶ", "ｶﾅ"]);
+    }
+
+    #[test]
+    fn words_of_latin_text() {
+        assert_eq!(
+            texts("Rust言語は2015年に公開"),
+            vec!["Rust", "言語", "語は", "2015", "年に", "に公", "公開"]
+        );
+        assert_eq!(texts("wiki-json loader"), vec!["wiki", "json", "loader"]);
+        // punctuation splits the runs
+        assert_eq!(texts("東京、大阪。"), vec!["東京", "大阪"]);
+        assert!(texts("").is_empty());
+        assert!(texts("、。 ").is_empty());
+    }
+
+    #[test]
+    fn byte_offsets_and_positions() {
+        assert_eq!(
+            tokens("a東京b"),
+            vec![
+                (String::from("a"), 0, 1, 0),
+                (String::from("東京"), 1, 7, 1),
+                (String::from("b"), 7, 8, 2),
+            ]
+        );
+        let text = "Ünïcode 漢字";
+        for (token, from, to, _) in tokens(text) {
+            assert_eq!(&text[from..to], token);
+        }
+        assert_eq!(tokens(text)[1], (String::from("漢字"), 10, 16, 1));
+    }
+}
@@ -0,0 +1,173 @@
+use elasticsearch::http::transport::{Connection, ConnectionPool};
+use log::warn;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use url::Url;
+
+/// Sends the requests to the nodes in turn. A request retried after a node error goes to the next node.
+#[derive(Debug, Clone)]
+pub struct RoundRobinConnectionPool {
+    connections: Vec<Connection>,
+    // shared by the clones of the pool in a transport
+    next: Arc<AtomicUsize>,
+}
+
+impl RoundRobinConnectionPool {
+    /// `urls` must not be empty.
+    pub fn new(urls: Vec<Url>) -> Self {
+        RoundRobinConnectionPool {
+            connections: urls.into_iter().map(Connection::new).collect(),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl ConnectionPool for RoundRobinConnectionPool {
+    fn next(&self) -> &Connection {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.connections[next % self.connections.len()]
+    }
+}
+
+/// Reads the HTTP addresses of the nodes from a `_nodes/http` response.
+/// Dedicated master nodes are not used.
+pub fn sniffed_urls(nodes_info: &Value, scheme: &str) -> Vec<Url> {
+    let mut urls = vec![];
+    for node in nodes_info["nodes"]
+        .as_object()
+        .into_iter()
+        .flat_map(|nodes| nodes.values())
+    {
+        if let Some(roles) = node["roles"].as_array() {
+            if roles.len() == 1 && roles[0] == "master" {
+                continue;
+            }
+        }
+        // "hostname/ip:port" or "ip:port"
+        let address = match node["http"]["publish_address"].as_str() {
+            Some(address) => address.rsplit('/').next().unwrap_or(address),
+            None => continue,
+        };
+        match Url::parse(&format!("{}://{}", scheme, address)) {
+            Ok(url) => urls.push(url),
+            Err(e) => warn!("invalid publish_address {}. {}", address, e),
+        }
+    }
+    urls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::test_support::{block_on, TestServer};
+    use elasticsearch::http::headers::HeaderMap;
+    use elasticsearch::http::transport::{Transport, TransportBuilder};
+    use elasticsearch::http::Method;
+    use serde_json::json;
+    use std::net::TcpListener;
+
+    fn urls(urls: &[&str]) -> Vec<Url> {
+        urls.iter().map(|url| Url::parse(url).unwrap()).collect()
+    }
+
+    // a node that refuses connections
+    fn closed_url() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap()
+    }
+
+    fn transport(urls: Vec<Url>) -> Transport {
+        TransportBuilder::new(RoundRobinConnectionPool::new(urls))
+            .build()
+            .unwrap()
+    }
+
+    fn get(transport: &Transport, path: &str) -> Result<u16, elasticsearch::Error> {
+        block_on(transport.send(
+            Method::Get,
+            path,
+            HeaderMap::new(),
+            Option::<&Value>::None,
+            Option::<&str>::None,
+        ))
+        .map(|response| response.status_code().as_u16())
+    }
+
+    #[test]
+    fn publish_addresses() {
+        let nodes_info = json!({"nodes": {
+            "a": {"roles": ["master", "data"], "http": {"publish_address": "10.0.0.1:9200"}},
+            "b": {"roles": ["data"], "http": {"publish_address": "es-node-2/10.0.0.2:9201"}},
+            "c": {"http": {"publish_address": "[::1]:9202"}}
+        }});
+        let mut sniffed: Vec<String> = sniffed_urls(&nodes_info, "https")
+            .iter()
+            .map(|url| url.to_string())
+            .collect();
+        sniffed.sort();
+        assert_eq!(
+            sniffed,
+            vec![
+                "https://10.0.0.1:9200/",
+                "https://10.0.0.2:9201/",
+                "https://[::1]:9202/"
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_nodes_without_http_address() {
+        let nodes_info = json!({"nodes": {
+            "master": {"roles": ["master"], "http": {"publish_address": "10.0.0.1:9200"}},
+            "no_http": {"roles": ["data"]},
+            "invalid": {"roles": ["data"], "http": {"publish_address": "10.0.0.3:port"}},
+            "data": {"roles": ["data"], "http": {"publish_address": "10.0.0.4:9200"}}
+        }});
+        assert_eq!(
+            sniffed_urls(&nodes_info, "http"),
+            urls(&["http://10.0.0.4:9200"])
+        );
+        assert!(sniffed_urls(&json!({}), "http").is_empty());
+    }
+
+    #[test]
+    fn round_robin_order() {
+        let servers: Vec<TestServer> = (0..3)
+            .map(|_| TestServer::start(|_| (200, String::from("{}"))))
+            .collect();
+        let transport = transport(
+            servers
+                .iter()
+                .map(|server| Url::parse(&server.url).unwrap())
+                .collect(),
+        );
+        // the clones of the transport share the position
+        let cloned = transport.clone();
+        for i in 0..7 {
+            let transport = if i % 2 == 0 { &transport } else { &cloned };
+            assert_eq!(get(transport, &format!("/{}", i)).unwrap(), 200);
+        }
+        let paths: Vec<Vec<String>> = servers
+            .iter()
+            .map(|server| server.requests().into_iter().map(|r| r.path).collect())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![vec!["/0", "/3", "/6"], vec!["/1", "/4"], vec!["/2", "/5"]]
+        );
+    }
+
+    #[test]
+    fn next_node_after_error() {
+        let server = TestServer::start(|_| (200, String::from("{}")));
+        let transport = transport(vec![closed_url(), Url::parse(&server.url).unwrap()]);
+        assert!(get(&transport, "/first").is_err());
+        // the retry goes to the next node
+        assert_eq!(get(&transport, "/retry").unwrap(), 200);
+        assert!(get(&transport, "/second").is_err());
+        assert_eq!(get(&transport, "/third").unwrap(), 200);
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, vec!["/retry", "/third"]);
+    }
+}
@@ -1,85 +1,342 @@
-use crate::loader::document::Document;
+use crate::error::LoaderError;
+use crate::loader::dead_letter::{DeadLetter, DeadLetterWriter};
+use crate::loader::transform::TransformedDocument;
+use crate::output::batch::default_max_batch_bytes;
+use crate::output::bulk::{
+    check_alias_name, delete_documents, proceed_chunk, scroll_missing_ids, switch_alias,
+    ApiResponse, RestApi,
+};
+use crate::output::connection_pool::{sniffed_urls, RoundRobinConnectionPool};
+use crate::output::rebuild::{generation_name, RebuildConfig};
+use crate::output::retry::RetryConfig;
 use async_trait::async_trait;
-use elasticsearch::http::request::JsonBody;
-use elasticsearch::http::transport::{SingleNodeConnectionPool, TransportBuilder};
-use elasticsearch::http::StatusCode;
-use elasticsearch::indices::{IndicesCreateParts, IndicesExistsParts};
-use elasticsearch::{BulkParts, Elasticsearch};
+use elasticsearch::auth::Credentials;
+use elasticsearch::cert::{Certificate, CertificateValidation};
+use elasticsearch::http::headers::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use elasticsearch::http::response::Response;
+use elasticsearch::http::transport::{CloudConnectionPool, Transport, TransportBuilder};
+use elasticsearch::http::{Method as EsMethod, StatusCode};
+use elasticsearch::indices::{
+    IndicesCreateParts, IndicesExistsParts, IndicesForcemergeParts, IndicesGetSettingsParts,
+    IndicesPutSettingsParts, IndicesRefreshParts,
+};
+use elasticsearch::nodes::NodesInfoParts;
+use elasticsearch::Elasticsearch;
+use lazy_static::lazy_static;
 use log::{debug, info, warn};
+use reqwest::Method;
 use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
-use std::io::Error;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
 use url::Url;
 
+lazy_static! {
+    // Nodes are sniffed once before loading. The outputs of the files share the connection pool
+    // of the sniffed nodes and its number of nodes, keyed by the config file.
+    static ref SNIFFED: Mutex<HashMap<String, (Elasticsearch, usize)>> = Mutex::new(HashMap::new());
+}
+
 #[async_trait]
-pub trait SearchEngine {
-    fn new(config_file: &str) -> Self
+pub trait SearchEngine: Send + Sync {
+    fn new(config_file: &str) -> Result<Self, LoaderError>
     where
         Self: Sized;
-    fn add_document(&mut self, document: Document);
-    fn initialize(&self);
-    fn exist_index(&self) -> bool;
-    fn close(&mut self);
+    /// Documents rejected by the search engine are written to `dead_letter`.
+    fn set_dead_letter(&mut self, dead_letter: Arc<DeadLetterWriter>);
+    /// In sync mode, documents whose revision is already indexed are skipped.
+    fn set_sync(&mut self, sync: bool);
+    async fn add_document(&mut self, document: TransformedDocument) -> Result<(), LoaderError>;
+    /// Number of added documents whose requests have finished. Requests finish in the order
+    /// the documents were added.
+    fn acknowledged(&self) -> usize;
+    async fn initialize(&self) -> Result<(), LoaderError>;
+    async fn exist_index(&self) -> Result<bool, LoaderError>;
+    async fn close(&mut self) -> Result<(), LoaderError>;
+    /// Called once after all files are loaded, even if some files failed to load.
+    async fn finalize(&self) -> Result<(), LoaderError>;
+    /// Deletes the indexed documents whose ids are not in `ids`. Returns the number of deleted documents.
+    async fn delete_missing(&self, ids: &HashSet<String>) -> Result<usize, LoaderError>;
+    /// Sends the documents to `index_name` instead of the index in the config.
+    fn set_index(&mut self, index_name: &str);
+    /// Creates a new generation of the index from the schema file for a rebuild. Returns its name.
+    /// Fails before loading if the index in the config cannot be switched to the generation.
+    async fn create_generation(&self) -> Result<String, LoaderError>;
+    /// Checks that `generation` has the `loaded` documents accepted by the search engine,
+    /// switches the index in the config to it and deletes the old generations.
+    async fn switch_generation(&self, generation: &str, loaded: usize) -> Result<(), LoaderError>;
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct EsConfig {
+    /// Not used if `cloud_id` is set.
+    #[serde(default)]
     url: String,
+    /// More nodes of the cluster. The requests are sent to `url` and `urls` in turn.
+    #[serde(default)]
+    urls: Vec<String>,
+    /// Finds the nodes of the cluster from `url` and `urls` before loading.
+    #[serde(default)]
+    sniff: bool,
+    /// Cloud ID of an Elastic Cloud deployment.
+    cloud_id: Option<String>,
+    /// Basic authentication.
+    username: Option<String>,
+    password: Option<String>,
+    /// API key authentication.
+    api_key_id: Option<String>,
+    api_key: Option<String>,
+    /// PEM file of the CA certificate that signed the certificate of the cluster.
+    ca_cert: Option<String>,
+    /// Set false to skip the certificate validation, only for test clusters.
+    #[serde(default = "default_verify_certificate")]
+    verify_certificate: bool,
+    /// Proxy server. The proxy environment variables are not used.
+    proxy_url: Option<String>,
+    proxy_username: Option<String>,
+    proxy_password: Option<String>,
     buffer_size: usize,
     index_name: String,
     schema_file: String,
+    #[serde(default = "default_concurrent_requests")]
+    concurrent_requests: usize,
+    /// Maximum size of a bulk request body. Bulk requests are split to fit in it.
+    #[serde(default = "default_max_batch_bytes")]
+    max_batch_bytes: usize,
+    #[serde(default)]
+    retry: RetryConfig,
+    #[serde(default)]
+    rebuild: RebuildConfig,
+    bulk_load: Option<BulkLoadConfig>,
+}
+
+/// Index settings while loading. Put under `bulk_load:` in the config yaml to enable them.
+/// The original settings are restored after loading.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct BulkLoadConfig {
+    refresh_interval: String,
+    number_of_replicas: u32,
+    /// Force-merges the index into this number of segments after loading.
+    max_num_segments: Option<i64>,
+    /// Refreshes the index after loading.
+    refresh: bool,
+}
+
+impl Default for BulkLoadConfig {
+    fn default() -> Self {
+        BulkLoadConfig {
+            refresh_interval: String::from("-1"),
+            number_of_replicas: 0,
+            max_num_segments: None,
+            refresh: true,
+        }
+    }
+}
+
+const BULK_LOAD_SETTINGS: [&str; 2] = ["index.refresh_interval", "index.number_of_replicas"];
+
+pub fn default_concurrent_requests() -> usize {
+    1
+}
+
+pub fn default_verify_certificate() -> bool {
+    true
 }
 
+/// A bulk request in flight. It returns the documents rejected by the search engine.
+pub type BulkTask = JoinHandle<Result<Vec<DeadLetter>, LoaderError>>;
+
 pub struct ElasticsearchOutput {
     client: Elasticsearch,
-    buffer: Vec<Document>,
-    config: EsConfig,
+    buffer: Vec<TransformedDocument>,
+    config: Arc<EsConfig>,
+    in_flight: VecDeque<(usize, BulkTask)>,
+    acknowledged: usize,
+    rejected: usize,
+    dead_letter: Option<Arc<DeadLetterWriter>>,
+    sync: bool,
+    // the index and its settings before `bulk_load` was applied
+    original_settings: Mutex<Option<(String, Value)>>,
+    config_file: String,
+    // number of the nodes in the connection pool
+    nodes: usize,
+}
+
+fn load_config(config_file: &str) -> Result<EsConfig, LoaderError> {
+    read_config(config_file)
 }
 
-fn load_config(config_file: &str) -> EsConfig {
+/// Reads a yaml config file for a search engine output.
+pub fn read_config<T>(config_file: &str) -> Result<T, LoaderError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let config_error = |message: String| LoaderError::Config {
+        path: config_file.to_string(),
+        message,
+    };
     let f = File::open(config_file)
-        .expect(format!("config file is not found. {}", config_file).as_str());
-    let config: EsConfig = serde_yaml::from_reader(f).expect(format!("Parse Error").as_str());
-    return config;
+        .map_err(|e| config_error(format!("config file is not found. {}", e)))?;
+    serde_yaml::from_reader(f).map_err(|e| config_error(format!("Parse Error. {}", e)))
+}
+
+fn node_urls(config: &EsConfig, config_file: &str) -> Result<Vec<Url>, LoaderError> {
+    let config_error = |message: String| LoaderError::Config {
+        path: config_file.to_string(),
+        message,
+    };
+    let parse_url = |url: &String| {
+        Url::parse(url).map_err(|e| config_error(format!("invalid url {}. {}", url, e)))
+    };
+    let urls = std::iter::once(&config.url)
+        .filter(|url| !url.is_empty())
+        .chain(&config.urls)
+        .map(parse_url)
+        .collect::<Result<Vec<_>, _>>()?;
+    if urls.is_empty() && config.cloud_id.is_none() {
+        return Err(config_error(String::from(
+            "url, urls or cloud_id is required",
+        )));
+    }
+    Ok(urls)
+}
+
+/// Builds the transport to `nodes`, or to Elastic Cloud if `cloud_id` is set.
+fn build_transport(
+    config: &EsConfig,
+    config_file: &str,
+    nodes: Vec<Url>,
+) -> Result<Transport, LoaderError> {
+    let config_error = |message: String| LoaderError::Config {
+        path: config_file.to_string(),
+        message,
+    };
+    if config.cloud_id.is_some() && !nodes.is_empty() {
+        return Err(config_error(String::from(
+            "set either url, urls or cloud_id, not both",
+        )));
+    }
+    let mut builder = match &config.cloud_id {
+        Some(cloud_id) => TransportBuilder::new(
+            CloudConnectionPool::new(cloud_id)
+                .map_err(|e| config_error(format!("invalid cloud_id. {}", e)))?,
+        ),
+        None => TransportBuilder::new(RoundRobinConnectionPool::new(nodes)),
+    };
+    builder = match (&config.username, &config.api_key_id, &config.api_key) {
+        (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+            return Err(config_error(String::from(
+                "set either username or api_key, not both",
+            )))
+        }
+        (Some(username), None, None) => builder.auth(Credentials::Basic(
+            username.clone(),
+            config.password.clone().unwrap_or_default(),
+        )),
+        (None, Some(api_key_id), Some(api_key)) => {
+            builder.auth(Credentials::ApiKey(api_key_id.clone(), api_key.clone()))
+        }
+        (None, None, None) => builder,
+        (None, _, _) => {
+            return Err(config_error(String::from(
+                "api_key_id and api_key must be set together",
+            )))
+        }
+    };
+    if !config.verify_certificate {
+        warn!("The certificate of the cluster is not verified.");
+        builder = builder.cert_validation(CertificateValidation::None);
+    } else if let Some(ca_cert) = &config.ca_cert {
+        let pem = std::fs::read(ca_cert)
+            .map_err(|e| config_error(format!("cannot read ca_cert {}. {}", ca_cert, e)))?;
+        let certificate = Certificate::from_pem(&pem)
+            .map_err(|e| config_error(format!("invalid ca_cert {}. {}", ca_cert, e)))?;
+        builder = builder.cert_validation(CertificateValidation::Full(certificate));
+    }
+    builder = match &config.proxy_url {
+        Some(proxy_url) => builder.proxy(
+            Url::parse(proxy_url)
+                .map_err(|e| config_error(format!("invalid url {}. {}", proxy_url, e)))?,
+            config.proxy_username.as_deref(),
+            config.proxy_password.as_deref(),
+        ),
+        None => builder.disable_proxy(),
+    };
+    builder
+        .build()
+        .map_err(|e| LoaderError::Transport(e.to_string()))
 }
 
-pub fn load_schema(schema_file: &str) -> Value {
+pub fn load_schema(schema_file: &str) -> Result<Value, LoaderError> {
     info!("schema file is {}", schema_file);
+    let schema_error = |message: String| LoaderError::Schema {
+        path: schema_file.to_string(),
+        message,
+    };
     let f = File::open(schema_file)
-        .expect(format!("schema file is not found. {}", schema_file).as_str());
-    let schema: Value = serde_json::from_reader(f).expect("schema cannot read...");
-    return schema;
+        .map_err(|e| schema_error(format!("schema file is not found. {}", e)))?;
+    serde_json::from_reader(f).map_err(|e| schema_error(format!("schema cannot read. {}", e)))
 }
 
 #[async_trait]
 impl SearchEngine for ElasticsearchOutput {
-    fn new(_config_file: &str) -> Self {
+    fn new(_config_file: &str) -> Result<Self, LoaderError> {
         // read config
-        let config = load_config(_config_file);
+        let config = load_config(_config_file)?;
         debug!("url: {}", config.url);
         debug!("buffer_size: {}", config.buffer_size);
-        // TODO Elastic Cloud?
-        let url = Url::parse(config.url.as_str()).unwrap();
-        let conn_pool = SingleNodeConnectionPool::new(url);
-        let transport = TransportBuilder::new(conn_pool)
-            .disable_proxy()
-            .build()
-            .unwrap();
-        let client = Elasticsearch::new(transport);
-        let buffer = vec![];
-        ElasticsearchOutput {
+        let sniffed = SNIFFED.lock().unwrap().get(_config_file).cloned();
+        let (client, node_count) = match sniffed {
+            Some(sniffed) => sniffed,
+            None => {
+                let nodes = node_urls(&config, _config_file)?;
+                let node_count = nodes.len().max(1);
+                let transport = build_transport(&config, _config_file, nodes)?;
+                (Elasticsearch::new(transport), node_count)
+            }
+        };
+        let buffer = Vec::with_capacity(config.buffer_size);
+        Ok(ElasticsearchOutput {
             client,
             buffer,
-            config,
-        }
+            config: Arc::new(config),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+            original_settings: Mutex::new(None),
+            config_file: _config_file.to_string(),
+            nodes: node_count,
+        })
+    }
+
+    fn set_dead_letter(&mut self, dead_letter: Arc<DeadLetterWriter>) {
+        self.dead_letter = Some(dead_letter);
+    }
+
+    fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
     }
 
-    fn add_document(&mut self, _document: Document) {
+    async fn add_document(&mut self, _document: TransformedDocument) -> Result<(), LoaderError> {
         self.buffer.push(_document);
+        if self.buffer.len() >= self.config.buffer_size {
+            self.flush().await?;
+        }
+        Ok(())
     }
 
-    fn initialize(&self) {
-        if self.exist_index() {
+    fn acknowledged(&self) -> usize {
+        self.acknowledged
+    }
+
+    async fn initialize(&self) -> Result<(), LoaderError> {
+        self.sniff().await?;
+        if self.exist_index().await? {
             //no-op if index already exists
             info!(
                 "{} index already exists. skip initialization phase.",
@@ -87,144 +344,687 @@ impl SearchEngine for ElasticsearchOutput {
             );
         } else {
             // load schema.json from file
-            // -> if not found, panic!
             // create index with schema file
             info!("{} index is creating...", &self.config.index_name);
-            let mut _rt = tokio::runtime::Runtime::new().expect("Fail initializing runtime");
-            let task = self.call_indices_create();
-            _rt.block_on(task).expect("Something wrong...")
+            self.call_indices_create(&self.config.index_name).await?;
         }
+        self.apply_bulk_load_settings(&self.config.index_name).await
     }
 
-    fn exist_index(&self) -> bool {
-        let mut _rt = tokio::runtime::Runtime::new().expect("Fail initializing runtime");
-        let task = self.call_indices_exists();
-        _rt.block_on(task).expect("Something wrong...")
+    async fn exist_index(&self) -> Result<bool, LoaderError> {
+        self.call_indices_exists().await
     }
 
-    fn close(&mut self) {
-        let chunk_size = if self.buffer.len() <= self.config.buffer_size {
-            self.buffer.len()
-        } else {
-            self.config.buffer_size
+    async fn close(&mut self) -> Result<(), LoaderError> {
+        if !self.buffer.is_empty() {
+            self.flush().await?;
+        }
+        while !self.in_flight.is_empty() {
+            self.wait_oldest().await?;
+        }
+        match self.rejected {
+            0 => Ok(()),
+            count => Err(LoaderError::Rejected { count }),
+        }
+    }
+
+    async fn finalize(&self) -> Result<(), LoaderError> {
+        SNIFFED.lock().unwrap().remove(&self.config_file);
+        let original_settings = self.original_settings.lock().unwrap().take();
+        let (bulk_load, (index_name, settings)) = match (&self.config.bulk_load, original_settings)
+        {
+            (Some(bulk_load), Some(original_settings)) => (bulk_load, original_settings),
+            _ => return Ok(()),
         };
-        let mut _rt = tokio::runtime::Runtime::new().expect("Fail initializing runtime");
-        let mut tasks = vec![];
-        for chunk in self.buffer.chunks(chunk_size) {
-            let task = self.proceed_chunk(chunk);
-            tasks.push(task);
+        let indices: [&str; 1] = [index_name.as_str()];
+        // merge before the replicas are added, so that they copy the merged segments
+        if let Some(max_num_segments) = bulk_load.max_num_segments {
+            info!("{} index is force-merging...", index_name);
+            let response = self
+                .send_with_failover(|| async move {
+                    self.client
+                        .indices()
+                        .forcemerge(IndicesForcemergeParts::Index(&indices))
+                        .max_num_segments(max_num_segments)
+                        .send()
+                        .await
+                })
+                .await?;
+            if !response.status_code().is_success() {
+                warn!(
+                    "Force merge request has failed. Status Code is {:?}.",
+                    response.status_code()
+                );
+            }
+        }
+        self.call_put_settings(&index_name, settings).await?;
+        info!("{} index settings were restored.", index_name);
+        if bulk_load.refresh {
+            self.send_with_failover(|| async move {
+                self.client
+                    .indices()
+                    .refresh(IndicesRefreshParts::Index(&indices))
+                    .send()
+                    .await
+            })
+            .await?;
         }
+        Ok(())
+    }
 
-        for task in tasks {
-            _rt.block_on(task).expect("Error on task...");
+    async fn delete_missing(&self, ids: &HashSet<String>) -> Result<usize, LoaderError> {
+        let missing = scroll_missing_ids(
+            &self.api(),
+            &self.config.index_name,
+            self.config.buffer_size,
+            ids,
+        )
+        .await?;
+        delete_documents(
+            &self.api(),
+            &self.config.index_name,
+            &missing,
+            self.config.buffer_size,
+        )
+        .await?;
+        Ok(missing.len())
+    }
+
+    fn set_index(&mut self, index_name: &str) {
+        Arc::make_mut(&mut self.config).index_name = index_name.to_string();
+    }
+
+    async fn create_generation(&self) -> Result<String, LoaderError> {
+        self.sniff().await?;
+        check_alias_name(&self.api(), &self.config.index_name, &self.config_file).await?;
+        let generation = generation_name(&self.config.index_name, '_');
+        info!("{} index is creating...", generation);
+        self.call_indices_create(&generation).await?;
+        self.apply_bulk_load_settings(&generation).await?;
+        Ok(generation)
+    }
+
+    async fn switch_generation(&self, generation: &str, loaded: usize) -> Result<(), LoaderError> {
+        switch_alias(
+            &self.api(),
+            &self.config.index_name,
+            generation,
+            loaded,
+            &self.config.rebuild,
+        )
+        .await
+    }
+}
+
+/// The client that sends a request again to the next nodes if a node cannot be connected.
+#[derive(Clone)]
+struct FailoverClient {
+    client: Elasticsearch,
+    nodes: usize,
+}
+
+fn es_method(method: Method) -> EsMethod {
+    if method == Method::GET {
+        EsMethod::Get
+    } else if method == Method::PUT {
+        EsMethod::Put
+    } else if method == Method::DELETE {
+        EsMethod::Delete
+    } else if method == Method::HEAD {
+        EsMethod::Head
+    } else {
+        EsMethod::Post
+    }
+}
+
+async fn read_response(response: Response) -> Result<ApiResponse, LoaderError> {
+    let status = response.status_code();
+    ApiResponse::new(status, &response.text().await?)
+}
+
+#[async_trait]
+impl RestApi for FailoverClient {
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<ApiResponse, LoaderError> {
+        let body = body.as_ref().map(|body| body.to_string());
+        let response = send_with_failover(self.nodes, || {
+            self.client.send(
+                es_method(method.clone()),
+                path,
+                HeaderMap::new(),
+                Option::<&Value>::None,
+                body.as_deref(),
+            )
+        })
+        .await?;
+        read_response(response).await
+    }
+
+    async fn bulk(&self, index_name: &str, body: String) -> Result<ApiResponse, LoaderError> {
+        let path = format!("{}/_bulk", index_name);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-ndjson"),
+        );
+        let response = send_with_failover(self.nodes, || {
+            self.client.send(
+                EsMethod::Post,
+                &path,
+                headers.clone(),
+                Option::<&Value>::None,
+                Some(body.as_str()),
+            )
+        })
+        .await?;
+        read_response(response).await
+    }
+}
+
+/// Sends a request, and sends it again to the next nodes if a node cannot be connected.
+async fn send_with_failover<F, R>(nodes: usize, send: F) -> Result<Response, elasticsearch::Error>
+where
+    F: Fn() -> R,
+    R: Future<Output = Result<Response, elasticsearch::Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        match send().await {
+            Err(err) if attempt < nodes => {
+                warn!("Request has failed. {}. try the next node.", err);
+                attempt += 1;
+            }
+            result => return result,
         }
-        self.buffer.clear();
     }
 }
 
 impl ElasticsearchOutput {
-    async fn call_indices_create(&self) -> Result<(), Error> {
-        let schema_json = load_schema(&self.config.schema_file);
+    /// Sends the buffered documents as one bulk request and waits for the oldest
+    /// request if more than `concurrent_requests` are in flight.
+    async fn flush(&mut self) -> Result<(), LoaderError> {
+        let chunk = std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(self.config.buffer_size),
+        );
+        let chunk_len = chunk.len();
+        let task = proceed_chunk(
+            self.api(),
+            self.config.index_name.clone(),
+            self.config.max_batch_bytes,
+            self.config.retry.clone(),
+            chunk,
+            self.sync,
+        );
+        self.in_flight.push_back((chunk_len, tokio::spawn(task)));
+        while self.in_flight.len() > self.config.concurrent_requests {
+            self.wait_oldest().await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a request, and sends it again to the next nodes if a node cannot be connected.
+    async fn send_with_failover<F, R>(&self, send: F) -> Result<Response, elasticsearch::Error>
+    where
+        F: Fn() -> R,
+        R: Future<Output = Result<Response, elasticsearch::Error>>,
+    {
+        send_with_failover(self.nodes, send).await
+    }
+
+    fn api(&self) -> FailoverClient {
+        FailoverClient {
+            client: self.client.clone(),
+            nodes: self.nodes,
+        }
+    }
+
+    /// Finds the nodes of the cluster for the outputs created after this.
+    async fn sniff(&self) -> Result<(), LoaderError> {
+        if !self.config.sniff || self.config.cloud_id.is_some() {
+            return Ok(());
+        }
         let response = self
-            .client
-            .indices()
-            .create(IndicesCreateParts::Index(&self.config.index_name))
-            .body(schema_json)
-            .send()
+            .send_with_failover(|| async move {
+                self.client
+                    .nodes()
+                    .info(NodesInfoParts::Metric(&["http"]))
+                    .send()
+                    .await
+            })
             .await;
-        match response {
+        let nodes_info = match response {
+            Ok(response) if response.status_code().is_success() => response.json::<Value>().await?,
             Ok(response) => {
-                if !response.status_code().is_success() {
-                    warn!(
-                        "Create index request has failed. Status Code is {:?}.",
-                        response.status_code()
-                    );
-                    // FIXME if ...
-                    panic!("create index failed")
-                } else {
-                    info!("{} index was created.", &self.config.index_name);
-                }
+                warn!(
+                    "Nodes info request has failed. Status Code is {:?}. use the configured nodes.",
+                    response.status_code()
+                );
+                return Ok(());
+            }
+            Err(err) => {
+                warn!(
+                    "Nodes info request has failed. {}. use the configured nodes.",
+                    err
+                );
                 return Ok(());
             }
-            Err(_) => panic!("create index failed"),
+        };
+        let configured = node_urls(&self.config, &self.config_file)?;
+        let nodes = sniffed_urls(&nodes_info, configured[0].scheme());
+        if nodes.is_empty() {
+            warn!("No nodes were found. use the configured nodes.");
+            return Ok(());
         }
+        info!("{} nodes were found.", nodes.len());
+        debug!("nodes: {:?}", nodes);
+        let node_count = nodes.len();
+        let transport = build_transport(&self.config, &self.config_file, nodes)?;
+        SNIFFED.lock().unwrap().insert(
+            self.config_file.clone(),
+            (Elasticsearch::new(transport), node_count),
+        );
+        Ok(())
     }
 
-    async fn call_indices_exists(&self) -> Result<bool, Error> {
-        let indices: [&str; 1] = [&self.config.index_name.as_str()];
-        let result = self
-            .client
-            .indices()
-            .exists(IndicesExistsParts::Index(&indices))
-            .send()
-            .await;
-        match result {
-            Ok(response) => match response.status_code() {
-                StatusCode::NOT_FOUND => return Ok(false),
-                StatusCode::OK => return Ok(true),
-                _ => {
-                    warn!(
-                        "Indices exists request has failed. Status Code is {:?}.",
-                        response.status_code()
-                    );
-                    panic!("Indices exists request failed")
+    async fn wait_oldest(&mut self) -> Result<(), LoaderError> {
+        if let Some((chunk_len, task)) = self.in_flight.pop_front() {
+            let rejected = task
+                .await
+                .map_err(|e| LoaderError::Transport(format!("bulk task failed. {}", e)))??;
+            self.acknowledged += chunk_len;
+            self.rejected += rejected.len();
+            if let Some(dead_letter) = &self.dead_letter {
+                for entry in &rejected {
+                    dead_letter.write(entry)?;
                 }
-            },
-            Err(_) => panic!("Indices exists request failed..."),
+            }
         }
+        Ok(())
     }
 
-    pub async fn proceed_chunk(
-        &self,
-        chunk: &[Document],
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut body: Vec<JsonBody<_>> = Vec::new();
-        let mut doc_id = String::new();
-        for d in chunk {
-            if doc_id.is_empty() {
-                doc_id.push_str(d.id.as_str());
-            }
-            body.push(json!({"index": {"_id": d.id}}).into());
-            body.push(JsonBody::from(serde_json::to_value(d).unwrap()));
-        }
-        info!("Sending {} documents... {}", chunk.len(), doc_id);
-        let bulk_response = self
-            .client
-            .bulk(BulkParts::Index(self.config.index_name.as_str()))
-            .body(body)
-            .send()
+    async fn call_indices_create(&self, index_name: &str) -> Result<(), LoaderError> {
+        let schema_json = &load_schema(&self.config.schema_file)?;
+        let response = self
+            .send_with_failover(|| async move {
+                self.client
+                    .indices()
+                    .create(IndicesCreateParts::Index(index_name))
+                    .body(schema_json)
+                    .send()
+                    .await
+            })
             .await?;
-        if !bulk_response.status_code().is_success() {
+        if !response.status_code().is_success() {
             warn!(
-                "Bulk request has failed. Status Code is {:?}. First doc id is [{}]",
-                bulk_response.status_code(),
-                doc_id
+                "Create index request has failed. Status Code is {:?}.",
+                response.status_code()
             );
-            panic!("bulk indexing failed")
-        } else {
-            info!("response : {}", bulk_response.status_code());
-            let response_body = bulk_response.json::<Value>().await?;
-            let successful = response_body["errors"].as_bool().unwrap() == false;
-            if successful == false {
-                warn!("Bulk Request has some errors. {:?}, {}", successful, doc_id);
-                let items = response_body["items"].as_array().unwrap();
-                for item in items {
-                    if let Some(index_obj) = item["index"].as_object() {
-                        if index_obj.contains_key("error") {
-                            if let Some(obj) = index_obj["error"].as_object() {
-                                warn!(
-                                    "error id:[{}], type:[{}], reason:[{}]",
-                                    index_obj.get("_id").unwrap(),
-                                    obj.get("type").unwrap(),
-                                    obj.get("reason").unwrap()
-                                );
-                            }
-                        }
-                    }
-                }
-            }
+            return Err(LoaderError::Transport(format!(
+                "create index failed. Status Code is {:?}.",
+                response.status_code()
+            )));
+        }
+        info!("{} index was created.", index_name);
+        Ok(())
+    }
+
+    /// Applies the `bulk_load` settings and keeps the original settings for `finalize`.
+    async fn apply_bulk_load_settings(&self, index_name: &str) -> Result<(), LoaderError> {
+        let bulk_load = match &self.config.bulk_load {
+            Some(bulk_load) => bulk_load,
+            None => return Ok(()),
+        };
+        let indices: [&str; 1] = [index_name];
+        let response = self
+            .send_with_failover(|| async move {
+                self.client
+                    .indices()
+                    .get_settings(IndicesGetSettingsParts::IndexName(
+                        &indices,
+                        &BULK_LOAD_SETTINGS,
+                    ))
+                    .flat_settings(true)
+                    .send()
+                    .await
+            })
+            .await?;
+        if !response.status_code().is_success() {
+            return Err(LoaderError::Transport(format!(
+                "get settings request failed. Status Code is {:?}.",
+                response.status_code()
+            )));
         }
-        info!("Finished bulk request. {}", doc_id);
+        // keyed by the concrete index if `index_name` is an alias
+        let body = response.json::<Value>().await?;
+        let current = body
+            .as_object()
+            .and_then(|indices| indices.values().next())
+            .map(|index| index["settings"].clone())
+            .unwrap_or_default();
+        // unset settings are restored to the defaults with null
+        let original: Value = BULK_LOAD_SETTINGS
+            .iter()
+            .map(|name| (name.to_string(), current[name].clone()))
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+        self.call_put_settings(
+            index_name,
+            json!({
+                "index.refresh_interval": bulk_load.refresh_interval,
+                "index.number_of_replicas": bulk_load.number_of_replicas,
+            }),
+        )
+        .await?;
+        info!("{} index settings were changed for loading.", index_name);
+        *self.original_settings.lock().unwrap() = Some((index_name.to_string(), original));
         Ok(())
     }
+
+    async fn call_put_settings(
+        &self,
+        index_name: &str,
+        settings: Value,
+    ) -> Result<(), LoaderError> {
+        let indices: [&str; 1] = [index_name];
+        let settings = &settings;
+        let response = self
+            .send_with_failover(|| async move {
+                self.client
+                    .indices()
+                    .put_settings(IndicesPutSettingsParts::Index(&indices))
+                    .body(settings)
+                    .send()
+                    .await
+            })
+            .await?;
+        if !response.status_code().is_success() {
+            return Err(LoaderError::Transport(format!(
+                "put settings request failed. Status Code is {:?}.",
+                response.status_code()
+            )));
+        }
+        Ok(())
+    }
+
+    async fn call_indices_exists(&self) -> Result<bool, LoaderError> {
+        let indices: [&str; 1] = [self.config.index_name.as_str()];
+        let response = self
+            .send_with_failover(|| async move {
+                self.client
+                    .indices()
+                    .exists(IndicesExistsParts::Index(&indices))
+                    .send()
+                    .await
+            })
+            .await?;
+        match response.status_code() {
+            StatusCode::NOT_FOUND => Ok(false),
+            StatusCode::OK => Ok(true),
+            _ => {
+                warn!(
+                    "Indices exists request has failed. Status Code is {:?}.",
+                    response.status_code()
+                );
+                Err(LoaderError::Transport(format!(
+                    "indices exists request failed. Status Code is {:?}.",
+                    response.status_code()
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::test_support::{block_on, document, TestServer};
+    use serde_json::json;
+
+    fn output(yaml: &str) -> ElasticsearchOutput {
+        let config: EsConfig = serde_yaml::from_str(&format!(
+            "buffer_size: 10\nindex_name: wiki\nschema_file: schema.json\n{}",
+            yaml
+        ))
+        .unwrap();
+        let nodes = node_urls(&config, "elasticsearch.yaml").unwrap();
+        let node_count = nodes.len();
+        let transport = build_transport(&config, "elasticsearch.yaml", nodes).unwrap();
+        ElasticsearchOutput {
+            client: Elasticsearch::new(transport),
+            buffer: vec![],
+            config: Arc::new(config),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+            original_settings: Mutex::new(None),
+            config_file: String::from("elasticsearch.yaml"),
+            nodes: node_count,
+        }
+    }
+
+    // answers the requests of an existing index "wiki" that is an alias of "wiki_1"
+    fn settings_server() -> TestServer {
+        TestServer::start(|request| {
+            if request.method == "GET" && request.path.starts_with("/wiki/_settings") {
+                let body = json!({"wiki_1": {"settings": {"index.refresh_interval": "5s"}}});
+                (200, body.to_string())
+            } else {
+                (200, json!({"acknowledged": true}).to_string())
+            }
+        })
+    }
+
+    #[test]
+    fn apply_and_restore_bulk_load_settings() {
+        let server = settings_server();
+        let output = output(&format!(
+            "url: {}\nbulk_load:\n  refresh: true\n",
+            server.url
+        ));
+        block_on(output.initialize()).unwrap();
+        block_on(output.finalize()).unwrap();
+        let requests = server.requests();
+        let puts: Vec<Value> = requests
+            .iter()
+            .filter(|request| request.method == "PUT")
+            .map(|request| {
+                assert!(request.path.starts_with("/wiki/_settings"));
+                serde_json::from_str(&request.body).unwrap()
+            })
+            .collect();
+        assert_eq!(
+            puts,
+            vec![
+                json!({"index.refresh_interval": "-1", "index.number_of_replicas": 0}),
+                // the replicas were not set, so they are restored to the default
+                json!({"index.refresh_interval": "5s", "index.number_of_replicas": null}),
+            ]
+        );
+        assert!(requests.last().unwrap().path.starts_with("/wiki/_refresh"));
+    }
+
+    #[test]
+    fn restore_bulk_load_settings_once() {
+        let server = settings_server();
+        let output = output(&format!(
+            "url: {}\nbulk_load:\n  refresh: false\n",
+            server.url
+        ));
+        block_on(output.initialize()).unwrap();
+        block_on(output.finalize()).unwrap();
+        block_on(output.finalize()).unwrap();
+        let puts = server
+            .requests()
+            .iter()
+            .filter(|request| request.method == "PUT")
+            .count();
+        assert_eq!(puts, 2);
+    }
+
+    #[test]
+    fn skip_settings_without_bulk_load() {
+        let server = settings_server();
+        let output = output(&format!("url: {}\n", server.url));
+        block_on(output.initialize()).unwrap();
+        block_on(output.finalize()).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "HEAD");
+    }
+
+    #[test]
+    fn get_settings_from_the_next_node() {
+        let server = settings_server();
+        // nothing listens on the first node
+        let output = output(&format!(
+            "urls: [\"http://127.0.0.1:1\", \"{}\"]\nbulk_load:\n  refresh: false\n",
+            server.url
+        ));
+        block_on(output.apply_bulk_load_settings("wiki")).unwrap();
+        block_on(output.finalize()).unwrap();
+        let methods: Vec<String> = server
+            .requests()
+            .iter()
+            .map(|request| request.method.clone())
+            .collect();
+        assert_eq!(methods, vec!["GET", "PUT", "PUT"]);
+    }
+
+    fn transport_error(yaml: &str) -> String {
+        let config: EsConfig = serde_yaml::from_str(&format!(
+            "buffer_size: 10\nindex_name: wiki\nschema_file: schema.json\n{}",
+            yaml
+        ))
+        .unwrap();
+        let nodes = node_urls(&config, "elasticsearch.yaml").unwrap();
+        match build_transport(&config, "elasticsearch.yaml", nodes) {
+            Err(LoaderError::Config { path, message }) => {
+                assert_eq!(path, "elasticsearch.yaml");
+                message
+            }
+            Err(err) => panic!("not a config error {}", err),
+            Ok(_) => panic!("the transport was built"),
+        }
+    }
+
+    #[test]
+    fn fail_on_two_authentications() {
+        for auth in [
+            "username: elastic\napi_key: key\n",
+            "username: elastic\napi_key_id: id\napi_key: key\n",
+        ] {
+            let message = transport_error(&format!("url: http://localhost:9200\n{}", auth));
+            assert_eq!(message, "set either username or api_key, not both");
+        }
+        let message = transport_error("url: http://localhost:9200\napi_key: key\n");
+        assert_eq!(message, "api_key_id and api_key must be set together");
+    }
+
+    #[test]
+    fn fail_on_cloud_id_with_urls() {
+        let cloud_id = "cloud_id: \"test:bG9jYWxob3N0JGFiY2QkZWZnaA==\"\n";
+        for nodes in [
+            "url: http://localhost:9200\n",
+            "urls: [\"http://localhost:9200\"]\n",
+        ] {
+            let message = transport_error(&format!("{}{}", cloud_id, nodes));
+            assert_eq!(message, "set either url, urls or cloud_id, not both");
+        }
+    }
+
+    #[test]
+    fn fail_on_unreadable_ca_cert() {
+        let message =
+            transport_error("url: http://localhost:9200\nca_cert: /nonexistent/http_ca.crt\n");
+        assert!(message.starts_with("cannot read ca_cert /nonexistent/http_ca.crt."));
+        // not used without the validation
+        let config: EsConfig = serde_yaml::from_str(
+            "url: http://localhost:9200\nca_cert: /nonexistent/http_ca.crt\nverify_certificate: false\nbuffer_size: 10\nindex_name: wiki\nschema_file: schema.json\n",
+        )
+        .unwrap();
+        let nodes = node_urls(&config, "elasticsearch.yaml").unwrap();
+        assert!(build_transport(&config, "elasticsearch.yaml", nodes).is_ok());
+    }
+
+    #[test]
+    fn fail_on_invalid_proxy_url() {
+        let message = transport_error("url: http://localhost:9200\nproxy_url: \"not a url\"\n");
+        assert!(message.starts_with("invalid url not a url."));
+    }
+
+    #[test]
+    fn send_requests_through_proxy() {
+        let proxy = TestServer::start(|_| (404, String::from("{}")));
+        let output = output(&format!(
+            "url: http://elasticsearch.invalid:9200\nproxy_url: {}\n",
+            proxy.url
+        ));
+        assert!(!block_on(output.exist_index()).unwrap());
+        let requests = proxy.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "http://elasticsearch.invalid:9200/wiki");
+    }
+
+    #[test]
+    fn share_sniffed_nodes() {
+        let node = TestServer::start(|request| {
+            let id =
+                request.body.lines().next().map(|line| {
+                    serde_json::from_str::<Value>(line).unwrap()["index"]["_id"].clone()
+                });
+            let body = json!({"errors": false, "items": [{"index": {"_id": id, "status": 201}}]});
+            (200, body.to_string())
+        });
+        let address = node.url.trim_start_matches("http://").to_string();
+        let seed = TestServer::start(move |request| {
+            let body = if request.path.starts_with("/_nodes/http") {
+                json!({"nodes": {"a": {"roles": ["data"], "http": {"publish_address": address}}}})
+            } else {
+                json!({})
+            };
+            (200, body.to_string())
+        });
+        let config_file = std::env::temp_dir().join(format!(
+            "wiki-json-loader-sniff-{}.yaml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &config_file,
+            format!(
+                "url: {}\nsniff: true\nbuffer_size: 10\nindex_name: wiki\nschema_file: schema.json\n",
+                seed.url
+            ),
+        )
+        .unwrap();
+        let config_file = config_file.to_str().unwrap();
+        let initializer = ElasticsearchOutput::new(config_file).unwrap();
+        block_on(initializer.initialize()).unwrap();
+        for id in &["1", "2"] {
+            let mut output = ElasticsearchOutput::new(config_file).unwrap();
+            block_on(async {
+                output.add_document(document(id, "1")).await?;
+                output.close().await
+            })
+            .unwrap();
+        }
+        block_on(initializer.finalize()).unwrap();
+        let seed_paths: Vec<String> = seed.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(seed_paths.len(), 2);
+        assert!(seed_paths[0].starts_with("/_nodes/http"));
+        let node_paths: Vec<String> = node.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(node_paths.len(), 2);
+        assert!(node_paths
+            .iter()
+            .all(|path| path.starts_with("/wiki/_bulk")));
+        // the sniffed nodes are released by finalize
+        assert!(!SNIFFED.lock().unwrap().contains_key(config_file));
+        std::fs::remove_file(config_file).unwrap();
+    }
 }
@@ -0,0 +1,709 @@
+use crate::error::LoaderError;
+use crate::loader::dead_letter::{DeadLetter, DeadLetterWriter};
+use crate::loader::transform::TransformedDocument;
+use crate::output::batch::{default_max_batch_bytes, split_by_bytes};
+use crate::output::elasticsearch_output::SearchEngine;
+use crate::output::elasticsearch_output::{default_concurrent_requests, read_config, BulkTask};
+use crate::output::rebuild::{generation_name, is_generation, RebuildConfig};
+use crate::output::retry::{is_retryable_status, RetryConfig};
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const PRIMARY_KEY: &str = "id";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MeilisearchConfig {
+    url: String,
+    /// Sent as a Bearer token.
+    api_key: Option<String>,
+    buffer_size: usize,
+    index_name: String,
+    #[serde(default)]
+    settings: MeilisearchSettings,
+    #[serde(default = "default_concurrent_requests")]
+    concurrent_requests: usize,
+    /// Maximum size of a request body. Requests are split to fit in it.
+    #[serde(default = "default_max_batch_bytes")]
+    max_batch_bytes: usize,
+    /// Interval of polling the task of a request.
+    #[serde(default = "default_task_poll_interval_ms")]
+    task_poll_interval_ms: u64,
+    /// A task not finished in this time is an error.
+    #[serde(default = "default_task_timeout_secs")]
+    task_timeout_secs: u64,
+    #[serde(default)]
+    retry: RetryConfig,
+    #[serde(default)]
+    rebuild: RebuildConfig,
+}
+
+/// Index settings applied before loading.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct MeilisearchSettings {
+    searchable_attributes: Option<Vec<String>>,
+    filterable_attributes: Option<Vec<String>>,
+    sortable_attributes: Option<Vec<String>>,
+}
+
+fn default_task_poll_interval_ms() -> u64 {
+    500
+}
+
+fn default_task_timeout_secs() -> u64 {
+    600
+}
+
+pub struct MeilisearchOutput {
+    client: Client,
+    buffer: Vec<TransformedDocument>,
+    config: Arc<MeilisearchConfig>,
+    in_flight: VecDeque<(usize, BulkTask)>,
+    acknowledged: usize,
+    rejected: usize,
+    dead_letter: Option<Arc<DeadLetterWriter>>,
+    sync: bool,
+}
+
+fn load_config(config_file: &str) -> Result<MeilisearchConfig, LoaderError> {
+    read_config(config_file)
+}
+
+/// Returns the body of a successful response.
+async fn check_status(response: Response, request: &str) -> Result<Value, LoaderError> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.json::<Value>().await.unwrap_or_default();
+        return Err(LoaderError::Transport(format!(
+            "{} request failed. Status Code is {:?}. {}",
+            request, status, body["message"]
+        )));
+    }
+    Ok(response.json::<Value>().await?)
+}
+
+/// Uid of the task enqueued by a request.
+fn task_uid(body: &Value, request: &str) -> Result<u64, LoaderError> {
+    body["taskUid"].as_u64().ok_or_else(|| {
+        LoaderError::Transport(format!("{} response has no taskUid. {}", request, body))
+    })
+}
+
+#[async_trait]
+impl SearchEngine for MeilisearchOutput {
+    fn new(_config_file: &str) -> Result<Self, LoaderError>
+    where
+        Self: Sized,
+    {
+        let config = load_config(_config_file)?;
+        debug!("url: {}", config.url);
+        debug!("buffer_size: {}", config.buffer_size);
+        let buffer = Vec::with_capacity(config.buffer_size);
+        Ok(MeilisearchOutput {
+            client: Client::new(),
+            buffer,
+            config: Arc::new(config),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+        })
+    }
+
+    fn set_dead_letter(&mut self, dead_letter: Arc<DeadLetterWriter>) {
+        self.dead_letter = Some(dead_letter);
+    }
+
+    fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    async fn add_document(&mut self, _document: TransformedDocument) -> Result<(), LoaderError> {
+        self.buffer.push(_document);
+        if self.buffer.len() >= self.config.buffer_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    fn acknowledged(&self) -> usize {
+        self.acknowledged
+    }
+
+    async fn initialize(&self) -> Result<(), LoaderError> {
+        if self.exist_index().await? {
+            info!(
+                "{} index already exists. skip initialization phase.",
+                &self.config.index_name
+            );
+        } else {
+            info!("{} index is creating...", &self.config.index_name);
+            self.call_indices_create(&self.config.index_name).await?;
+        }
+        // applied to an existing index too, e.g. to make `id` filterable for --sync
+        self.call_update_settings(&self.config.index_name).await
+    }
+
+    async fn exist_index(&self) -> Result<bool, LoaderError> {
+        self.call_exist_index(&self.config.index_name).await
+    }
+
+    async fn close(&mut self) -> Result<(), LoaderError> {
+        if !self.buffer.is_empty() {
+            self.flush().await?;
+        }
+        while !self.in_flight.is_empty() {
+            self.wait_oldest().await?;
+        }
+        match self.rejected {
+            0 => Ok(()),
+            count => Err(LoaderError::Rejected { count }),
+        }
+    }
+
+    async fn finalize(&self) -> Result<(), LoaderError> {
+        Ok(())
+    }
+
+    async fn delete_missing(&self, ids: &HashSet<String>) -> Result<usize, LoaderError> {
+        let missing = self.call_missing_ids(ids).await?;
+        for chunk in missing.chunks(self.config.buffer_size) {
+            info!("Deleting {} documents... {}", chunk.len(), chunk[0]);
+            let response = self
+                .request(
+                    Method::POST,
+                    &format!("indexes/{}/documents/delete-batch", self.config.index_name),
+                )
+                .json(chunk)
+                .send()
+                .await?;
+            self.call_wait_task(response, "delete").await?;
+        }
+        Ok(missing.len())
+    }
+
+    fn set_index(&mut self, index_name: &str) {
+        Arc::make_mut(&mut self.config).index_name = index_name.to_string();
+    }
+
+    async fn create_generation(&self) -> Result<String, LoaderError> {
+        // the generation is swapped with the index, so the index need not be an alias
+        let generation = generation_name(&self.config.index_name, '_');
+        info!("{} index is creating...", generation);
+        self.call_indices_create(&generation).await?;
+        self.call_update_settings(&generation).await?;
+        Ok(generation)
+    }
+
+    async fn switch_generation(&self, generation: &str, loaded: usize) -> Result<(), LoaderError> {
+        let response = self
+            .request(Method::GET, &format!("indexes/{}/stats", generation))
+            .send()
+            .await?;
+        let count = check_status(response, "stats").await?["numberOfDocuments"]
+            .as_u64()
+            .unwrap_or_default() as usize;
+        info!("{} index has {} documents.", generation, count);
+        self.config.rebuild.check_count(generation, count, loaded)?;
+        // Meilisearch has no aliases. The indexes are swapped, so the generation
+        // keeps the old documents after the swap
+        let index_name = self.config.index_name.as_str();
+        if !self.call_exist_index(index_name).await? {
+            self.call_indices_create(index_name).await?;
+        }
+        let response = self
+            .request(Method::POST, "swap-indexes")
+            .json(&json!([{ "indexes": [index_name, generation] }]))
+            .send()
+            .await?;
+        self.call_wait_task(response, "swap indexes").await?;
+        info!("{} index was switched to {}.", index_name, generation);
+        let response = self
+            .request(Method::GET, "indexes")
+            .query(&[("limit", "1000")])
+            .send()
+            .await?;
+        let generations: Vec<String> = check_status(response, "list indexes").await?["results"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|index| index["uid"].as_str())
+            .filter(|uid| is_generation(uid, index_name, '_'))
+            .map(String::from)
+            .collect();
+        // the generation has the documents swapped out of the index
+        for index in self.config.rebuild.expired(generations, generation) {
+            let response = self
+                .request(Method::DELETE, &format!("indexes/{}", index))
+                .send()
+                .await?;
+            match self.call_wait_task(response, "delete index").await {
+                Ok(()) => info!("{} index was deleted.", index),
+                Err(e) => warn!("{}. {}", e, index),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MeilisearchOutput {
+    /// Sends the buffered documents and waits for the oldest request if more than
+    /// `concurrent_requests` are in flight.
+    async fn flush(&mut self) -> Result<(), LoaderError> {
+        let chunk = std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(self.config.buffer_size),
+        );
+        let chunk_len = chunk.len();
+        let task = MeilisearchOutput::proceed_chunk(
+            self.client.clone(),
+            self.config.clone(),
+            chunk,
+            self.sync,
+        );
+        self.in_flight.push_back((chunk_len, tokio::spawn(task)));
+        while self.in_flight.len() > self.config.concurrent_requests {
+            self.wait_oldest().await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_oldest(&mut self) -> Result<(), LoaderError> {
+        if let Some((chunk_len, task)) = self.in_flight.pop_front() {
+            let rejected = task
+                .await
+                .map_err(|e| LoaderError::Transport(format!("bulk task failed. {}", e)))??;
+            self.acknowledged += chunk_len;
+            self.rejected += rejected.len();
+            if let Some(dead_letter) = &self.dead_letter {
+                for entry in &rejected {
+                    dead_letter.write(entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        MeilisearchOutput::request_to(&self.client, &self.config, method, path)
+    }
+
+    fn request_to(
+        client: &Client,
+        config: &MeilisearchConfig,
+        method: Method,
+        path: &str,
+    ) -> RequestBuilder {
+        let url = format!("{}/{}", config.url.trim_end_matches('/'), path);
+        let request = client.request(method, url.as_str());
+        match &config.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+
+    async fn call_exist_index(&self, index_name: &str) -> Result<bool, LoaderError> {
+        let response = self
+            .request(Method::GET, &format!("indexes/{}", index_name))
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => check_status(response, "get index").await.map(|_| true),
+        }
+    }
+
+    /// Creates an index with `id` as the primary key.
+    async fn call_indices_create(&self, index_name: &str) -> Result<(), LoaderError> {
+        let response = self
+            .request(Method::POST, "indexes")
+            .json(&json!({"uid": index_name, "primaryKey": PRIMARY_KEY}))
+            .send()
+            .await?;
+        self.call_wait_task(response, "create index").await?;
+        info!("{} index was created.", index_name);
+        Ok(())
+    }
+
+    /// Applies the settings in the config to the index.
+    async fn call_update_settings(&self, index_name: &str) -> Result<(), LoaderError> {
+        let config = &self.config.settings;
+        let mut body = serde_json::Map::new();
+        for (key, attributes) in [
+            ("searchableAttributes", &config.searchable_attributes),
+            ("filterableAttributes", &config.filterable_attributes),
+            ("sortableAttributes", &config.sortable_attributes),
+        ] {
+            if let Some(attributes) = attributes {
+                body.insert(key.to_string(), json!(attributes));
+            }
+        }
+        if !body.is_empty() {
+            let response = self
+                .request(Method::PATCH, &format!("indexes/{}/settings", index_name))
+                .json(&body)
+                .send()
+                .await?;
+            self.call_wait_task(response, "update settings").await?;
+            info!("{} index settings were updated.", index_name);
+        }
+        Ok(())
+    }
+
+    /// Waits for the task of an administrative request and fails if the task fails.
+    async fn call_wait_task(&self, response: Response, request: &str) -> Result<(), LoaderError> {
+        let task_uid = task_uid(&check_status(response, request).await?, request)?;
+        let task = MeilisearchOutput::wait_task(&self.client, &self.config, task_uid).await?;
+        match task["status"].as_str() {
+            Some("succeeded") => Ok(()),
+            status => Err(LoaderError::Transport(format!(
+                "{} task {} has {}. {}",
+                request,
+                task_uid,
+                status.unwrap_or_default(),
+                task["error"]["message"]
+            ))),
+        }
+    }
+
+    /// Polls the task until it succeeds, fails or is canceled. Returns the finished task.
+    async fn wait_task(
+        client: &Client,
+        config: &MeilisearchConfig,
+        task_uid: u64,
+    ) -> Result<Value, LoaderError> {
+        let started = Instant::now();
+        let timeout = Duration::from_secs(config.task_timeout_secs);
+        loop {
+            let response = MeilisearchOutput::request_to(
+                client,
+                config,
+                Method::GET,
+                &format!("tasks/{}", task_uid),
+            )
+            .send()
+            .await?;
+            let task = check_status(response, "get task").await?;
+            match task["status"].as_str() {
+                Some("enqueued") | Some("processing") => {}
+                _ => return Ok(task),
+            }
+            if started.elapsed() > timeout {
+                return Err(LoaderError::Transport(format!(
+                    "task {} did not finish in {} seconds",
+                    task_uid, config.task_timeout_secs
+                )));
+            }
+            tokio::time::delay_for(Duration::from_millis(config.task_poll_interval_ms)).await;
+        }
+    }
+
+    /// Lists the ids of the indexed documents that are not in `ids`.
+    async fn call_missing_ids(&self, ids: &HashSet<String>) -> Result<Vec<String>, LoaderError> {
+        let mut missing = vec![];
+        let limit = self.config.buffer_size;
+        let mut offset = 0;
+        loop {
+            let response = self
+                .request(
+                    Method::GET,
+                    &format!("indexes/{}/documents", self.config.index_name),
+                )
+                .query(&[
+                    ("fields", PRIMARY_KEY.to_string()),
+                    ("limit", limit.to_string()),
+                    ("offset", offset.to_string()),
+                ])
+                .send()
+                .await?;
+            let body = check_status(response, "get documents").await?;
+            let docs = body["results"].as_array().cloned().unwrap_or_default();
+            for doc in &docs {
+                if let Some(id) = doc[PRIMARY_KEY].as_str() {
+                    if !ids.contains(id) {
+                        missing.push(id.to_string());
+                    }
+                }
+            }
+            if docs.len() < limit {
+                break;
+            }
+            offset += limit;
+        }
+        Ok(missing)
+    }
+
+    /// Removes the documents whose revision is already indexed.
+    /// `id` must be in `filterable_attributes`.
+    async fn skip_unchanged(
+        client: &Client,
+        config: &MeilisearchConfig,
+        chunk: Vec<TransformedDocument>,
+    ) -> Result<Vec<TransformedDocument>, LoaderError> {
+        let ids: Vec<&str> = chunk.iter().map(|d| d.source.id.as_str()).collect();
+        let response = MeilisearchOutput::request_to(
+            client,
+            config,
+            Method::POST,
+            &format!("indexes/{}/documents/fetch", config.index_name),
+        )
+        .json(&json!({
+            "filter": format!("{} IN {}", PRIMARY_KEY, json!(ids)),
+            "fields": [PRIMARY_KEY, "revision_id"],
+            "limit": chunk.len(),
+        }))
+        .send()
+        .await?;
+        let body = check_status(response, "fetch documents").await?;
+        let indexed: HashMap<String, String> = body["results"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|doc| {
+                Some((
+                    doc[PRIMARY_KEY].as_str()?.to_string(),
+                    doc["revision_id"].as_str()?.to_string(),
+                ))
+            })
+            .collect();
+        let chunk_len = chunk.len();
+        let changed: Vec<TransformedDocument> = chunk
+            .into_iter()
+            .filter(|d| match indexed.get(&d.source.id) {
+                Some(revision_id) => d.source.is_newer_than(revision_id),
+                None => true,
+            })
+            .collect();
+        if changed.len() < chunk_len {
+            info!("Skipped {} unchanged documents.", chunk_len - changed.len());
+        }
+        Ok(changed)
+    }
+
+    async fn proceed_chunk(
+        client: Client,
+        config: Arc<MeilisearchConfig>,
+        chunk: Vec<TransformedDocument>,
+        sync: bool,
+    ) -> Result<Vec<DeadLetter>, LoaderError> {
+        let mut chunk = chunk;
+        if sync {
+            chunk = MeilisearchOutput::skip_unchanged(&client, &config, chunk).await?;
+        }
+        let (batches, mut rejected) = split_by_bytes(
+            chunk,
+            config.max_batch_bytes,
+            // separated by ","
+            |d| serde_json::to_string(&d.fields).unwrap().len() + 1,
+            |d| &d.source,
+        );
+        for batch in batches {
+            let doc_id = batch[0].source.id.clone();
+            let task_uid = MeilisearchOutput::send_batch(&client, &config, &batch).await?;
+            let task = MeilisearchOutput::wait_task(&client, &config, task_uid).await?;
+            if task["status"] == "succeeded" {
+                info!("Finished bulk request. {}", doc_id);
+                continue;
+            }
+            // Meilisearch fails the whole task, so all the documents of the task are rejected
+            let error_type = task["error"]["code"].as_str().unwrap_or("task_canceled");
+            let reason = task["error"]["message"].as_str().unwrap_or_default();
+            warn!(
+                "Bulk Request has some errors. task:[{}], type:[{}], reason:[{}]. First doc id is [{}]",
+                task_uid, error_type, reason, doc_id
+            );
+            for d in batch {
+                rejected.push(DeadLetter::Rejected {
+                    id: d.source.id.clone(),
+                    error_type: error_type.to_string(),
+                    reason: reason.to_string(),
+                    document: serde_json::to_value(&d.source).unwrap(),
+                });
+            }
+        }
+        Ok(rejected)
+    }
+
+    /// Adds the documents to the index. Returns the uid of the enqueued task.
+    async fn send_batch(
+        client: &Client,
+        config: &MeilisearchConfig,
+        docs: &[TransformedDocument],
+    ) -> Result<u64, LoaderError> {
+        let doc_id = docs[0].source.id.as_str();
+        let fields: Vec<_> = docs.iter().map(|d| &d.fields).collect();
+        let body = serde_json::to_string(&fields).unwrap();
+        // retries of the whole request
+        let mut attempt = 0;
+        loop {
+            info!("Sending {} documents... {}", docs.len(), doc_id);
+            let result = MeilisearchOutput::request_to(
+                client,
+                config,
+                Method::POST,
+                &format!("indexes/{}/documents", config.index_name),
+            )
+            .query(&[("primaryKey", PRIMARY_KEY)])
+            .header("Content-Type", "application/json")
+            .body(body.clone())
+            .send()
+            .await;
+            let response = match result {
+                Ok(response) => response,
+                Err(err) => {
+                    warn!(
+                        "Bulk request has failed. {}. First doc id is [{}]",
+                        err, doc_id
+                    );
+                    if attempt < config.retry.max_retries {
+                        attempt += 1;
+                        config.retry.wait(attempt).await;
+                        continue;
+                    }
+                    return Err(err.into());
+                }
+            };
+            let status = response.status();
+            info!("response : {}", status);
+            if status.is_success() {
+                let task = response.json::<Value>().await?;
+                return task_uid(&task, "bulk");
+            }
+            warn!(
+                "Bulk request has failed. Status Code is {:?}. First doc id is [{}]",
+                status, doc_id
+            );
+            if is_retryable_status(status.as_u16()) && attempt < config.retry.max_retries {
+                attempt += 1;
+                config.retry.wait(attempt).await;
+                continue;
+            }
+            return Err(LoaderError::Transport(format!(
+                "bulk request failed. Status Code is {:?}. First doc id is [{}]",
+                status, doc_id
+            )));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::test_support::{block_on, document, TestServer};
+
+    fn output(url: &str, settings: &str) -> MeilisearchOutput {
+        let config: MeilisearchConfig = serde_yaml::from_str(&format!(
+            "url: {}\nbuffer_size: 10\nindex_name: wiki\ntask_poll_interval_ms: 1\nretry:\n  initial_backoff_ms: 1\n  max_backoff_ms: 1\n{}",
+            url, settings
+        ))
+        .unwrap();
+        MeilisearchOutput {
+            client: Client::new(),
+            buffer: vec![],
+            config: Arc::new(config),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+        }
+    }
+
+    fn load(output: &mut MeilisearchOutput, ids: &[&str]) -> Result<(), LoaderError> {
+        block_on(async {
+            for id in ids {
+                output.add_document(document(id, "1")).await?;
+            }
+            output.close().await
+        })
+    }
+
+    // enqueues every request as task 1 and answers the task with `task`
+    fn task_server(task: Value) -> TestServer {
+        TestServer::start(move |request| {
+            if request.path.starts_with("/tasks/") {
+                (200, task.to_string())
+            } else {
+                (202, json!({"taskUid": 1, "status": "enqueued"}).to_string())
+            }
+        })
+    }
+
+    #[test]
+    fn add_documents_in_succeeded_task() {
+        let server = task_server(json!({"uid": 1, "status": "succeeded"}));
+        let mut output = output(&server.url, "");
+        load(&mut output, &["1", "2"]).unwrap();
+        assert_eq!(output.acknowledged(), 2);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/indexes/wiki/documents?primaryKey=id");
+        let docs: Vec<Value> = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(docs[0]["id"], "1");
+        assert_eq!(docs[1]["id"], "2");
+        assert_eq!(requests[1].path, "/tasks/1");
+    }
+
+    #[test]
+    fn reject_documents_of_failed_task() {
+        let server = task_server(json!({
+            "uid": 1,
+            "status": "failed",
+            "error": {"code": "invalid_document_fields", "message": "invalid"}
+        }));
+        let mut output = output(&server.url, "");
+        match load(&mut output, &["1", "2"]) {
+            Err(LoaderError::Rejected { count }) => assert_eq!(count, 2),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(output.acknowledged(), 2);
+    }
+
+    #[test]
+    fn fail_on_task_timeout() {
+        let server = task_server(json!({"uid": 1, "status": "processing"}));
+        let mut output = output(&server.url, "task_timeout_secs: 0\n");
+        match load(&mut output, &["1"]) {
+            Err(LoaderError::Transport(message)) => {
+                assert!(message.contains("task 1 did not finish in 0 seconds"))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn apply_settings_to_existing_index() {
+        let server = task_server(json!({"uid": 1, "status": "succeeded"}));
+        let output = output(
+            &server.url,
+            "settings:\n  filterable_attributes: [\"id\"]\n",
+        );
+        block_on(output.initialize()).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(requests[0].path, "/indexes/wiki");
+        assert_eq!(requests[1].method, "PATCH");
+        assert_eq!(requests[1].path, "/indexes/wiki/settings");
+        let settings: Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(settings, json!({"filterableAttributes": ["id"]}));
+        assert_eq!(requests.len(), 3);
+    }
+
+    #[test]
+    fn read_task_uid() {
+        let body = json!({"taskUid": 12, "status": "enqueued"});
+        assert_eq!(task_uid(&body, "bulk").unwrap(), 12);
+    }
+
+    #[test]
+    fn fail_without_task_uid() {
+        assert!(task_uid(&json!({"status": "enqueued"}), "bulk").is_err());
+        assert!(task_uid(&json!({"taskUid": "12"}), "bulk").is_err());
+        assert!(task_uid(&Value::Null, "bulk").is_err());
+    }
+}
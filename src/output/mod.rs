@@ -1,2 +1,15 @@
 pub mod azure_search_output;
+pub mod batch;
+pub mod bulk;
+pub mod cjk_tokenizer;
+pub mod connection_pool;
 pub mod elasticsearch_output;
+pub mod meilisearch_output;
+pub mod opensearch_output;
+pub mod rebuild;
+pub mod retry;
+pub mod solr_output;
+pub mod tantivy_output;
+#[cfg(test)]
+pub mod test_support;
+pub mod typesense_output;
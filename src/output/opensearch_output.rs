@@ -0,0 +1,576 @@
+use crate::error::LoaderError;
+use crate::loader::dead_letter::DeadLetterWriter;
+use crate::loader::transform::TransformedDocument;
+use crate::output::batch::default_max_batch_bytes;
+use crate::output::bulk::{
+    check_alias_name, delete_documents, proceed_chunk, scroll_missing_ids, switch_alias,
+    ApiResponse, RestApi,
+};
+use crate::output::elasticsearch_output::SearchEngine;
+use crate::output::elasticsearch_output::{
+    default_concurrent_requests, default_verify_certificate, load_schema, read_config, BulkTask,
+};
+use crate::output::rebuild::{generation_name, RebuildConfig};
+use crate::output::retry::RetryConfig;
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
+use serde_json::Value;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpenSearchConfig {
+    url: String,
+    /// Basic authentication of the security plugin.
+    username: Option<String>,
+    password: Option<String>,
+    /// PEM file of the CA certificate that signed the certificate of the cluster.
+    ca_cert: Option<String>,
+    /// Set false to skip the certificate validation, only for test clusters.
+    #[serde(default = "default_verify_certificate")]
+    verify_certificate: bool,
+    buffer_size: usize,
+    index_name: String,
+    schema_file: String,
+    #[serde(default = "default_concurrent_requests")]
+    concurrent_requests: usize,
+    /// Maximum size of a bulk request body. Bulk requests are split to fit in it.
+    #[serde(default = "default_max_batch_bytes")]
+    max_batch_bytes: usize,
+    #[serde(default)]
+    retry: RetryConfig,
+    #[serde(default)]
+    rebuild: RebuildConfig,
+}
+
+/// Output to OpenSearch with its REST API.
+pub struct OpenSearchOutput {
+    client: Client,
+    buffer: Vec<TransformedDocument>,
+    config: Arc<OpenSearchConfig>,
+    in_flight: VecDeque<(usize, BulkTask)>,
+    acknowledged: usize,
+    rejected: usize,
+    dead_letter: Option<Arc<DeadLetterWriter>>,
+    sync: bool,
+    config_file: String,
+}
+
+fn load_config(config_file: &str) -> Result<OpenSearchConfig, LoaderError> {
+    read_config(config_file)
+}
+
+fn build_client(config: &OpenSearchConfig, config_file: &str) -> Result<Client, LoaderError> {
+    let config_error = |message: String| LoaderError::Config {
+        path: config_file.to_string(),
+        message,
+    };
+    let mut builder = Client::builder();
+    if !config.verify_certificate {
+        warn!("The certificate of the cluster is not verified.");
+        builder = builder.danger_accept_invalid_certs(true);
+    } else if let Some(ca_cert) = &config.ca_cert {
+        let pem = std::fs::read(ca_cert)
+            .map_err(|e| config_error(format!("cannot read ca_cert {}. {}", ca_cert, e)))?;
+        let certificate = reqwest::Certificate::from_pem(&pem)
+            .map_err(|e| config_error(format!("invalid ca_cert {}. {}", ca_cert, e)))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    Ok(builder.build()?)
+}
+
+#[async_trait]
+impl SearchEngine for OpenSearchOutput {
+    fn new(_config_file: &str) -> Result<Self, LoaderError>
+    where
+        Self: Sized,
+    {
+        let config = load_config(_config_file)?;
+        debug!("url: {}", config.url);
+        debug!("buffer_size: {}", config.buffer_size);
+        let client = build_client(&config, _config_file)?;
+        let buffer = Vec::with_capacity(config.buffer_size);
+        Ok(OpenSearchOutput {
+            client,
+            buffer,
+            config: Arc::new(config),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+            config_file: _config_file.to_string(),
+        })
+    }
+
+    fn set_dead_letter(&mut self, dead_letter: Arc<DeadLetterWriter>) {
+        self.dead_letter = Some(dead_letter);
+    }
+
+    fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    async fn add_document(&mut self, _document: TransformedDocument) -> Result<(), LoaderError> {
+        self.buffer.push(_document);
+        if self.buffer.len() >= self.config.buffer_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    fn acknowledged(&self) -> usize {
+        self.acknowledged
+    }
+
+    async fn initialize(&self) -> Result<(), LoaderError> {
+        if self.exist_index().await? {
+            info!(
+                "{} index already exists. skip initialization phase.",
+                &self.config.index_name
+            );
+        } else {
+            info!("{} index is creating...", &self.config.index_name);
+            self.call_indices_create(&self.config.index_name).await?;
+        }
+        Ok(())
+    }
+
+    async fn exist_index(&self) -> Result<bool, LoaderError> {
+        let response = self
+            .request(Method::HEAD, &self.config.index_name)
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            StatusCode::OK => Ok(true),
+            status => {
+                warn!(
+                    "Indices exists request has failed. Status Code is {:?}.",
+                    status
+                );
+                Err(LoaderError::Transport(format!(
+                    "indices exists request failed. Status Code is {:?}.",
+                    status
+                )))
+            }
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), LoaderError> {
+        if !self.buffer.is_empty() {
+            self.flush().await?;
+        }
+        while !self.in_flight.is_empty() {
+            self.wait_oldest().await?;
+        }
+        match self.rejected {
+            0 => Ok(()),
+            count => Err(LoaderError::Rejected { count }),
+        }
+    }
+
+    async fn finalize(&self) -> Result<(), LoaderError> {
+        Ok(())
+    }
+
+    async fn delete_missing(&self, ids: &HashSet<String>) -> Result<usize, LoaderError> {
+        let missing = scroll_missing_ids(
+            &self.api(),
+            &self.config.index_name,
+            self.config.buffer_size,
+            ids,
+        )
+        .await?;
+        delete_documents(
+            &self.api(),
+            &self.config.index_name,
+            &missing,
+            self.config.buffer_size,
+        )
+        .await?;
+        Ok(missing.len())
+    }
+
+    fn set_index(&mut self, index_name: &str) {
+        Arc::make_mut(&mut self.config).index_name = index_name.to_string();
+    }
+
+    async fn create_generation(&self) -> Result<String, LoaderError> {
+        check_alias_name(&self.api(), &self.config.index_name, &self.config_file).await?;
+        let generation = generation_name(&self.config.index_name, '_');
+        info!("{} index is creating...", generation);
+        self.call_indices_create(&generation).await?;
+        Ok(generation)
+    }
+
+    async fn switch_generation(&self, generation: &str, loaded: usize) -> Result<(), LoaderError> {
+        switch_alias(
+            &self.api(),
+            &self.config.index_name,
+            generation,
+            loaded,
+            &self.config.rebuild,
+        )
+        .await
+    }
+}
+
+/// The REST API of an OpenSearch cluster.
+struct OpenSearchClient {
+    client: Client,
+    config: Arc<OpenSearchConfig>,
+}
+
+#[async_trait]
+impl RestApi for OpenSearchClient {
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<ApiResponse, LoaderError> {
+        let mut request = OpenSearchOutput::request_to(&self.client, &self.config, method, path);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        ApiResponse::new(status, &response.text().await?)
+    }
+
+    async fn bulk(&self, index_name: &str, body: String) -> Result<ApiResponse, LoaderError> {
+        let response = OpenSearchOutput::request_to(
+            &self.client,
+            &self.config,
+            Method::POST,
+            &format!("{}/_bulk", index_name),
+        )
+        .header("Content-Type", "application/x-ndjson")
+        .body(body)
+        .send()
+        .await?;
+        let status = response.status();
+        ApiResponse::new(status, &response.text().await?)
+    }
+}
+
+impl OpenSearchOutput {
+    /// Sends the buffered documents as one bulk request and waits for the oldest
+    /// request if more than `concurrent_requests` are in flight.
+    async fn flush(&mut self) -> Result<(), LoaderError> {
+        let chunk = std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(self.config.buffer_size),
+        );
+        let chunk_len = chunk.len();
+        let task = proceed_chunk(
+            self.api(),
+            self.config.index_name.clone(),
+            self.config.max_batch_bytes,
+            self.config.retry.clone(),
+            chunk,
+            self.sync,
+        );
+        self.in_flight.push_back((chunk_len, tokio::spawn(task)));
+        while self.in_flight.len() > self.config.concurrent_requests {
+            self.wait_oldest().await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_oldest(&mut self) -> Result<(), LoaderError> {
+        if let Some((chunk_len, task)) = self.in_flight.pop_front() {
+            let rejected = task
+                .await
+                .map_err(|e| LoaderError::Transport(format!("bulk task failed. {}", e)))??;
+            self.acknowledged += chunk_len;
+            self.rejected += rejected.len();
+            if let Some(dead_letter) = &self.dead_letter {
+                for entry in &rejected {
+                    dead_letter.write(entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn api(&self) -> OpenSearchClient {
+        OpenSearchClient {
+            client: self.client.clone(),
+            config: self.config.clone(),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        OpenSearchOutput::request_to(&self.client, &self.config, method, path)
+    }
+
+    fn request_to(
+        client: &Client,
+        config: &OpenSearchConfig,
+        method: Method,
+        path: &str,
+    ) -> RequestBuilder {
+        let url = format!("{}/{}", config.url.trim_end_matches('/'), path);
+        let request = client.request(method, url.as_str());
+        match &config.username {
+            Some(username) => request.basic_auth(username, config.password.as_ref()),
+            None => request,
+        }
+    }
+
+    async fn call_indices_create(&self, index_name: &str) -> Result<(), LoaderError> {
+        let schema_json = load_schema(&self.config.schema_file)?;
+        let response = self
+            .request(Method::PUT, index_name)
+            .json(&schema_json)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            warn!(
+                "Create index request has failed. Status Code is {:?}.",
+                status
+            );
+            warn!("{:?}", response.text().await?);
+            return Err(LoaderError::Transport(format!(
+                "create index failed. Status Code is {:?}.",
+                status
+            )));
+        }
+        info!("{} index was created.", index_name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::rebuild::is_generation;
+    use crate::output::test_support::{block_on, document, TestServer};
+    use serde_json::json;
+
+    fn output(url: &str) -> OpenSearchOutput {
+        let config: OpenSearchConfig = serde_yaml::from_str(&format!(
+            "url: {}\nbuffer_size: 10\nindex_name: wiki\nschema_file: schema.json\nretry:\n  initial_backoff_ms: 1\n  max_backoff_ms: 1\n",
+            url
+        ))
+        .unwrap();
+        OpenSearchOutput {
+            client: Client::new(),
+            buffer: vec![],
+            config: Arc::new(config),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+            config_file: String::from("opensearch.yaml"),
+        }
+    }
+
+    fn load(output: &mut OpenSearchOutput, ids: &[&str]) -> Result<(), LoaderError> {
+        block_on(async {
+            for id in ids {
+                output.add_document(document(id, "1")).await?;
+            }
+            output.close().await
+        })
+    }
+
+    fn index_item(id: &str, status: u16) -> Value {
+        json!({"index": {"_id": id, "status": status}})
+    }
+
+    fn error_item(id: &str, status: u16, error_type: &str) -> Value {
+        json!({"index": {
+            "_id": id,
+            "status": status,
+            "error": {"type": error_type, "reason": "test"}
+        }})
+    }
+
+    #[test]
+    fn bulk_success() {
+        let server = TestServer::start(|_| {
+            let body =
+                json!({"errors": false, "items": [index_item("1", 201), index_item("2", 201)]});
+            (200, body.to_string())
+        });
+        let mut output = output(&server.url);
+        load(&mut output, &["1", "2"]).unwrap();
+        assert_eq!(output.acknowledged(), 2);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/wiki/_bulk");
+        let lines: Vec<Value> = requests[0]
+            .body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], json!({"index": {"_id": "1"}}));
+        assert_eq!(lines[1]["title"], "Title 1");
+        assert_eq!(lines[2], json!({"index": {"_id": "2"}}));
+    }
+
+    #[test]
+    fn partial_rejection() {
+        let server = TestServer::start(|_| {
+            let body = json!({"errors": true, "items": [
+                index_item("1", 201),
+                error_item("2", 400, "mapper_parsing_exception")
+            ]});
+            (200, body.to_string())
+        });
+        let mut output = output(&server.url);
+        match load(&mut output, &["1", "2"]) {
+            Err(LoaderError::Rejected { count }) => assert_eq!(count, 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(output.acknowledged(), 2);
+        // rejected documents are not sent again
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn retry_busy_request_and_documents() {
+        let mut count = 0;
+        let server = TestServer::start(move |_| {
+            count += 1;
+            match count {
+                1 => (429, json!({"error": "too many requests"}).to_string()),
+                2 => {
+                    let body = json!({"errors": true, "items": [
+                        index_item("1", 201),
+                        error_item("2", 429, "es_rejected_execution_exception")
+                    ]});
+                    (200, body.to_string())
+                }
+                _ => {
+                    let body = json!({"errors": false, "items": [index_item("2", 201)]});
+                    (200, body.to_string())
+                }
+            }
+        });
+        let mut output = output(&server.url);
+        load(&mut output, &["1", "2"]).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].body, requests[1].body);
+        // only the document that failed with a busy status is sent again
+        assert_eq!(requests[2].body.lines().count(), 2);
+        assert!(requests[2].body.contains(r#""_id":"2""#));
+    }
+
+    #[test]
+    fn switch_alias_to_new_generation() {
+        let server = TestServer::start(|request| {
+            let body = match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/wiki_20200701000000/_count") => json!({"count": 2}),
+                ("GET", "/_alias/wiki") => {
+                    json!({"wiki_20200601000000": {"aliases": {"wiki": {}}}})
+                }
+                ("GET", "/wiki_*") => json!({
+                    "wiki_20200501000000": {},
+                    "wiki_20200601000000": {},
+                    "wiki_20200701000000": {},
+                    "wiki_backup": {}
+                }),
+                _ => json!({"acknowledged": true}),
+            };
+            (200, body.to_string())
+        });
+        let output = output(&server.url);
+        block_on(output.switch_generation("wiki_20200701000000", 2)).unwrap();
+        let requests = server.requests();
+        let aliases = requests
+            .iter()
+            .find(|request| request.path == "/_aliases")
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&aliases.body).unwrap(),
+            json!({"actions": [
+                {"remove": {"index": "wiki_20200601000000", "alias": "wiki"}},
+                {"add": {"index": "wiki_20200701000000", "alias": "wiki"}}
+            ]})
+        );
+        // the previous generation is kept
+        let deleted: Vec<&str> = requests
+            .iter()
+            .filter(|request| request.method == "DELETE")
+            .map(|request| request.path.as_str())
+            .collect();
+        assert_eq!(deleted, vec!["/wiki_20200501000000"]);
+    }
+
+    #[test]
+    fn fail_rebuild_of_index_before_loading() {
+        let server =
+            TestServer::start(
+                |request| match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/_alias/wiki") => {
+                        (404, json!({"error": "alias [wiki] missing"}).to_string())
+                    }
+                    ("HEAD", "/wiki") => (200, String::new()),
+                    _ => (200, json!({"acknowledged": true}).to_string()),
+                },
+            );
+        let output = output(&server.url);
+        match block_on(output.create_generation()) {
+            Err(LoaderError::Config { message, .. }) => {
+                assert!(message.contains("wiki is an index"))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(server
+            .requests()
+            .iter()
+            .all(|request| request.method != "PUT"));
+    }
+
+    #[test]
+    fn create_generation_for_alias() {
+        let server =
+            TestServer::start(
+                |request| match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/_alias/wiki") => (
+                        200,
+                        json!({"wiki_20200601000000": {"aliases": {"wiki": {}}}}).to_string(),
+                    ),
+                    _ => (200, json!({"acknowledged": true}).to_string()),
+                },
+            );
+        let mut output = output(&server.url);
+        Arc::make_mut(&mut output.config).schema_file =
+            String::from("sample/elasticsearch/index_schema.json");
+        let generation = block_on(output.create_generation()).unwrap();
+        assert!(is_generation(&generation, "wiki", '_'));
+        let requests = server.requests();
+        assert_eq!(requests.last().unwrap().method, "PUT");
+        assert_eq!(requests.last().unwrap().path, format!("/{}", generation));
+    }
+
+    #[test]
+    fn keep_alias_if_generation_is_short() {
+        let server = TestServer::start(|request| {
+            let body = match request.path.as_str() {
+                "/wiki_20200701000000/_count" => json!({"count": 1}),
+                _ => json!({"acknowledged": true}),
+            };
+            (200, body.to_string())
+        });
+        let output = output(&server.url);
+        match block_on(output.switch_generation("wiki_20200701000000", 2)) {
+            Err(LoaderError::CountMismatch { count, .. }) => assert_eq!(count, 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(server
+            .requests()
+            .iter()
+            .all(|request| request.path != "/_aliases"));
+    }
+}
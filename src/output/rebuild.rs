@@ -0,0 +1,173 @@
+use crate::error::LoaderError;
+use chrono::{NaiveDateTime, Utc};
+
+const GENERATION_FORMAT: &str = "%Y%m%d%H%M%S";
+// length of the timestamps in GENERATION_FORMAT
+const GENERATION_LENGTH: usize = 14;
+
+/// Settings of the rebuild mode. Put under `rebuild:` in the config yaml.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RebuildConfig {
+    /// Number of old generations kept after switching. Older generations are deleted.
+    pub keep_generations: usize,
+    /// Ratio of the loaded documents that the new generation must have before switching.
+    /// Duplicated ids in the input make the index smaller than the number of loaded documents.
+    pub min_doc_ratio: f64,
+}
+
+impl Default for RebuildConfig {
+    fn default() -> Self {
+        RebuildConfig {
+            keep_generations: 1,
+            min_doc_ratio: 1.0,
+        }
+    }
+}
+
+impl RebuildConfig {
+    /// Fails if the new generation doesn't have enough documents to replace the current one.
+    pub fn check_count(
+        &self,
+        generation: &str,
+        count: usize,
+        expected: usize,
+    ) -> Result<(), LoaderError> {
+        if count == 0 || (count as f64) < expected as f64 * self.min_doc_ratio {
+            return Err(LoaderError::CountMismatch {
+                index: generation.to_string(),
+                count,
+                expected,
+            });
+        }
+        Ok(())
+    }
+
+    /// Generations to delete, except `current` and the `keep_generations` newest ones.
+    pub fn expired(&self, generations: Vec<String>, current: &str) -> Vec<String> {
+        let mut generations: Vec<String> = generations
+            .into_iter()
+            .filter(|generation| generation != current)
+            .collect();
+        // the timestamp suffix sorts in time order
+        generations.sort();
+        generations.reverse();
+        generations.split_off(self.keep_generations.min(generations.len()))
+    }
+}
+
+/// Name of a new generation of `index_name`, like `wiki_20200701123456`.
+pub fn generation_name(index_name: &str, separator: char) -> String {
+    format!(
+        "{}{}{}",
+        index_name,
+        separator,
+        Utc::now().format(GENERATION_FORMAT)
+    )
+}
+
+/// Whether `name` is a generation of `index_name` made by `generation_name`.
+pub fn is_generation(name: &str, index_name: &str, separator: char) -> bool {
+    name.strip_prefix(index_name)
+        .and_then(|suffix| suffix.strip_prefix(separator))
+        // chrono also accepts shorter fields like a single digit second
+        .filter(|suffix| suffix.len() == GENERATION_LENGTH)
+        .map(|suffix| NaiveDateTime::parse_from_str(suffix, GENERATION_FORMAT).is_ok())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn keep_newest_generations() {
+        let generations = names(&[
+            "wiki_20200401000000",
+            "wiki_20200601000000",
+            "wiki_20200501000000",
+            "wiki_20200701000000",
+        ]);
+        let config = RebuildConfig::default();
+        assert_eq!(
+            config.expired(generations.clone(), "wiki_20200701000000"),
+            names(&["wiki_20200501000000", "wiki_20200401000000"])
+        );
+        let config = RebuildConfig {
+            keep_generations: 0,
+            ..RebuildConfig::default()
+        };
+        assert_eq!(
+            config.expired(generations.clone(), "wiki_20200701000000"),
+            names(&[
+                "wiki_20200601000000",
+                "wiki_20200501000000",
+                "wiki_20200401000000"
+            ])
+        );
+        let config = RebuildConfig {
+            keep_generations: 5,
+            ..RebuildConfig::default()
+        };
+        assert!(config
+            .expired(generations, "wiki_20200701000000")
+            .is_empty());
+    }
+
+    #[test]
+    fn never_expire_current_generation() {
+        let config = RebuildConfig {
+            keep_generations: 0,
+            ..RebuildConfig::default()
+        };
+        assert!(config
+            .expired(names(&["wiki_20200701000000"]), "wiki_20200701000000")
+            .is_empty());
+    }
+
+    #[test]
+    fn check_count_at_min_doc_ratio() {
+        let config = RebuildConfig {
+            min_doc_ratio: 0.9,
+            ..RebuildConfig::default()
+        };
+        assert!(config.check_count("wiki", 90, 100).is_ok());
+        assert!(config.check_count("wiki", 100, 100).is_ok());
+        match config.check_count("wiki", 89, 100) {
+            Err(LoaderError::CountMismatch {
+                count, expected, ..
+            }) => assert_eq!((count, expected), (89, 100)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let config = RebuildConfig::default();
+        assert!(config.check_count("wiki", 100, 100).is_ok());
+        assert!(config.check_count("wiki", 99, 100).is_err());
+    }
+
+    #[test]
+    fn fail_empty_generation() {
+        let config = RebuildConfig {
+            min_doc_ratio: 0.0,
+            ..RebuildConfig::default()
+        };
+        assert!(config.check_count("wiki", 0, 0).is_err());
+        assert!(config.check_count("wiki", 1, 100).is_ok());
+    }
+
+    #[test]
+    fn match_generation_names() {
+        let generation = generation_name("wiki", '_');
+        assert!(is_generation(&generation, "wiki", '_'));
+        assert!(!is_generation(&generation, "wiki", '-'));
+        assert!(is_generation("wiki-20200701123456", "wiki", '-'));
+        assert!(!is_generation("wiki_backup", "wiki", '_'));
+        assert!(!is_generation("wiki_20201301000000", "wiki", '_'));
+        assert!(!is_generation("wiki_2020070112345", "wiki", '_'));
+        assert!(!is_generation("wiki_en_20200701123456", "wiki", '_'));
+        assert!(!is_generation("wiki", "wiki", '_'));
+    }
+}
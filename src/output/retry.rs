@@ -0,0 +1,114 @@
+use log::info;
+use std::time::Duration;
+
+/// Retry settings for bulk requests. Put under `retry:` in the config yaml.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Number of retries when a whole request fails.
+    pub max_retries: usize,
+    /// Number of retries of a document that failed in a partially successful request.
+    pub max_document_retries: usize,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Ratio of the backoff that is randomized, between 0.0 and 1.0.
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 5,
+            max_document_retries: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Backoff before the `attempt`-th retry. `attempt` starts from 1.
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(32) as u32;
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(2u64.saturating_pow(exponent))
+            .min(self.max_backoff_ms);
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+        Duration::from_millis((backoff as f64 * (1.0 - jitter)) as u64)
+    }
+
+    pub async fn wait(&self, attempt: usize) {
+        let backoff = self.backoff(attempt);
+        info!("Retry #{} after {} ms...", attempt, backoff.as_millis());
+        tokio::time::delay_for(backoff).await;
+    }
+}
+
+/// Status codes that mean the search engine is busy or temporarily unavailable.
+pub fn is_retryable_status(status_code: u16) -> bool {
+    matches!(status_code, 429 | 502 | 503 | 504)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn without_jitter() -> RetryConfig {
+        RetryConfig {
+            jitter: 0.0,
+            ..RetryConfig::default()
+        }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially() {
+        let config = without_jitter();
+        let backoffs: Vec<u128> = (1..=6)
+            .map(|attempt| config.backoff(attempt).as_millis())
+            .collect();
+        assert_eq!(backoffs, vec![500, 1000, 2000, 4000, 8000, 16000]);
+        // attempt 0 is the same as the first retry
+        assert_eq!(config.backoff(0).as_millis(), 500);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let config = without_jitter();
+        assert_eq!(config.backoff(7).as_millis(), 30_000);
+        assert_eq!(config.backoff(100).as_millis(), 30_000);
+        assert_eq!(config.backoff(usize::MAX).as_millis(), 30_000);
+    }
+
+    #[test]
+    fn jitter_within_bounds() {
+        let config = RetryConfig::default();
+        for attempt in 1..=10 {
+            let max = without_jitter().backoff(attempt);
+            for _ in 0..100 {
+                let backoff = config.backoff(attempt);
+                assert!(backoff <= max);
+                assert!(backoff >= max / 2);
+            }
+        }
+        // jitter is clamped to 1.0
+        let config = RetryConfig {
+            jitter: 2.0,
+            ..RetryConfig::default()
+        };
+        for _ in 0..100 {
+            assert!(config.backoff(1).as_millis() <= 500);
+        }
+    }
+
+    #[test]
+    fn retryable_status() {
+        for status_code in [429, 502, 503, 504] {
+            assert!(is_retryable_status(status_code));
+        }
+        for status_code in [200, 201, 400, 401, 404, 409, 413, 500, 501] {
+            assert!(!is_retryable_status(status_code));
+        }
+    }
+}
@@ -0,0 +1,934 @@
+use crate::error::LoaderError;
+use crate::loader::dead_letter::{DeadLetter, DeadLetterWriter};
+use crate::loader::transform::TransformedDocument;
+use crate::output::azure_search_output::id_separator;
+use crate::output::batch::{default_max_batch_bytes, split_by_bytes};
+use crate::output::elasticsearch_output::SearchEngine;
+use crate::output::elasticsearch_output::{
+    default_concurrent_requests, load_schema, read_config, BulkTask,
+};
+use crate::output::rebuild::{generation_name, is_generation, RebuildConfig};
+use crate::output::retry::{is_retryable_status, RetryConfig};
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use reqwest::{Client, Method, RequestBuilder, Response};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SolrConfig {
+    /// Base url of Solr, like `http://localhost:8983/solr`.
+    url: String,
+    collection: String,
+    /// Commands of the Schema API, sent after the collection is created.
+    schema_file: String,
+    /// SolrCloud uses the Collections API. Set false to create a core of a standalone Solr.
+    #[serde(default = "default_cloud")]
+    cloud: bool,
+    #[serde(default = "default_config_set")]
+    config_set: String,
+    #[serde(default = "default_one")]
+    num_shards: usize,
+    #[serde(default = "default_one")]
+    replication_factor: usize,
+    /// Basic authentication.
+    username: Option<String>,
+    password: Option<String>,
+    buffer_size: usize,
+    #[serde(default = "default_concurrent_requests")]
+    concurrent_requests: usize,
+    /// Maximum size of an update request body. Requests are split to fit in it.
+    #[serde(default = "default_max_batch_bytes")]
+    max_batch_bytes: usize,
+    #[serde(default)]
+    retry: RetryConfig,
+    #[serde(default)]
+    rebuild: RebuildConfig,
+}
+
+fn default_cloud() -> bool {
+    true
+}
+
+fn default_config_set() -> String {
+    String::from("_default")
+}
+
+fn default_one() -> usize {
+    1
+}
+
+/// Error of an update request rejected by Solr.
+struct SolrError {
+    code: u16,
+    msg: String,
+}
+
+pub struct SolrOutput {
+    client: Client,
+    buffer: Vec<TransformedDocument>,
+    config: Arc<SolrConfig>,
+    config_file: String,
+    in_flight: VecDeque<(usize, BulkTask)>,
+    acknowledged: usize,
+    rejected: usize,
+    dead_letter: Option<Arc<DeadLetterWriter>>,
+    sync: bool,
+}
+
+fn load_config(config_file: &str) -> Result<SolrConfig, LoaderError> {
+    read_config(config_file)
+}
+
+/// Returns the body of a successful response.
+async fn check_status(response: Response, request: &str) -> Result<Value, LoaderError> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.json::<Value>().await.unwrap_or_default();
+        return Err(LoaderError::Transport(format!(
+            "{} request failed. Status Code is {:?}. {}",
+            request, status, body["error"]["msg"]
+        )));
+    }
+    Ok(response.json::<Value>().await?)
+}
+
+#[async_trait]
+impl SearchEngine for SolrOutput {
+    fn new(_config_file: &str) -> Result<Self, LoaderError>
+    where
+        Self: Sized,
+    {
+        let config = load_config(_config_file)?;
+        debug!("url: {}", config.url);
+        debug!("buffer_size: {}", config.buffer_size);
+        let buffer = Vec::with_capacity(config.buffer_size);
+        Ok(SolrOutput {
+            client: Client::new(),
+            buffer,
+            config: Arc::new(config),
+            config_file: _config_file.to_string(),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+        })
+    }
+
+    fn set_dead_letter(&mut self, dead_letter: Arc<DeadLetterWriter>) {
+        self.dead_letter = Some(dead_letter);
+    }
+
+    fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    async fn add_document(&mut self, _document: TransformedDocument) -> Result<(), LoaderError> {
+        self.buffer.push(_document);
+        if self.buffer.len() >= self.config.buffer_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    fn acknowledged(&self) -> usize {
+        self.acknowledged
+    }
+
+    async fn initialize(&self) -> Result<(), LoaderError> {
+        if self.exist_index().await? {
+            info!(
+                "{} collection already exists. skip initialization phase.",
+                &self.config.collection
+            );
+        } else {
+            info!("{} collection is creating...", &self.config.collection);
+            self.call_collection_create(&self.config.collection).await?;
+        }
+        Ok(())
+    }
+
+    /// In SolrCloud, the collection may be an alias made by a rebuild.
+    async fn exist_index(&self) -> Result<bool, LoaderError> {
+        let name = self.config.collection.as_str();
+        if self.config.cloud {
+            Ok(self
+                .call_list_collections()
+                .await?
+                .iter()
+                .any(|c| c == name)
+                || self.call_list_aliases().await?.contains_key(name))
+        } else {
+            let response = self
+                .request(Method::GET, "admin/cores")
+                .query(&[("action", "STATUS"), ("core", name)])
+                .send()
+                .await?;
+            let body = check_status(response, "core status").await?;
+            Ok(body["status"][name]["name"].is_string())
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), LoaderError> {
+        if !self.buffer.is_empty() {
+            self.flush().await?;
+        }
+        while !self.in_flight.is_empty() {
+            self.wait_oldest().await?;
+        }
+        self.call_commit(&self.config.collection).await?;
+        match self.rejected {
+            0 => Ok(()),
+            count => Err(LoaderError::Rejected { count }),
+        }
+    }
+
+    async fn finalize(&self) -> Result<(), LoaderError> {
+        Ok(())
+    }
+
+    async fn delete_missing(&self, ids: &HashSet<String>) -> Result<usize, LoaderError> {
+        let missing = self.call_cursor_missing_ids(ids).await?;
+        for chunk in missing.chunks(self.config.buffer_size) {
+            info!("Deleting {} documents... {}", chunk.len(), chunk[0]);
+            let response = self
+                .request(Method::POST, &format!("{}/update", self.config.collection))
+                .json(&json!({ "delete": chunk }))
+                .send()
+                .await?;
+            check_status(response, "delete").await?;
+        }
+        self.call_commit(&self.config.collection).await?;
+        Ok(missing.len())
+    }
+
+    fn set_index(&mut self, index_name: &str) {
+        Arc::make_mut(&mut self.config).collection = index_name.to_string();
+    }
+
+    async fn create_generation(&self) -> Result<String, LoaderError> {
+        if !self.config.cloud {
+            return Err(LoaderError::Config {
+                path: self.config_file.clone(),
+                message: String::from("rebuild needs the collection aliases of SolrCloud"),
+            });
+        }
+        let alias = self.config.collection.as_str();
+        // CREATEALIAS fails after loading if a collection has the name of the alias
+        if self
+            .call_list_collections()
+            .await?
+            .iter()
+            .any(|c| c == alias)
+        {
+            return Err(LoaderError::Config {
+                path: self.config_file.clone(),
+                message: format!(
+                    "{} is a collection, but --rebuild needs an alias of that name. Move the collection to an alias before rebuilding it",
+                    alias
+                ),
+            });
+        }
+        let generation = generation_name(alias, '_');
+        info!("{} collection is creating...", generation);
+        self.call_collection_create(&generation).await?;
+        Ok(generation)
+    }
+
+    async fn switch_generation(&self, generation: &str, loaded: usize) -> Result<(), LoaderError> {
+        self.call_commit(generation).await?;
+        let response = self
+            .request(Method::GET, &format!("{}/select", generation))
+            .query(&[("q", "*:*"), ("rows", "0")])
+            .send()
+            .await?;
+        let count = check_status(response, "count").await?["response"]["numFound"]
+            .as_u64()
+            .unwrap_or_default() as usize;
+        info!("{} collection has {} documents.", generation, count);
+        self.config.rebuild.check_count(generation, count, loaded)?;
+        // CREATEALIAS replaces the collections of an existing alias
+        let alias = self.config.collection.as_str();
+        let response = self
+            .request(Method::GET, "admin/collections")
+            .query(&[
+                ("action", "CREATEALIAS"),
+                ("name", alias),
+                ("collections", generation),
+            ])
+            .send()
+            .await?;
+        check_status(response, "create alias").await?;
+        info!("{} alias was switched to {}.", alias, generation);
+        let generations: Vec<String> = self
+            .call_list_collections()
+            .await?
+            .into_iter()
+            .filter(|name| is_generation(name, alias, '_'))
+            .collect();
+        for collection in self.config.rebuild.expired(generations, generation) {
+            let response = self
+                .request(Method::GET, "admin/collections")
+                .query(&[("action", "DELETE"), ("name", collection.as_str())])
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                warn!(
+                    "Delete collection request has failed. Status Code is {:?}. {}",
+                    response.status(),
+                    collection
+                );
+                continue;
+            }
+            info!("{} collection was deleted.", collection);
+            // the config set copied for the collection
+            let response = self
+                .request(Method::GET, "admin/configs")
+                .query(&[("action", "DELETE"), ("name", collection.as_str())])
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                warn!(
+                    "Delete config set request has failed. Status Code is {:?}. {}",
+                    response.status(),
+                    collection
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes the fields, dynamic fields and field types that are already in `schema` from
+/// the Schema API commands. Adding them again fails.
+fn skip_existing_fields(commands: Value, schema: &Value) -> Value {
+    let mut commands = match commands {
+        Value::Object(commands) => commands,
+        other => return other,
+    };
+    for (command, key) in &[
+        ("add-field", "fields"),
+        ("add-dynamic-field", "dynamicFields"),
+        ("add-field-type", "fieldTypes"),
+    ] {
+        let existing: HashSet<&str> = schema[key]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|field| field["name"].as_str())
+            .collect();
+        let is_new = |field: &Value| match field["name"].as_str() {
+            Some(name) if existing.contains(name) => {
+                info!("{} {} already exists. skip it.", command, name);
+                false
+            }
+            _ => true,
+        };
+        // a command has one field or an array of fields
+        let fields: Vec<Value> = match commands.remove(*command) {
+            Some(Value::Array(fields)) => fields.into_iter().filter(is_new).collect(),
+            Some(field) => std::iter::once(field).filter(is_new).collect(),
+            None => continue,
+        };
+        if !fields.is_empty() {
+            commands.insert(command.to_string(), Value::from(fields));
+        }
+    }
+    Value::Object(commands)
+}
+
+impl SolrOutput {
+    /// Sends the buffered documents as update requests and waits for the oldest
+    /// request if more than `concurrent_requests` are in flight.
+    async fn flush(&mut self) -> Result<(), LoaderError> {
+        let chunk = std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(self.config.buffer_size),
+        );
+        let chunk_len = chunk.len();
+        let task =
+            SolrOutput::proceed_chunk(self.client.clone(), self.config.clone(), chunk, self.sync);
+        self.in_flight.push_back((chunk_len, tokio::spawn(task)));
+        while self.in_flight.len() > self.config.concurrent_requests {
+            self.wait_oldest().await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_oldest(&mut self) -> Result<(), LoaderError> {
+        if let Some((chunk_len, task)) = self.in_flight.pop_front() {
+            let rejected = task
+                .await
+                .map_err(|e| LoaderError::Transport(format!("bulk task failed. {}", e)))??;
+            self.acknowledged += chunk_len;
+            self.rejected += rejected.len();
+            if let Some(dead_letter) = &self.dead_letter {
+                for entry in &rejected {
+                    dead_letter.write(entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        SolrOutput::request_to(&self.client, &self.config, method, path)
+    }
+
+    fn request_to(
+        client: &Client,
+        config: &SolrConfig,
+        method: Method,
+        path: &str,
+    ) -> RequestBuilder {
+        let url = format!("{}/{}", config.url.trim_end_matches('/'), path);
+        let request = client
+            .request(method, url.as_str())
+            .query(&[("wt", "json")]);
+        match &config.username {
+            Some(username) => request.basic_auth(username, config.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Creates a collection, or a core of a standalone Solr, and adds the fields in the schema file.
+    /// In SolrCloud, the collection has its own copy of `config_set`, so the fields are not
+    /// added to the other collections.
+    async fn call_collection_create(&self, name: &str) -> Result<(), LoaderError> {
+        let schema_json = load_schema(&self.config.schema_file)?;
+        let response = if self.config.cloud {
+            self.call_config_set_copy(name).await?;
+            self.request(Method::GET, "admin/collections")
+                .query(&[
+                    ("action", "CREATE"),
+                    ("name", name),
+                    ("collection.configName", name),
+                    ("numShards", &self.config.num_shards.to_string()),
+                    (
+                        "replicationFactor",
+                        &self.config.replication_factor.to_string(),
+                    ),
+                ])
+                .send()
+                .await?
+        } else {
+            self.request(Method::GET, "admin/cores")
+                .query(&[
+                    ("action", "CREATE"),
+                    ("name", name),
+                    ("configSet", &self.config.config_set),
+                ])
+                .send()
+                .await?
+        };
+        check_status(response, "create collection").await?;
+        // the config set may already have the fields, e.g. a shared config set of a standalone Solr
+        let response = self
+            .request(Method::GET, &format!("{}/schema", name))
+            .send()
+            .await?;
+        let schema = check_status(response, "get schema").await?;
+        let commands = skip_existing_fields(schema_json, &schema["schema"]);
+        if commands
+            .as_object()
+            .is_some_and(|commands| !commands.is_empty())
+        {
+            let response = self
+                .request(Method::POST, &format!("{}/schema", name))
+                .json(&commands)
+                .send()
+                .await?;
+            check_status(response, "schema").await?;
+        }
+        info!("{} collection was created.", name);
+        Ok(())
+    }
+
+    /// Copies `config_set` to a config set named `name` with the Configsets API,
+    /// unless it already exists.
+    async fn call_config_set_copy(&self, name: &str) -> Result<(), LoaderError> {
+        let response = self
+            .request(Method::GET, "admin/configs")
+            .query(&[("action", "LIST")])
+            .send()
+            .await?;
+        let exists = check_status(response, "list config sets").await?["configSets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|config_set| config_set == name);
+        if exists {
+            info!("{} config set already exists.", name);
+            return Ok(());
+        }
+        let response = self
+            .request(Method::GET, "admin/configs")
+            .query(&[
+                ("action", "CREATE"),
+                ("name", name),
+                ("baseConfigSet", &self.config.config_set),
+                ("configSetProp.immutable", "false"),
+            ])
+            .send()
+            .await?;
+        check_status(response, "create config set").await?;
+        info!(
+            "{} config set was copied from {}.",
+            name, self.config.config_set
+        );
+        Ok(())
+    }
+
+    async fn call_list_collections(&self) -> Result<Vec<String>, LoaderError> {
+        let response = self
+            .request(Method::GET, "admin/collections")
+            .query(&[("action", "LIST")])
+            .send()
+            .await?;
+        Ok(
+            check_status(response, "list collections").await?["collections"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|name| name.as_str())
+                .map(String::from)
+                .collect(),
+        )
+    }
+
+    /// Aliases and their collections.
+    async fn call_list_aliases(&self) -> Result<Map<String, Value>, LoaderError> {
+        let response = self
+            .request(Method::GET, "admin/collections")
+            .query(&[("action", "LISTALIASES")])
+            .send()
+            .await?;
+        Ok(check_status(response, "list aliases").await?["aliases"]
+            .as_object()
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn call_commit(&self, collection: &str) -> Result<(), LoaderError> {
+        let response = self
+            .request(Method::POST, &format!("{}/update", collection))
+            .json(&json!({"commit": {}}))
+            .send()
+            .await?;
+        check_status(response, "commit").await?;
+        info!("{} collection was committed.", collection);
+        Ok(())
+    }
+
+    /// Lists the ids of the indexed documents that are not in `ids`.
+    async fn call_cursor_missing_ids(
+        &self,
+        ids: &HashSet<String>,
+    ) -> Result<Vec<String>, LoaderError> {
+        let mut missing = vec![];
+        let mut cursor_mark = String::from("*");
+        let rows = self.config.buffer_size.to_string();
+        loop {
+            let response = self
+                .request(Method::GET, &format!("{}/select", self.config.collection))
+                .query(&[
+                    ("q", "*:*"),
+                    ("fl", "id"),
+                    ("sort", "id asc"),
+                    ("rows", rows.as_str()),
+                    ("cursorMark", cursor_mark.as_str()),
+                ])
+                .send()
+                .await?;
+            let body = check_status(response, "select").await?;
+            for doc in body["response"]["docs"].as_array().into_iter().flatten() {
+                if let Some(id) = doc["id"].as_str() {
+                    if !ids.contains(id) {
+                        missing.push(id.to_string());
+                    }
+                }
+            }
+            // the same cursor mark is returned at the end
+            match body["nextCursorMark"].as_str() {
+                Some(next) if next != cursor_mark => cursor_mark = next.to_string(),
+                _ => break,
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Removes the documents whose revision is already indexed.
+    async fn skip_unchanged(
+        client: &Client,
+        config: &SolrConfig,
+        chunk: Vec<TransformedDocument>,
+    ) -> Result<Vec<TransformedDocument>, LoaderError> {
+        let ids: Vec<String> = chunk.iter().map(|d| d.source.id.clone()).collect();
+        let separator = id_separator(&ids);
+        let response = SolrOutput::request_to(
+            client,
+            config,
+            Method::POST,
+            &format!("{}/select", config.collection),
+        )
+        .json(&json!({
+            "query": "*:*",
+            "filter": format!(
+                "{{!terms f=id separator=\"{}\"}}{}",
+                separator,
+                ids.join(&separator.to_string())
+            ),
+            "fields": "id,revision_id",
+            "limit": chunk.len(),
+        }))
+        .send()
+        .await?;
+        let body = check_status(response, "select").await?;
+        let indexed: HashMap<String, String> = body["response"]["docs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|doc| {
+                Some((
+                    doc["id"].as_str()?.to_string(),
+                    doc["revision_id"].as_str()?.to_string(),
+                ))
+            })
+            .collect();
+        let chunk_len = chunk.len();
+        let changed: Vec<TransformedDocument> = chunk
+            .into_iter()
+            .filter(|d| match indexed.get(&d.source.id) {
+                Some(revision_id) => d.source.is_newer_than(revision_id),
+                None => true,
+            })
+            .collect();
+        if changed.len() < chunk_len {
+            info!("Skipped {} unchanged documents.", chunk_len - changed.len());
+        }
+        Ok(changed)
+    }
+
+    async fn proceed_chunk(
+        client: Client,
+        config: Arc<SolrConfig>,
+        chunk: Vec<TransformedDocument>,
+        sync: bool,
+    ) -> Result<Vec<DeadLetter>, LoaderError> {
+        let mut chunk = chunk;
+        if sync {
+            chunk = SolrOutput::skip_unchanged(&client, &config, chunk).await?;
+        }
+        let (batches, mut rejected) = split_by_bytes(
+            chunk,
+            config.max_batch_bytes,
+            // separated by ","
+            |d| serde_json::to_string(&d.fields).unwrap().len() + 1,
+            |d| &d.source,
+        );
+        for batch in batches {
+            let doc_id = batch[0].source.id.clone();
+            let error = match SolrOutput::send_batch(&client, &config, &batch).await? {
+                Some(error) => error,
+                None => continue,
+            };
+            if batch.len() == 1 {
+                rejected.push(SolrOutput::reject(&batch[0], error));
+                continue;
+            }
+            // Solr rejects the whole batch, so find the failed documents one by one
+            warn!("Bulk Request has some errors. {}", doc_id);
+            for d in &batch {
+                if let Some(error) =
+                    SolrOutput::send_batch(&client, &config, std::slice::from_ref(d)).await?
+                {
+                    rejected.push(SolrOutput::reject(d, error));
+                }
+            }
+            info!("Finished bulk request. {}", doc_id);
+        }
+        Ok(rejected)
+    }
+
+    fn reject(d: &TransformedDocument, error: SolrError) -> DeadLetter {
+        warn!(
+            "error id:[{}], type:[{}], reason:[{}]",
+            d.source.id, error.code, error.msg
+        );
+        DeadLetter::Rejected {
+            id: d.source.id.clone(),
+            error_type: error.code.to_string(),
+            reason: error.msg,
+            document: serde_json::to_value(&d.source).unwrap(),
+        }
+    }
+
+    /// Posts the documents to `/update`. Returns the error if Solr rejects them.
+    async fn send_batch(
+        client: &Client,
+        config: &SolrConfig,
+        docs: &[TransformedDocument],
+    ) -> Result<Option<SolrError>, LoaderError> {
+        let doc_id = docs[0].source.id.as_str();
+        let fields: Vec<_> = docs.iter().map(|d| &d.fields).collect();
+        let body = serde_json::to_string(&fields).unwrap();
+        // retries of the whole request
+        let mut attempt = 0;
+        loop {
+            info!("Sending {} documents... {}", docs.len(), doc_id);
+            let result = SolrOutput::request_to(
+                client,
+                config,
+                Method::POST,
+                &format!("{}/update", config.collection),
+            )
+            .header("Content-Type", "application/json")
+            .body(body.clone())
+            .send()
+            .await;
+            let response = match result {
+                Ok(response) => response,
+                Err(err) => {
+                    warn!(
+                        "Bulk request has failed. {}. First doc id is [{}]",
+                        err, doc_id
+                    );
+                    if attempt < config.retry.max_retries {
+                        attempt += 1;
+                        config.retry.wait(attempt).await;
+                        continue;
+                    }
+                    return Err(err.into());
+                }
+            };
+            let status = response.status();
+            info!("response : {}", status);
+            if status.is_success() {
+                if docs.len() > 1 {
+                    info!("Finished bulk request. {}", doc_id);
+                }
+                return Ok(None);
+            }
+            warn!(
+                "Bulk request has failed. Status Code is {:?}. First doc id is [{}]",
+                status, doc_id
+            );
+            if is_retryable_status(status.as_u16()) && attempt < config.retry.max_retries {
+                attempt += 1;
+                config.retry.wait(attempt).await;
+                continue;
+            }
+            if status.is_client_error() {
+                let body = response.json::<Value>().await.unwrap_or_default();
+                return Ok(Some(SolrError {
+                    code: status.as_u16(),
+                    msg: body["error"]["msg"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                }));
+            }
+            return Err(LoaderError::Transport(format!(
+                "bulk request failed. Status Code is {:?}. First doc id is [{}]",
+                status, doc_id
+            )));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::test_support::{block_on, document, TestServer};
+
+    fn output(url: &str) -> SolrOutput {
+        let config: SolrConfig = serde_yaml::from_str(&format!(
+            "url: {}\ncollection: wiki\nschema_file: schema.json\nbuffer_size: 10\nretry:\n  initial_backoff_ms: 1\n  max_backoff_ms: 1\n",
+            url
+        ))
+        .unwrap();
+        SolrOutput {
+            client: Client::new(),
+            buffer: vec![],
+            config: Arc::new(config),
+            config_file: String::from("solr.yaml"),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+        }
+    }
+
+    fn load(output: &mut SolrOutput, ids: &[&str]) -> Result<(), LoaderError> {
+        block_on(async {
+            for id in ids {
+                output.add_document(document(id, "1")).await?;
+            }
+            output.close().await
+        })
+    }
+
+    // ids of the documents in an update request
+    fn ids(body: &str) -> Vec<String> {
+        serde_json::from_str::<Vec<Value>>(body)
+            .unwrap()
+            .iter()
+            .map(|doc| doc["id"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn solr_error(msg: &str) -> String {
+        json!({"error": {"msg": msg, "code": 400}}).to_string()
+    }
+
+    #[test]
+    fn commit_on_close() {
+        let server = TestServer::start(|_| (200, json!({"responseHeader": {}}).to_string()));
+        let mut output = output(&server.url);
+        load(&mut output, &["1", "2"]).unwrap();
+        assert_eq!(output.acknowledged(), 2);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/wiki/update?wt=json");
+        assert_eq!(ids(&requests[0].body), vec!["1", "2"]);
+        assert_eq!(requests[1].path, "/wiki/update?wt=json");
+        assert_eq!(
+            serde_json::from_str::<Value>(&requests[1].body).unwrap(),
+            json!({"commit": {}})
+        );
+    }
+
+    #[test]
+    fn resend_rejected_batch_one_by_one() {
+        let server = TestServer::start(|request| {
+            if request.body.contains("commit") {
+                return (200, json!({}).to_string());
+            }
+            match ids(&request.body).as_slice() {
+                [id] if id == "1" || id == "3" => (200, json!({}).to_string()),
+                _ => (400, solr_error("ERROR: [doc=2] unknown field 'foo'")),
+            }
+        });
+        let mut output = output(&server.url);
+        match load(&mut output, &["1", "2", "3"]) {
+            Err(LoaderError::Rejected { count }) => assert_eq!(count, 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(output.acknowledged(), 3);
+        let sent: Vec<Vec<String>> = server
+            .requests()
+            .iter()
+            .filter(|request| !request.body.contains("commit"))
+            .map(|request| ids(&request.body))
+            .collect();
+        assert_eq!(
+            sent,
+            vec![vec!["1", "2", "3"], vec!["1"], vec!["2"], vec!["3"]]
+        );
+        // the accepted documents are committed
+        assert!(server.requests().last().unwrap().body.contains("commit"));
+    }
+
+    #[test]
+    fn retry_busy_batch() {
+        let mut count = 0;
+        let server = TestServer::start(move |_| {
+            count += 1;
+            match count {
+                1 => (503, solr_error("busy")),
+                _ => (200, json!({}).to_string()),
+            }
+        });
+        let mut output = output(&server.url);
+        load(&mut output, &["1", "2"]).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].body, requests[1].body);
+    }
+
+    #[test]
+    fn fail_batch_on_server_error() {
+        let server = TestServer::start(|_| (500, solr_error("server error")));
+        let mut output = output(&server.url);
+        match load(&mut output, &["1", "2"]) {
+            Err(LoaderError::Transport(message)) => assert!(message.contains("500")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // not sent one by one, nor committed
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn alias_exists_as_collection() {
+        let server = TestServer::start(|request| {
+            let body = if request.path.contains("action=LISTALIASES") {
+                json!({"aliases": {"wiki": "wiki_20200701000000"}})
+            } else {
+                json!({"collections": ["wiki_20200701000000"]})
+            };
+            (200, body.to_string())
+        });
+        let mut other = output(&server.url);
+        other.set_index("enwiki");
+        assert!(!block_on(other.exist_index()).unwrap());
+        let output = output(&server.url);
+        assert!(block_on(output.exist_index()).unwrap());
+    }
+
+    #[test]
+    fn fail_rebuild_of_collection_before_loading() {
+        let server = TestServer::start(|_| (200, json!({"collections": ["wiki"]}).to_string()));
+        let output = output(&server.url);
+        match block_on(output.create_generation()) {
+            Err(LoaderError::Config { message, .. }) => {
+                assert!(message.contains("wiki is a collection"))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].path.contains("action=LIST"));
+    }
+
+    #[test]
+    fn skip_fields_in_schema() {
+        let commands = json!({
+            "add-field": [
+                {"name": "title", "type": "text_ja"},
+                {"name": "revision_id", "type": "string"}
+            ],
+            "add-field-type": {"name": "text_ja", "class": "solr.TextField"},
+            "add-copy-field": {"source": "title", "dest": "_text_"}
+        });
+        let schema = json!({
+            "fields": [{"name": "id"}, {"name": "revision_id"}],
+            "fieldTypes": [{"name": "text_ja"}]
+        });
+        assert_eq!(
+            skip_existing_fields(commands, &schema),
+            json!({
+                "add-field": [{"name": "title", "type": "text_ja"}],
+                "add-copy-field": {"source": "title", "dest": "_text_"}
+            })
+        );
+    }
+
+    #[test]
+    fn skip_all_fields_of_existing_schema() {
+        let commands = json!({"add-field": [{"name": "title", "type": "text_ja"}]});
+        let schema = json!({"fields": [{"name": "title"}]});
+        assert_eq!(skip_existing_fields(commands, &schema), json!({}));
+    }
+
+    #[test]
+    fn keep_fields_of_new_schema() {
+        let commands = json!({"add-dynamic-field": {"name": "*_s", "type": "string"}});
+        assert_eq!(
+            skip_existing_fields(commands, &Value::Null),
+            json!({"add-dynamic-field": [{"name": "*_s", "type": "string"}]})
+        );
+    }
+}
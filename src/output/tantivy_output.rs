@@ -0,0 +1,735 @@
+use crate::error::LoaderError;
+use crate::loader::dead_letter::{DeadLetter, DeadLetterWriter};
+use crate::loader::transform::TransformedDocument;
+use crate::output::cjk_tokenizer::CjkBigramTokenizer;
+use crate::output::elasticsearch_output::SearchEngine;
+use crate::output::elasticsearch_output::{load_schema, read_config};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use serde_json::Map;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use tantivy::collector::TopDocs;
+use tantivy::query::TermQuery;
+use tantivy::schema::{
+    DateOptions, Field, IndexRecordOption, NumericOptions, Schema, TextFieldIndexing, TextOptions,
+    Value, STORED, STRING,
+};
+use tantivy::tokenizer::{
+    LowerCaser, NgramTokenizer, SimpleTokenizer, TextAnalyzer, WhitespaceTokenizer,
+};
+use tantivy::{Index, IndexWriter, Searcher, TantivyDocument, Term};
+use tokio::task;
+
+type SharedWriter = Arc<RwLock<IndexWriter>>;
+
+/// Tokenizers registered by Tantivy.
+const BUILTIN_TOKENIZERS: [&str; 4] = ["default", "raw", "en_stem", "whitespace"];
+
+lazy_static! {
+    // An index has only one writer, so the files loaded in parallel share it.
+    static ref WRITERS: Mutex<HashMap<PathBuf, SharedWriter>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TantivyConfig {
+    /// Parent directory of the index.
+    index_dir: String,
+    index_name: String,
+    schema_file: String,
+    buffer_size: usize,
+    /// Memory of the index writer, shared by its indexing threads.
+    #[serde(default = "default_writer_memory_bytes")]
+    writer_memory_bytes: usize,
+}
+
+fn default_writer_memory_bytes() -> usize {
+    100 * 1024 * 1024
+}
+
+/// Schema file of the Tantivy output.
+#[derive(Debug, Deserialize)]
+struct IndexSchema {
+    #[serde(default)]
+    tokenizers: HashMap<String, TokenizerConfig>,
+    fields: Vec<FieldConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenizerConfig {
+    #[serde(flatten)]
+    kind: TokenizerKind,
+    #[serde(default = "default_lowercase")]
+    lowercase: bool,
+}
+
+fn default_lowercase() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TokenizerKind {
+    Simple,
+    Whitespace,
+    /// Bigrams of CJK characters for Japanese.
+    CjkBigram,
+    Ngram {
+        min_gram: usize,
+        max_gram: usize,
+        #[serde(default)]
+        prefix_only: bool,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct FieldConfig {
+    name: String,
+    #[serde(rename = "type")]
+    field_type: FieldType,
+    /// Tokenizer of a text field. Tantivy's `default`, `raw`, `en_stem` and `whitespace`
+    /// can be used without definition.
+    tokenizer: Option<String>,
+    #[serde(default)]
+    stored: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FieldType {
+    Text,
+    /// Indexed as a single term.
+    String,
+    Date,
+    U64,
+    F64,
+}
+
+pub struct TantivyOutput {
+    config: TantivyConfig,
+    config_file: String,
+    buffer: Vec<TransformedDocument>,
+    // the index and the writer are opened at the first flush
+    index: Option<(Index, SharedWriter)>,
+    // documents added but not committed yet. rejected and unchanged documents are not counted,
+    // so a checkpoint can be behind them and they are added again with --resume
+    added: usize,
+    acknowledged: usize,
+    rejected: usize,
+    dead_letter: Option<Arc<DeadLetterWriter>>,
+    sync: bool,
+}
+
+fn load_config(config_file: &str) -> Result<TantivyConfig, LoaderError> {
+    read_config(config_file)
+}
+
+fn load_index_schema(schema_file: &str) -> Result<IndexSchema, LoaderError> {
+    serde_json::from_value(load_schema(schema_file)?).map_err(|e| LoaderError::Schema {
+        path: schema_file.to_string(),
+        message: format!("schema cannot read. {}", e),
+    })
+}
+
+fn build_schema(index_schema: &IndexSchema) -> Result<Schema, String> {
+    let mut builder = Schema::builder();
+    for field in &index_schema.fields {
+        match field.field_type {
+            FieldType::Text => {
+                let tokenizer = field.tokenizer.as_deref().unwrap_or("default");
+                if !BUILTIN_TOKENIZERS.contains(&tokenizer)
+                    && !index_schema.tokenizers.contains_key(tokenizer)
+                {
+                    return Err(format!(
+                        "tokenizer {} of {} is not defined",
+                        tokenizer, field.name
+                    ));
+                }
+                let indexing = TextFieldIndexing::default()
+                    .set_tokenizer(tokenizer)
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+                let mut options = TextOptions::default().set_indexing_options(indexing);
+                if field.stored {
+                    options = options.set_stored();
+                }
+                builder.add_text_field(&field.name, options);
+            }
+            FieldType::String => {
+                let options = if field.stored {
+                    STRING | STORED
+                } else {
+                    STRING
+                };
+                builder.add_text_field(&field.name, options);
+            }
+            FieldType::Date => {
+                let mut options = DateOptions::default().set_indexed().set_fast();
+                if field.stored {
+                    options = options.set_stored();
+                }
+                builder.add_date_field(&field.name, options);
+            }
+            FieldType::U64 => {
+                let mut options = NumericOptions::default().set_indexed().set_fast();
+                if field.stored {
+                    options = options.set_stored();
+                }
+                builder.add_u64_field(&field.name, options);
+            }
+            FieldType::F64 => {
+                let mut options = NumericOptions::default().set_indexed().set_fast();
+                if field.stored {
+                    options = options.set_stored();
+                }
+                builder.add_f64_field(&field.name, options);
+            }
+        }
+    }
+    let schema = builder.build();
+    // documents are replaced by the id
+    match index_schema.fields.iter().find(|field| field.name == "id") {
+        Some(FieldConfig {
+            field_type: FieldType::String,
+            stored: true,
+            ..
+        }) => Ok(schema),
+        _ => Err(String::from("id must be a stored string field")),
+    }
+}
+
+/// Registers the tokenizers in the schema file. Tokenizers are not saved in the index,
+/// so they are registered every time the index is opened.
+fn register_tokenizers(index: &Index, index_schema: &IndexSchema) -> tantivy::Result<()> {
+    for (name, tokenizer) in &index_schema.tokenizers {
+        let builder = match &tokenizer.kind {
+            TokenizerKind::Simple => TextAnalyzer::builder(SimpleTokenizer::default()).dynamic(),
+            TokenizerKind::Whitespace => {
+                TextAnalyzer::builder(WhitespaceTokenizer::default()).dynamic()
+            }
+            TokenizerKind::CjkBigram => TextAnalyzer::builder(CjkBigramTokenizer).dynamic(),
+            TokenizerKind::Ngram {
+                min_gram,
+                max_gram,
+                prefix_only,
+            } => TextAnalyzer::builder(NgramTokenizer::new(*min_gram, *max_gram, *prefix_only)?)
+                .dynamic(),
+        };
+        let builder = if tokenizer.lowercase {
+            builder.filter_dynamic(LowerCaser)
+        } else {
+            builder
+        };
+        index.tokenizers().register(name, builder.build());
+    }
+    Ok(())
+}
+
+/// Revision of the indexed document with the id.
+fn indexed_revision(
+    searcher: &Searcher,
+    id_field: Field,
+    revision_field: Field,
+    id: &str,
+) -> tantivy::Result<Option<String>> {
+    let query = TermQuery::new(
+        Term::from_field_text(id_field, id),
+        IndexRecordOption::Basic,
+    );
+    let top_docs = searcher.search(&query, &TopDocs::with_limit(1))?;
+    Ok(match top_docs.first() {
+        Some((_, address)) => {
+            let doc: TantivyDocument = searcher.doc(*address)?;
+            doc.get_first(revision_field)
+                .and_then(|value| value.as_str())
+                .map(String::from)
+        }
+        None => None,
+    })
+}
+
+/// Adds the documents to the index, replacing the documents with the same ids.
+/// Returns the documents that don't match the schema.
+fn add_documents(
+    index: &Index,
+    writer: &RwLock<IndexWriter>,
+    docs: Vec<TransformedDocument>,
+    sync: bool,
+) -> tantivy::Result<(Vec<DeadLetter>, usize)> {
+    let schema = index.schema();
+    let id_field = schema.get_field("id")?;
+    // in sync mode, documents whose revision is already committed are skipped
+    let searcher = if sync {
+        Some((index.reader()?.searcher(), schema.get_field("revision_id")?))
+    } else {
+        None
+    };
+    let writer = writer.read().unwrap();
+    let mut unchanged = 0;
+    let mut rejected = vec![];
+    for d in docs {
+        if let Some((searcher, revision_field)) = &searcher {
+            let revision = indexed_revision(searcher, id_field, *revision_field, &d.source.id)?;
+            if let Some(revision_id) = revision {
+                if !d.source.is_newer_than(&revision_id) {
+                    unchanged += 1;
+                    continue;
+                }
+            }
+        }
+        let fields: Map<String, serde_json::Value> = d
+            .fields
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        match TantivyDocument::from_json_object(&schema, fields) {
+            Ok(doc) => {
+                writer.delete_term(Term::from_field_text(id_field, &d.source.id));
+                writer.add_document(doc)?;
+            }
+            Err(e) => {
+                warn!(
+                    "error id:[{}], type:[invalid_document], reason:[{}]",
+                    d.source.id, e
+                );
+                rejected.push(DeadLetter::Rejected {
+                    id: d.source.id.clone(),
+                    error_type: String::from("invalid_document"),
+                    reason: e.to_string(),
+                    document: serde_json::to_value(&d.source).unwrap(),
+                });
+            }
+        }
+    }
+    if unchanged > 0 {
+        info!("Skipped {} unchanged documents.", unchanged);
+    }
+    Ok((rejected, unchanged))
+}
+
+#[async_trait]
+impl SearchEngine for TantivyOutput {
+    fn new(_config_file: &str) -> Result<Self, LoaderError>
+    where
+        Self: Sized,
+    {
+        let config = load_config(_config_file)?;
+        debug!("index_dir: {}", config.index_dir);
+        debug!("buffer_size: {}", config.buffer_size);
+        let buffer = Vec::with_capacity(config.buffer_size);
+        Ok(TantivyOutput {
+            config,
+            config_file: _config_file.to_string(),
+            buffer,
+            index: None,
+            added: 0,
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+        })
+    }
+
+    fn set_dead_letter(&mut self, dead_letter: Arc<DeadLetterWriter>) {
+        self.dead_letter = Some(dead_letter);
+    }
+
+    fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    async fn add_document(&mut self, _document: TransformedDocument) -> Result<(), LoaderError> {
+        self.buffer.push(_document);
+        if self.buffer.len() >= self.config.buffer_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Documents are acknowledged when they are committed.
+    fn acknowledged(&self) -> usize {
+        self.acknowledged
+    }
+
+    async fn initialize(&self) -> Result<(), LoaderError> {
+        let path = self.index_path();
+        if self.exist_index().await? {
+            info!(
+                "{} index already exists. skip initialization phase.",
+                path.display()
+            );
+            return Ok(());
+        }
+        info!("{} index is creating...", path.display());
+        let index_schema = load_index_schema(&self.config.schema_file)?;
+        let schema = build_schema(&index_schema).map_err(|message| LoaderError::Schema {
+            path: self.config.schema_file.clone(),
+            message,
+        })?;
+        std::fs::create_dir_all(&path).map_err(|source| LoaderError::Io {
+            path: path.display().to_string(),
+            source,
+        })?;
+        Index::create_in_dir(&path, schema)?;
+        info!("{} index was created.", path.display());
+        Ok(())
+    }
+
+    async fn exist_index(&self) -> Result<bool, LoaderError> {
+        Ok(self.index_path().join("meta.json").is_file())
+    }
+
+    async fn close(&mut self) -> Result<(), LoaderError> {
+        if !self.buffer.is_empty() {
+            self.flush().await?;
+        }
+        if let Some((_, writer)) = &self.index {
+            let writer = writer.clone();
+            task::spawn_blocking(move || writer.write().unwrap().commit())
+                .await
+                .map_err(|e| LoaderError::Index(format!("commit task failed. {}", e)))??;
+            info!("{} index was committed.", self.index_path().display());
+            self.acknowledged += self.added;
+            self.added = 0;
+        }
+        match self.rejected {
+            0 => Ok(()),
+            count => Err(LoaderError::Rejected { count }),
+        }
+    }
+
+    /// Waits for the merges of the segments and releases the writer.
+    async fn finalize(&self) -> Result<(), LoaderError> {
+        let writer = WRITERS.lock().unwrap().remove(&self.index_path());
+        if let Some(writer) = writer {
+            match Arc::try_unwrap(writer) {
+                Ok(writer) => {
+                    let writer = writer.into_inner().unwrap();
+                    task::spawn_blocking(move || writer.wait_merging_threads())
+                        .await
+                        .map_err(|e| LoaderError::Index(format!("merge task failed. {}", e)))??;
+                }
+                Err(_) => warn!(
+                    "The writer of {} is still used. The merges of the segments are not waited for.",
+                    self.index_path().display()
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes with the writer shared by the outputs, and releases it like `finalize`.
+    async fn delete_missing(&self, ids: &HashSet<String>) -> Result<usize, LoaderError> {
+        let index = self.open_index()?;
+        let writer = self.shared_writer(&index)?;
+        let ids = ids.clone();
+        let deleted = task::spawn_blocking(move || -> tantivy::Result<usize> {
+            let id_field = index.schema().get_field("id")?;
+            let searcher = index.reader()?.searcher();
+            let mut missing = vec![];
+            for segment_reader in searcher.segment_readers() {
+                let store_reader = segment_reader.get_store_reader(1)?;
+                for doc in store_reader.iter::<TantivyDocument>(segment_reader.alive_bitset()) {
+                    if let Some(id) = doc?.get_first(id_field).and_then(|value| value.as_str()) {
+                        if !ids.contains(id) {
+                            missing.push(id.to_string());
+                        }
+                    }
+                }
+            }
+            if missing.is_empty() {
+                return Ok(0);
+            }
+            info!("Deleting {} documents... {}", missing.len(), missing[0]);
+            let mut writer = writer.write().unwrap();
+            for id in &missing {
+                writer.delete_term(Term::from_field_text(id_field, id));
+            }
+            writer.commit()?;
+            Ok(missing.len())
+        })
+        .await
+        .map_err(|e| LoaderError::Index(format!("delete task failed. {}", e)))??;
+        self.finalize().await?;
+        Ok(deleted)
+    }
+
+    fn set_index(&mut self, index_name: &str) {
+        self.config.index_name = index_name.to_string();
+    }
+
+    async fn create_generation(&self) -> Result<String, LoaderError> {
+        Err(LoaderError::Config {
+            path: self.config_file.clone(),
+            message: String::from("rebuild is not supported by the Tantivy output"),
+        })
+    }
+
+    async fn switch_generation(
+        &self,
+        _generation: &str,
+        _loaded: usize,
+    ) -> Result<(), LoaderError> {
+        Ok(())
+    }
+}
+
+impl TantivyOutput {
+    fn index_path(&self) -> PathBuf {
+        Path::new(&self.config.index_dir).join(&self.config.index_name)
+    }
+
+    /// Opens the index and registers the tokenizers in the schema file.
+    fn open_index(&self) -> Result<Index, LoaderError> {
+        let index_schema = load_index_schema(&self.config.schema_file)?;
+        let index = Index::open_in_dir(self.index_path())?;
+        register_tokenizers(&index, &index_schema)?;
+        Ok(index)
+    }
+
+    /// The writer of the index, opened by the first output that needs it.
+    fn shared_writer(&self, index: &Index) -> Result<SharedWriter, LoaderError> {
+        let mut writers = WRITERS.lock().unwrap();
+        Ok(match writers.get(&self.index_path()) {
+            Some(writer) => writer.clone(),
+            None => {
+                let writer = Arc::new(RwLock::new(index.writer(self.config.writer_memory_bytes)?));
+                writers.insert(self.index_path(), writer.clone());
+                writer
+            }
+        })
+    }
+
+    /// Adds the buffered documents to the index on the blocking thread pool.
+    async fn flush(&mut self) -> Result<(), LoaderError> {
+        if self.index.is_none() {
+            let index = self.open_index()?;
+            let writer = self.shared_writer(&index)?;
+            self.index = Some((index, writer));
+        }
+        let (index, writer) = self.index.clone().unwrap();
+        let chunk = std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(self.config.buffer_size),
+        );
+        info!("Adding {} documents... {}", chunk.len(), chunk[0].source.id);
+        let chunk_len = chunk.len();
+        let sync = self.sync;
+        let (rejected, unchanged) =
+            task::spawn_blocking(move || add_documents(&index, &writer, chunk, sync))
+                .await
+                .map_err(|e| LoaderError::Index(format!("index task failed. {}", e)))??;
+        self.added += chunk_len - rejected.len() - unchanged;
+        self.rejected += rejected.len();
+        if let Some(dead_letter) = &self.dead_letter {
+            for entry in &rejected {
+                dead_letter.write(entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::test_support::{block_on, document};
+    use serde_json::json;
+    use std::fs;
+
+    const SCHEMA: &str = r#"{
+        "tokenizers": {"ja": {"type": "cjk_bigram"}},
+        "fields": [
+            {"name": "id", "type": "string", "stored": true},
+            {"name": "revision_id", "type": "string", "stored": true},
+            {"name": "title", "type": "text", "tokenizer": "ja", "stored": true}
+        ]
+    }"#;
+
+    fn index_schema(schema: &str) -> IndexSchema {
+        serde_json::from_str(schema).unwrap()
+    }
+
+    // writes the config and the schema to a new directory and returns the config file
+    fn config_file(name: &str, schema: &str) -> String {
+        let dir = std::env::temp_dir().join(format!(
+            "wiki-json-loader-tantivy-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let schema_file = dir.join("schema.json");
+        fs::write(&schema_file, schema).unwrap();
+        let config_file = dir.join("tantivy.yaml");
+        fs::write(
+            &config_file,
+            format!(
+                "index_dir: {}\nindex_name: wiki\nschema_file: {}\nbuffer_size: 10\nwriter_memory_bytes: 15000000\n",
+                dir.display(),
+                schema_file.display()
+            ),
+        )
+        .unwrap();
+        config_file.to_str().unwrap().to_string()
+    }
+
+    fn output(config_file: &str, sync: bool) -> TantivyOutput {
+        let mut output = TantivyOutput::new(config_file).unwrap();
+        output.set_sync(sync);
+        output
+    }
+
+    fn load(output: &mut TantivyOutput, docs: Vec<TransformedDocument>) -> Result<(), LoaderError> {
+        block_on(async {
+            for d in docs {
+                output.add_document(d).await?;
+            }
+            output.close().await
+        })
+    }
+
+    // ids and revisions of the committed documents
+    fn indexed(output: &TantivyOutput) -> Vec<(String, String)> {
+        let index = output.open_index().unwrap();
+        let schema = index.schema();
+        let id_field = schema.get_field("id").unwrap();
+        let revision_field = schema.get_field("revision_id").unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let mut docs = vec![];
+        for segment_reader in searcher.segment_readers() {
+            let store_reader = segment_reader.get_store_reader(1).unwrap();
+            for doc in store_reader.iter::<TantivyDocument>(segment_reader.alive_bitset()) {
+                let doc = doc.unwrap();
+                let field = |field| {
+                    doc.get_first(field)
+                        .and_then(|value| value.as_str())
+                        .unwrap()
+                        .to_string()
+                };
+                docs.push((field(id_field), field(revision_field)));
+            }
+        }
+        docs.sort();
+        docs
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(id, revision)| (id.to_string(), revision.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn build_valid_schema() {
+        let schema = build_schema(&index_schema(SCHEMA)).unwrap();
+        assert!(schema.get_field("title").is_ok());
+    }
+
+    #[test]
+    fn id_must_be_stored_string() {
+        for id in [
+            r#"{"name": "id", "type": "string"}"#,
+            r#"{"name": "id", "type": "text", "stored": true}"#,
+            r#"{"name": "title", "type": "string", "stored": true}"#,
+        ] {
+            let schema = format!(r#"{{"fields": [{}]}}"#, id);
+            assert_eq!(
+                build_schema(&index_schema(&schema)).unwrap_err(),
+                "id must be a stored string field"
+            );
+        }
+    }
+
+    #[test]
+    fn tokenizer_must_be_defined() {
+        let schema = r#"{"fields": [
+            {"name": "id", "type": "string", "stored": true},
+            {"name": "title", "type": "text", "tokenizer": "ja"}
+        ]}"#;
+        assert_eq!(
+            build_schema(&index_schema(schema)).unwrap_err(),
+            "tokenizer ja of title is not defined"
+        );
+        let schema = schema.replace("\"ja\"", "\"en_stem\"");
+        assert!(build_schema(&index_schema(&schema)).is_ok());
+    }
+
+    #[test]
+    fn commit_on_close_and_replace_by_id() {
+        let config_file = config_file("replace", SCHEMA);
+        let mut first = output(&config_file, false);
+        block_on(first.initialize()).unwrap();
+        block_on(async {
+            first.add_document(document("1", "1")).await?;
+            first.add_document(document("2", "1")).await
+        })
+        .unwrap();
+        assert_eq!(first.acknowledged(), 0);
+        load(&mut first, vec![]).unwrap();
+        assert_eq!(first.acknowledged(), 2);
+        assert_eq!(indexed(&first), pairs(&[("1", "1"), ("2", "1")]));
+
+        let mut second = output(&config_file, false);
+        load(&mut second, vec![document("1", "2")]).unwrap();
+        assert_eq!(indexed(&second), pairs(&[("1", "2"), ("2", "1")]));
+        block_on(second.finalize()).unwrap();
+    }
+
+    #[test]
+    fn reject_document_not_matching_schema() {
+        let schema = SCHEMA.replace(
+            r#"{"name": "title", "type": "text", "tokenizer": "ja", "stored": true}"#,
+            r#"{"name": "title", "type": "u64", "stored": true}"#,
+        );
+        let config_file = config_file("reject", &schema);
+        let mut output = output(&config_file, false);
+        block_on(output.initialize()).unwrap();
+        let mut valid = document("2", "1");
+        valid.fields.insert(String::from("title"), json!(2));
+        match load(&mut output, vec![document("1", "1"), valid]) {
+            Err(LoaderError::Rejected { count }) => assert_eq!(count, 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(output.acknowledged(), 1);
+        assert_eq!(indexed(&output), pairs(&[("2", "1")]));
+        block_on(output.finalize()).unwrap();
+    }
+
+    #[test]
+    fn skip_unchanged_documents() {
+        let config_file = config_file("sync", SCHEMA);
+        let mut first = output(&config_file, false);
+        block_on(first.initialize()).unwrap();
+        load(&mut first, vec![document("1", "2"), document("2", "2")]).unwrap();
+
+        let mut second = output(&config_file, true);
+        load(
+            &mut second,
+            vec![document("1", "1"), document("2", "3"), document("3", "1")],
+        )
+        .unwrap();
+        // the unchanged document is not acknowledged
+        assert_eq!(second.acknowledged(), 2);
+        assert_eq!(
+            indexed(&second),
+            pairs(&[("1", "2"), ("2", "3"), ("3", "1")])
+        );
+        block_on(second.finalize()).unwrap();
+    }
+
+    #[test]
+    fn delete_missing_with_shared_writer() {
+        let config_file = config_file("delete", SCHEMA);
+        let mut output = output(&config_file, false);
+        block_on(output.initialize()).unwrap();
+        load(&mut output, vec![document("1", "1"), document("2", "1")]).unwrap();
+        // the writer of the output is still open
+        let ids: HashSet<String> = vec![String::from("1")].into_iter().collect();
+        assert_eq!(block_on(output.delete_missing(&ids)).unwrap(), 1);
+        assert_eq!(indexed(&output), pairs(&[("1", "1")]));
+        assert!(!WRITERS.lock().unwrap().contains_key(&output.index_path()));
+    }
+}
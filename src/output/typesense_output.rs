@@ -0,0 +1,829 @@
+use crate::error::LoaderError;
+use crate::loader::dead_letter::{DeadLetter, DeadLetterWriter};
+use crate::loader::transform::TransformedDocument;
+use crate::output::batch::{default_max_batch_bytes, split_by_bytes};
+use crate::output::bulk::BulkItems;
+use crate::output::elasticsearch_output::SearchEngine;
+use crate::output::elasticsearch_output::{
+    default_concurrent_requests, load_schema, read_config, BulkTask,
+};
+use crate::output::rebuild::{generation_name, is_generation, RebuildConfig};
+use crate::output::retry::{is_retryable_status, RetryConfig};
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+const API_KEY_HEADER: &str = "X-TYPESENSE-API-KEY";
+
+// filters are sent in the query string, so they are kept well below the URL length limits
+const MAX_FILTER_LENGTH: usize = 2000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TypesenseConfig {
+    url: String,
+    api_key: String,
+    buffer_size: usize,
+    collection: String,
+    /// Collection schema without `name`.
+    schema_file: String,
+    #[serde(default = "default_concurrent_requests")]
+    concurrent_requests: usize,
+    /// Maximum size of an import request body. Requests are split to fit in it.
+    #[serde(default = "default_max_batch_bytes")]
+    max_batch_bytes: usize,
+    #[serde(default)]
+    retry: RetryConfig,
+    #[serde(default)]
+    rebuild: RebuildConfig,
+}
+
+pub struct TypesenseOutput {
+    client: Client,
+    buffer: Vec<TransformedDocument>,
+    config: Arc<TypesenseConfig>,
+    config_file: String,
+    in_flight: VecDeque<(usize, BulkTask)>,
+    acknowledged: usize,
+    rejected: usize,
+    dead_letter: Option<Arc<DeadLetterWriter>>,
+    sync: bool,
+}
+
+fn load_config(config_file: &str) -> Result<TypesenseConfig, LoaderError> {
+    read_config(config_file)
+}
+
+/// Returns the body of a successful response.
+async fn check_status(response: Response, request: &str) -> Result<Value, LoaderError> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.json::<Value>().await.unwrap_or_default();
+        return Err(LoaderError::Transport(format!(
+            "{} request failed. Status Code is {:?}. {}",
+            request, status, body["message"]
+        )));
+    }
+    Ok(response.json::<Value>().await?)
+}
+
+/// Returns the lines of a successful JSONL response.
+async fn check_status_lines(response: Response, request: &str) -> Result<Vec<Value>, LoaderError> {
+    let status = response.status();
+    if !status.is_success() {
+        return Err(LoaderError::Transport(format!(
+            "{} request failed. Status Code is {:?}.",
+            request, status
+        )));
+    }
+    parse_lines(&response.text().await?, request)
+}
+
+fn parse_lines(text: &str, request: &str) -> Result<Vec<Value>, LoaderError> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line).map_err(|e| {
+                LoaderError::Transport(format!(
+                    "cannot parse the {} response. {}. {}",
+                    request, e, line
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Typesense has no object fields without nested fields enabled, so `images` and `links`
+/// are flattened into string arrays. The arrays of the images have the same length.
+/// The id must be a string.
+fn flatten_fields(d: &TransformedDocument) -> Map<String, Value> {
+    let mut fields: Map<String, Value> = d
+        .fields
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    fields.insert(String::from("id"), Value::from(d.source.id.as_str()));
+    if let Some(Value::Array(images)) = fields.remove("images") {
+        let string_of = |value: &Value| Value::from(value.as_str().unwrap_or_default());
+        let targets: Vec<Value> = images.iter().map(|i| string_of(&i["target"])).collect();
+        let target_types: Vec<Value> = images
+            .iter()
+            .map(|i| string_of(&i["target_type"]))
+            .collect();
+        let texts: Vec<Value> = images
+            .iter()
+            .map(|i| string_of(&i["text"]["text"]))
+            .collect();
+        fields.insert(String::from("image_targets"), Value::from(targets));
+        fields.insert(
+            String::from("image_target_types"),
+            Value::from(target_types),
+        );
+        fields.insert(String::from("image_texts"), Value::from(texts));
+    }
+    if let Some(Value::Array(links)) = fields.remove("links") {
+        let targets: Vec<Value> = links
+            .iter()
+            .filter_map(|l| l["link_target"].as_str())
+            .map(Value::from)
+            .collect();
+        fields.insert(String::from("link_targets"), Value::from(targets));
+    }
+    fields
+}
+
+/// Reads the result lines of an import. Failed documents are retried only if `retry` is true,
+/// otherwise they are rejected. Fails if there is not a result for each document.
+fn read_import_results(
+    results: &[Value],
+    docs: Vec<TransformedDocument>,
+    retry: bool,
+) -> Result<BulkItems, LoaderError> {
+    if results.len() != docs.len() {
+        return Err(LoaderError::Transport(format!(
+            "import returned {} results for {} documents. First doc id is [{}]",
+            results.len(),
+            docs.len(),
+            docs.first()
+                .map(|d| d.source.id.as_str())
+                .unwrap_or_default()
+        )));
+    }
+    let mut items = BulkItems::default();
+    // results are returned in the same order as the request
+    for (result, d) in results.iter().zip(docs) {
+        if result["success"].as_bool().unwrap_or(false) {
+            continue;
+        }
+        let code = result["code"].as_u64().unwrap_or(400) as u16;
+        let reason = result["error"].as_str().unwrap_or_default();
+        warn!(
+            "error id:[{}], type:[{}], reason:[{}]",
+            d.source.id, code, reason
+        );
+        if is_retryable_status(code) && retry {
+            items.failed.push(d);
+        } else {
+            items.rejected.push(DeadLetter::Rejected {
+                id: d.source.id.clone(),
+                error_type: code.to_string(),
+                reason: reason.to_string(),
+                document: serde_json::to_value(&d.source).unwrap(),
+            });
+        }
+    }
+    Ok(items)
+}
+
+#[async_trait]
+impl SearchEngine for TypesenseOutput {
+    fn new(config_file: &str) -> Result<Self, LoaderError>
+    where
+        Self: Sized,
+    {
+        let config = load_config(config_file)?;
+        debug!("url: {}", config.url);
+        debug!("buffer_size: {}", config.buffer_size);
+        let buffer = Vec::with_capacity(config.buffer_size);
+        Ok(TypesenseOutput {
+            client: Client::new(),
+            buffer,
+            config: Arc::new(config),
+            config_file: config_file.to_string(),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+        })
+    }
+
+    fn set_dead_letter(&mut self, dead_letter: Arc<DeadLetterWriter>) {
+        self.dead_letter = Some(dead_letter);
+    }
+
+    fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    async fn add_document(&mut self, _document: TransformedDocument) -> Result<(), LoaderError> {
+        self.buffer.push(_document);
+        if self.buffer.len() >= self.config.buffer_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    fn acknowledged(&self) -> usize {
+        self.acknowledged
+    }
+
+    async fn initialize(&self) -> Result<(), LoaderError> {
+        if self.exist_index().await? {
+            info!(
+                "{} collection already exists. skip initialization phase.",
+                &self.config.collection
+            );
+        } else {
+            info!("{} collection is creating...", &self.config.collection);
+            self.call_collection_create(&self.config.collection).await?;
+        }
+        Ok(())
+    }
+
+    /// The collection may be an alias made by a rebuild.
+    async fn exist_index(&self) -> Result<bool, LoaderError> {
+        Ok(self.call_collection_exists().await? || self.call_alias_exists().await?)
+    }
+
+    async fn close(&mut self) -> Result<(), LoaderError> {
+        if !self.buffer.is_empty() {
+            self.flush().await?;
+        }
+        while !self.in_flight.is_empty() {
+            self.wait_oldest().await?;
+        }
+        match self.rejected {
+            0 => Ok(()),
+            count => Err(LoaderError::Rejected { count }),
+        }
+    }
+
+    async fn finalize(&self) -> Result<(), LoaderError> {
+        Ok(())
+    }
+
+    async fn delete_missing(&self, ids: &HashSet<String>) -> Result<usize, LoaderError> {
+        let response = self
+            .request(
+                Method::GET,
+                &format!("collections/{}/documents/export", self.config.collection),
+            )
+            .query(&[("include_fields", "id")])
+            .send()
+            .await?;
+        let missing: Vec<String> = check_status_lines(response, "export")
+            .await?
+            .iter()
+            .filter_map(|doc| doc["id"].as_str())
+            .filter(|id| !ids.contains(*id))
+            .map(String::from)
+            .collect();
+        let (chunks, unfiltered) = id_chunks(&missing);
+        for chunk in chunks {
+            info!("Deleting {} documents... {}", chunk.len(), chunk[0]);
+            let response = self
+                .request(
+                    Method::DELETE,
+                    &format!("collections/{}/documents", self.config.collection),
+                )
+                .query(&[("filter_by", id_filter(&chunk))])
+                .send()
+                .await?;
+            check_status(response, "delete").await?;
+        }
+        for id in unfiltered {
+            info!("Deleting 1 documents... {}", id);
+            let response = self
+                .request(Method::DELETE, &document_path(&self.config, id))
+                .send()
+                .await?;
+            check_status(response, "delete").await?;
+        }
+        Ok(missing.len())
+    }
+
+    fn set_index(&mut self, index_name: &str) {
+        Arc::make_mut(&mut self.config).collection = index_name.to_string();
+    }
+
+    async fn create_generation(&self) -> Result<String, LoaderError> {
+        // an alias cannot have the name of a collection, so the switch would fail after loading
+        if !self.call_alias_exists().await? && self.call_collection_exists().await? {
+            return Err(LoaderError::Config {
+                path: self.config_file.clone(),
+                message: format!(
+                    "{} is a collection, but --rebuild needs an alias of that name. Move the collection to an alias before rebuilding it",
+                    self.config.collection
+                ),
+            });
+        }
+        let generation = generation_name(&self.config.collection, '_');
+        info!("{} collection is creating...", generation);
+        self.call_collection_create(&generation).await?;
+        Ok(generation)
+    }
+
+    async fn switch_generation(&self, generation: &str, loaded: usize) -> Result<(), LoaderError> {
+        let response = self
+            .request(Method::GET, &format!("collections/{}", generation))
+            .send()
+            .await?;
+        let count = check_status(response, "get collection").await?["num_documents"]
+            .as_u64()
+            .unwrap_or_default() as usize;
+        info!("{} collection has {} documents.", generation, count);
+        self.config.rebuild.check_count(generation, count, loaded)?;
+        // upserting an alias points it to the new collection
+        let alias = self.config.collection.as_str();
+        let response = self
+            .request(Method::PUT, &format!("aliases/{}", alias))
+            .json(&json!({ "collection_name": generation }))
+            .send()
+            .await?;
+        check_status(response, "upsert alias").await?;
+        info!("{} alias was switched to {}.", alias, generation);
+        let response = self.request(Method::GET, "collections").send().await?;
+        let generations: Vec<String> = check_status(response, "list collections")
+            .await?
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|collection| collection["name"].as_str())
+            .filter(|name| is_generation(name, alias, '_'))
+            .map(String::from)
+            .collect();
+        for collection in self.config.rebuild.expired(generations, generation) {
+            let response = self
+                .request(Method::DELETE, &format!("collections/{}", collection))
+                .send()
+                .await?;
+            if response.status().is_success() {
+                info!("{} collection was deleted.", collection);
+            } else {
+                warn!(
+                    "Delete collection request has failed. Status Code is {:?}. {}",
+                    response.status(),
+                    collection
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Filter of the documents with the ids.
+fn id_filter(ids: &[&str]) -> String {
+    // backticks escape the ids
+    let ids: Vec<String> = ids.iter().map(|id| format!("`{}`", id)).collect();
+    format!("id:[{}]", ids.join(","))
+}
+
+/// Splits the ids into chunks whose filters are at most `MAX_FILTER_LENGTH` bytes.
+/// Ids with a backtick cannot be escaped in a filter, so they are returned separately.
+fn id_chunks(ids: &[String]) -> (Vec<Vec<&str>>, Vec<&str>) {
+    let mut chunks = vec![];
+    let mut unfiltered = vec![];
+    let mut chunk: Vec<&str> = vec![];
+    // length of "id:[]"
+    let mut length = 5;
+    for id in ids {
+        if id.contains('`') {
+            unfiltered.push(id.as_str());
+            continue;
+        }
+        // backticks and a comma
+        let id_length = id.len() + 3;
+        if !chunk.is_empty() && length + id_length > MAX_FILTER_LENGTH {
+            chunks.push(std::mem::take(&mut chunk));
+            length = 5;
+        }
+        length += id_length;
+        chunk.push(id.as_str());
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    (chunks, unfiltered)
+}
+
+/// Path of a document.
+fn document_path(config: &TypesenseConfig, id: &str) -> String {
+    format!(
+        "collections/{}/documents/{}",
+        config.collection,
+        utf8_percent_encode(id, NON_ALPHANUMERIC)
+    )
+}
+
+impl TypesenseOutput {
+    /// Sends the buffered documents as import requests and waits for the oldest
+    /// request if more than `concurrent_requests` are in flight.
+    async fn flush(&mut self) -> Result<(), LoaderError> {
+        let chunk = std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(self.config.buffer_size),
+        );
+        let chunk_len = chunk.len();
+        let task = TypesenseOutput::proceed_chunk(
+            self.client.clone(),
+            self.config.clone(),
+            chunk,
+            self.sync,
+        );
+        self.in_flight.push_back((chunk_len, tokio::spawn(task)));
+        while self.in_flight.len() > self.config.concurrent_requests {
+            self.wait_oldest().await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_oldest(&mut self) -> Result<(), LoaderError> {
+        if let Some((chunk_len, task)) = self.in_flight.pop_front() {
+            let rejected = task
+                .await
+                .map_err(|e| LoaderError::Transport(format!("bulk task failed. {}", e)))??;
+            self.acknowledged += chunk_len;
+            self.rejected += rejected.len();
+            if let Some(dead_letter) = &self.dead_letter {
+                for entry in &rejected {
+                    dead_letter.write(entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        TypesenseOutput::request_to(&self.client, &self.config, method, path)
+    }
+
+    fn request_to(
+        client: &Client,
+        config: &TypesenseConfig,
+        method: Method,
+        path: &str,
+    ) -> RequestBuilder {
+        let url = format!("{}/{}", config.url.trim_end_matches('/'), path);
+        client
+            .request(method, url.as_str())
+            .header(API_KEY_HEADER, config.api_key.as_str())
+    }
+
+    async fn call_collection_exists(&self) -> Result<bool, LoaderError> {
+        let response = self
+            .request(
+                Method::GET,
+                &format!("collections/{}", self.config.collection),
+            )
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => check_status(response, "get collection").await.map(|_| true),
+        }
+    }
+
+    async fn call_alias_exists(&self) -> Result<bool, LoaderError> {
+        let response = self
+            .request(Method::GET, &format!("aliases/{}", self.config.collection))
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => check_status(response, "get alias").await.map(|_| true),
+        }
+    }
+
+    async fn call_collection_create(&self, name: &str) -> Result<(), LoaderError> {
+        let mut schema_json = load_schema(&self.config.schema_file)?;
+        schema_json["name"] = Value::from(name);
+        let response = self
+            .request(Method::POST, "collections")
+            .json(&schema_json)
+            .send()
+            .await?;
+        check_status(response, "create collection").await?;
+        info!("{} collection was created.", name);
+        Ok(())
+    }
+
+    /// Removes the documents whose revision is already indexed.
+    async fn skip_unchanged(
+        client: &Client,
+        config: &TypesenseConfig,
+        chunk: Vec<TransformedDocument>,
+    ) -> Result<Vec<TransformedDocument>, LoaderError> {
+        let ids: Vec<String> = chunk.iter().map(|d| d.source.id.clone()).collect();
+        let (chunks, unfiltered) = id_chunks(&ids);
+        let mut indexed: HashMap<String, String> = HashMap::new();
+        let revision_of = |doc: &Value| {
+            Some((
+                doc["id"].as_str()?.to_string(),
+                doc["revision_id"].as_str()?.to_string(),
+            ))
+        };
+        for ids in chunks {
+            let response = TypesenseOutput::request_to(
+                client,
+                config,
+                Method::GET,
+                &format!("collections/{}/documents/export", config.collection),
+            )
+            .query(&[
+                ("filter_by", id_filter(&ids).as_str()),
+                ("include_fields", "id,revision_id"),
+            ])
+            .send()
+            .await?;
+            let docs = check_status_lines(response, "export").await?;
+            indexed.extend(docs.iter().filter_map(revision_of));
+        }
+        for id in unfiltered {
+            let response = TypesenseOutput::request_to(
+                client,
+                config,
+                Method::GET,
+                &document_path(config, id),
+            )
+            .send()
+            .await?;
+            if response.status() != StatusCode::NOT_FOUND {
+                let doc = check_status(response, "get document").await?;
+                indexed.extend(revision_of(&doc));
+            }
+        }
+        let chunk_len = chunk.len();
+        let changed: Vec<TransformedDocument> = chunk
+            .into_iter()
+            .filter(|d| match indexed.get(&d.source.id) {
+                Some(revision_id) => d.source.is_newer_than(revision_id),
+                None => true,
+            })
+            .collect();
+        if changed.len() < chunk_len {
+            info!("Skipped {} unchanged documents.", chunk_len - changed.len());
+        }
+        Ok(changed)
+    }
+
+    async fn proceed_chunk(
+        client: Client,
+        config: Arc<TypesenseConfig>,
+        chunk: Vec<TransformedDocument>,
+        sync: bool,
+    ) -> Result<Vec<DeadLetter>, LoaderError> {
+        let mut chunk = chunk;
+        if sync {
+            chunk = TypesenseOutput::skip_unchanged(&client, &config, chunk).await?;
+        }
+        let (batches, mut rejected) = split_by_bytes(
+            chunk,
+            config.max_batch_bytes,
+            // a line of JSONL
+            |d| serde_json::to_string(&flatten_fields(d)).unwrap().len() + 1,
+            |d| &d.source,
+        );
+        for batch in batches {
+            rejected.extend(TypesenseOutput::send_import(&client, &config, batch).await?);
+        }
+        Ok(rejected)
+    }
+
+    async fn send_import(
+        client: &Client,
+        config: &TypesenseConfig,
+        docs: Vec<TransformedDocument>,
+    ) -> Result<Vec<DeadLetter>, LoaderError> {
+        let mut docs = docs;
+        let mut rejected = vec![];
+        // retries of the whole request and of the failed documents
+        let mut attempt = 0;
+        let mut document_attempt = 0;
+        loop {
+            let mut body = String::new();
+            for d in &docs {
+                body.push_str(&serde_json::to_string(&flatten_fields(d)).unwrap());
+                body.push('\n');
+            }
+            let doc_id = docs[0].source.id.clone();
+            info!("Sending {} documents... {}", docs.len(), doc_id);
+            let result = TypesenseOutput::request_to(
+                client,
+                config,
+                Method::POST,
+                &format!("collections/{}/documents/import", config.collection),
+            )
+            .query(&[("action", "upsert")])
+            .header("Content-Type", "text/plain")
+            .body(body)
+            .send()
+            .await;
+            let import_response = match result {
+                Ok(response) if response.status().is_success() => response,
+                Ok(response) => {
+                    warn!(
+                        "Bulk request has failed. Status Code is {:?}. First doc id is [{}]",
+                        response.status(),
+                        doc_id
+                    );
+                    if is_retryable_status(response.status().as_u16())
+                        && attempt < config.retry.max_retries
+                    {
+                        attempt += 1;
+                        config.retry.wait(attempt).await;
+                        continue;
+                    }
+                    return Err(LoaderError::Transport(format!(
+                        "bulk request failed. Status Code is {:?}. First doc id is [{}]",
+                        response.status(),
+                        doc_id
+                    )));
+                }
+                Err(err) => {
+                    warn!(
+                        "Bulk request has failed. {}. First doc id is [{}]",
+                        err, doc_id
+                    );
+                    if attempt < config.retry.max_retries {
+                        attempt += 1;
+                        config.retry.wait(attempt).await;
+                        continue;
+                    }
+                    return Err(err.into());
+                }
+            };
+            info!("response : {}", import_response.status());
+            let results = check_status_lines(import_response, "import").await?;
+            let items = read_import_results(
+                &results,
+                docs,
+                document_attempt < config.retry.max_document_retries,
+            )?;
+            if items.rejected.is_empty() && items.failed.is_empty() {
+                info!("Finished bulk request. {}", doc_id);
+                return Ok(rejected);
+            }
+            warn!("Bulk Request has some errors. {}", doc_id);
+            rejected.extend(items.rejected);
+            if items.failed.is_empty() {
+                info!("Finished bulk request. {}", doc_id);
+                return Ok(rejected);
+            }
+            document_attempt += 1;
+            config.retry.wait(document_attempt).await;
+            docs = items.failed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::test_support::{block_on, document, TestServer};
+
+    fn output(url: &str) -> TypesenseOutput {
+        let config: TypesenseConfig = serde_yaml::from_str(&format!(
+            "url: {}\napi_key: key\ncollection: wiki\nschema_file: schema.json\nbuffer_size: 10\nretry:\n  initial_backoff_ms: 1\n  max_backoff_ms: 1\n",
+            url
+        ))
+        .unwrap();
+        TypesenseOutput {
+            client: Client::new(),
+            buffer: vec![],
+            config: Arc::new(config),
+            config_file: String::from("typesense.yaml"),
+            in_flight: VecDeque::new(),
+            acknowledged: 0,
+            rejected: 0,
+            dead_letter: None,
+            sync: false,
+        }
+    }
+
+    #[test]
+    fn alias_exists_as_collection() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/aliases/wiki" => (
+                200,
+                json!({"name": "wiki", "collection_name": "wiki_20200701000000"}).to_string(),
+            ),
+            _ => (404, json!({"message": "Not Found"}).to_string()),
+        });
+        let mut other = output(&server.url);
+        other.set_index("enwiki");
+        assert!(!block_on(other.exist_index()).unwrap());
+        let output = output(&server.url);
+        assert!(block_on(output.exist_index()).unwrap());
+    }
+
+    #[test]
+    fn fail_rebuild_of_collection_before_loading() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/collections/wiki" => (200, json!({"name": "wiki"}).to_string()),
+            _ => (404, json!({"message": "Not Found"}).to_string()),
+        });
+        let output = output(&server.url);
+        match block_on(output.create_generation()) {
+            Err(LoaderError::Config { message, .. }) => {
+                assert!(message.contains("wiki is a collection"))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(server
+            .requests()
+            .iter()
+            .all(|request| request.method == "GET"));
+    }
+
+    #[test]
+    fn flatten_images_and_links() {
+        let mut d = document("1", "10");
+        d.fields.insert(
+            String::from("images"),
+            json!([
+                {"target": "a.png", "target_type": "File", "text": {"text": "caption", "link_target": "Page"}},
+                {"target": "b.png", "target_type": "Image", "text": {"text": ""}}
+            ]),
+        );
+        d.fields.insert(
+            String::from("links"),
+            json!([{"link_target": "Page"}, {"link_target": "http://example.com"}]),
+        );
+        let fields = flatten_fields(&d);
+        assert_eq!(fields["id"], "1");
+        assert_eq!(fields["image_targets"], json!(["a.png", "b.png"]));
+        assert_eq!(fields["image_target_types"], json!(["File", "Image"]));
+        assert_eq!(fields["image_texts"], json!(["caption", ""]));
+        assert_eq!(
+            fields["link_targets"],
+            json!(["Page", "http://example.com"])
+        );
+        assert!(!fields.contains_key("images"));
+        assert!(!fields.contains_key("links"));
+    }
+
+    #[test]
+    fn read_results_in_order() {
+        let results = vec![
+            json!({"success": true}),
+            json!({"success": false, "code": 400, "error": "Field `title` must be a string."}),
+            json!({"success": false, "code": 503, "error": "Not ready"}),
+        ];
+        let docs = vec![document("1", "1"), document("2", "1"), document("3", "1")];
+        let items = read_import_results(&results, docs, true).unwrap();
+        assert_eq!(items.rejected.len(), 1);
+        match &items.rejected[0] {
+            DeadLetter::Rejected { id, error_type, .. } => {
+                assert_eq!(id, "2");
+                assert_eq!(error_type, "400");
+            }
+            _ => panic!("not a rejected document"),
+        }
+        assert_eq!(items.failed.len(), 1);
+        assert_eq!(items.failed[0].source.id, "3");
+    }
+
+    #[test]
+    fn reject_busy_documents_without_retry() {
+        let results = vec![json!({"success": false, "code": 503, "error": "Not ready"})];
+        let items = read_import_results(&results, vec![document("1", "1")], false).unwrap();
+        assert!(items.failed.is_empty());
+        assert_eq!(items.rejected.len(), 1);
+    }
+
+    #[test]
+    fn fail_on_missing_results() {
+        let results = vec![json!({"success": true})];
+        let docs = vec![document("1", "1"), document("2", "1")];
+        assert!(read_import_results(&results, docs, true).is_err());
+        assert!(read_import_results(&[], vec![document("1", "1")], true).is_err());
+    }
+
+    #[test]
+    fn fail_on_garbled_lines() {
+        let lines = parse_lines("{\"success\":true}\n\n{\"success\":false}\n", "import").unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(parse_lines("{\"success\":true}\n<html>", "import").is_err());
+    }
+
+    #[test]
+    fn escape_ids_in_filter() {
+        assert_eq!(id_filter(&["1", "a,b"]), "id:[`1`,`a,b`]");
+    }
+
+    #[test]
+    fn split_ids_into_bounded_filters() {
+        let ids: Vec<String> = (0..3000).map(|i| format!("{:05}", i)).collect();
+        let (chunks, unfiltered) = id_chunks(&ids);
+        assert!(unfiltered.is_empty());
+        assert!(chunks.len() > 1);
+        assert!(chunks
+            .iter()
+            .all(|chunk| id_filter(chunk).len() <= MAX_FILTER_LENGTH));
+        let joined: Vec<&str> = chunks.into_iter().flatten().collect();
+        assert_eq!(joined.len(), ids.len());
+        assert_eq!(joined[2999], "02999");
+    }
+
+    #[test]
+    fn keep_ids_with_backtick_out_of_filters() {
+        let ids = vec![String::from("1"), String::from("a`b"), String::from("2")];
+        let (chunks, unfiltered) = id_chunks(&ids);
+        assert_eq!(chunks, vec![vec!["1", "2"]]);
+        assert_eq!(unfiltered, vec!["a`b"]);
+    }
+}